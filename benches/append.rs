@@ -0,0 +1,60 @@
+//! Measure time and allocations of `set` in append-only workload,
+//! i.e. every key is written once. Run with `cargo bench`
+use kvs::KvStore;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fs::remove_file;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const RECORDS: usize = 1_000_000;
+
+/// System allocator, which counts allocations
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn main() {
+    let path = env::temp_dir().join(format!("kvs-bench-append-{}.db", std::process::id()));
+    let mut store = KvStore::builder(&path).auto_compact(false).build().unwrap();
+    let keys: Vec<String> = (0..RECORDS).map(|i| format!("key{}", i)).collect();
+    let value = "x".repeat(100);
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for key in &keys {
+        store.set(key, &value).unwrap();
+    }
+    store.flush().unwrap();
+    let secs = start.elapsed().as_secs_f64();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{} sets: {:.3}s, {:.0} ns/set, {:.2} allocations/set",
+        RECORDS,
+        secs,
+        secs * 1e9 / RECORDS as f64,
+        allocations as f64 / RECORDS as f64
+    );
+
+    drop(store);
+    let _ = remove_file(&path);
+}
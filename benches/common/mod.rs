@@ -0,0 +1,38 @@
+//! Fixture shared by benches
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+/// Header of the current log format, which the store writes before the first record
+const HEADER: &str = "kvs-log 1\n";
+
+/// Write time of the first record, the following ones are written a millisecond apart
+const FIRST_WRITE: u64 = 1_600_000_000_000;
+
+/// Log file in a temporary directory, which is removed on drop, also when bench panics
+pub struct TempLog {
+    pub path: PathBuf,
+    _dir: TempDir,
+}
+
+/// Write log of the current format with `records` records setting `key<i % keys>` to
+/// `value<i>`. Log is written directly, it's much faster than going through the store
+pub fn write_log(records: usize, keys: usize) -> TempLog {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("kvs.db");
+    let mut file = BufWriter::new(File::create(&path).unwrap());
+    file.write_all(HEADER.as_bytes()).unwrap();
+    for i in 0..records {
+        writeln!(
+            file,
+            "{{\"op\":\"set\",\"key\":\"key{}\",\"value\":\"value{}\",\"ts\":{}}}",
+            i % keys,
+            i,
+            FIRST_WRITE + i as u64
+        )
+        .unwrap();
+    }
+    file.flush().unwrap();
+    TempLog { path, _dir: dir }
+}
@@ -0,0 +1,29 @@
+//! Compare sequential and parallel compaction of a large log.
+//! Run with `cargo bench --bench compaction --features parallel-compaction`
+use kvs::KvStore;
+use std::time::Instant;
+
+mod common;
+
+const RECORDS: usize = 2_000_000;
+
+fn main() {
+    for &parallel in &[false, true] {
+        // Every key is written twice, so half of the log is dropped by compaction
+        let log = common::write_log(RECORDS * 2, RECORDS);
+        let mut store = KvStore::builder(&log.path)
+            .auto_compact(false)
+            .parallel_compaction(parallel)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        store.compact().unwrap();
+        let name = if parallel { "parallel" } else { "sequential" };
+        println!(
+            "{:<10} compaction of {} live records: {:.2}s",
+            name,
+            RECORDS,
+            start.elapsed().as_secs_f64()
+        );
+    }
+}
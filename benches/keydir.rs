@@ -0,0 +1,36 @@
+//! Compare reads of uncached keys with and without key index.
+//! Without index every read scans the whole log, with it the record is read right away.
+//! Run with `cargo bench --bench keydir`
+use kvs::{KvStore, LruCache};
+use std::time::Instant;
+
+mod common;
+
+const READS: usize = 200;
+
+fn main() {
+    for &records in &[10_000, 100_000] {
+        let log = common::write_log(records, records);
+
+        for &indexed in &[false, true] {
+            let mut store = KvStore::builder(&log.path)
+                .preload_cache(false)
+                .key_index(indexed)
+                .build_with_cache(LruCache::with_capacity(1))
+                .unwrap();
+            let start = Instant::now();
+            for i in 0..READS {
+                let key = format!("key{}", i * (records / READS));
+                store.get(&key).unwrap();
+            }
+            let name = if indexed { "index" } else { "scan" };
+            let per_read = start.elapsed().as_secs_f64() / READS as f64;
+            println!(
+                "{:<6} read of {} records log: {:.1}us",
+                name,
+                records,
+                per_read * 1e6
+            );
+        }
+    }
+}
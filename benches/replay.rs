@@ -0,0 +1,44 @@
+//! Compare reads of a large log with buffered reader and from memory-mapped file:
+//! replay on open and full scan of the log for an uncached key. Best of a few runs is taken.
+//! Run with `cargo bench --bench replay --features mmap`
+use kvs::{KvStore, LruCache};
+use std::time::Instant;
+
+mod common;
+
+const RECORDS: usize = 2_000_000;
+const RUNS: usize = 3;
+
+fn main() {
+    let log = common::write_log(RECORDS, RECORDS);
+
+    for &mmap in &[false, true] {
+        let name = if mmap { "mmap" } else { "buffered" };
+        let mut best = f64::MAX;
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            let store = KvStore::builder(&log.path)
+                .auto_compact(false)
+                .mmap(mmap)
+                .build()
+                .unwrap();
+            best = best.min(start.elapsed().as_secs_f64());
+            assert_eq!(store.len().unwrap(), RECORDS);
+        }
+        println!("{:<8} replay of {} records: {:.3}s", name, RECORDS, best);
+
+        let mut store = KvStore::builder(&log.path)
+            .auto_compact(false)
+            .preload_cache(false)
+            .mmap(mmap)
+            .build_with_cache(LruCache::with_capacity(1))
+            .unwrap();
+        let mut best = f64::MAX;
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            assert!(store.get("missing").is_err());
+            best = best.min(start.elapsed().as_secs_f64());
+        }
+        println!("{:<8} scan of {} records: {:.3}s", name, RECORDS, best);
+    }
+}
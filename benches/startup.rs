@@ -0,0 +1,46 @@
+//! Compare time of opening a large database with cache capacity estimated from file size,
+//! without capacity hint, with exact hint and with large read buffer.
+//! Run with `cargo bench --bench startup`
+use kvs::KvStore;
+use std::time::Instant;
+
+mod common;
+
+const RECORDS: usize = 2_000_000;
+
+fn main() {
+    let log = common::write_log(RECORDS, RECORDS);
+    let path = &log.path;
+
+    let start = Instant::now();
+    let store = KvStore::new(path).unwrap();
+    report("estimated", start.elapsed().as_secs_f64());
+    assert_eq!(store.len().unwrap(), RECORDS);
+    drop(store);
+
+    let start = Instant::now();
+    let store = KvStore::builder(path).cache_capacity(0).build().unwrap();
+    report("no hint", start.elapsed().as_secs_f64());
+    assert_eq!(store.len().unwrap(), RECORDS);
+    drop(store);
+
+    let start = Instant::now();
+    let store = KvStore::builder(path)
+        .cache_capacity(RECORDS)
+        .build()
+        .unwrap();
+    report("with hint", start.elapsed().as_secs_f64());
+    drop(store);
+
+    let start = Instant::now();
+    let store = KvStore::builder(path)
+        .read_buffer_size(1024 * 1024)
+        .build()
+        .unwrap();
+    report("1MiB read", start.elapsed().as_secs_f64());
+    drop(store);
+}
+
+fn report(name: &str, secs: f64) {
+    println!("{:<10} open of {} records: {:.3}s", name, RECORDS, secs);
+}
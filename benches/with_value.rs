@@ -0,0 +1,55 @@
+//! Compare reading large values with `get`, which clones the value,
+//! and with `with_value`, which borrows it. Run with `cargo bench`
+use kvs::KvStore;
+use std::env;
+use std::fs::remove_file;
+use std::time::Instant;
+
+const KEYS: usize = 100;
+const VALUE_SIZE: usize = 1024 * 1024;
+const ROUNDS: usize = 20;
+
+fn main() {
+    let path = env::temp_dir().join(format!("kvs-bench-with-value-{}.db", std::process::id()));
+    let path = path.to_str().unwrap().to_owned();
+    let mut store = KvStore::new(&path).unwrap();
+    let value = "x".repeat(VALUE_SIZE);
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        store.set(key, &value).unwrap();
+    }
+
+    let start = Instant::now();
+    let mut total = 0;
+    for _ in 0..ROUNDS {
+        for key in &keys {
+            total += store.get(key).unwrap().len();
+        }
+    }
+    report("get", start.elapsed().as_secs_f64(), total);
+
+    let start = Instant::now();
+    let mut total = 0;
+    for _ in 0..ROUNDS {
+        for key in &keys {
+            total += store.with_value(key, str::len).unwrap().unwrap();
+        }
+    }
+    report("with_value", start.elapsed().as_secs_f64(), total);
+
+    drop(store);
+    let _ = remove_file(&path);
+}
+
+fn report(name: &str, secs: f64, total: usize) {
+    let reads = KEYS * ROUNDS;
+    println!(
+        "{:<12} {} reads of {} bytes: {:.3}s, {:.0} ns/read (checksum {})",
+        name,
+        reads,
+        VALUE_SIZE,
+        secs,
+        secs * 1e9 / reads as f64,
+        total
+    );
+}
@@ -1,11 +1,53 @@
 use clap::{
-    crate_authors, crate_description, crate_name, crate_version, App, Arg, ArgMatches, SubCommand,
+    crate_authors, crate_description, crate_name, crate_version, value_t, App, AppSettings, Arg,
+    ArgMatches, SubCommand,
 };
-use kvs::{KvStore, KvsError, Result, Shell};
+use kvs::{
+    DeadReason, ErrorCode, FileStorage, KvStore, KvsError, Log, Result, Shell, Storage, Watcher,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::File;
+use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
 use std::process::exit;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+/// How often `watch` polls the database for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Print warnings of the store, e.g. about slow operations, to stderr
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("warning: {}", record.args());
+        }
+    }
 
-fn main() -> Result<()> {
-    let args = App::new(crate_name!())
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("error: {}", err);
+        exit(err.code() as i32)
+    }
+}
+
+/// Build parser of command line arguments
+fn app() -> App<'static, 'static> {
+    App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
@@ -16,6 +58,32 @@ fn main() -> Result<()> {
                 .help("path to database file")
                 .default_value("kvs.db"),
         )
+        .arg(
+            Arg::with_name("no-compact")
+                .long("no-compact")
+                .help("disable automatic compaction (same as setting KVS_NO_COMPACT)"),
+        )
+        .arg(
+            Arg::with_name("compact-on-open")
+                .long("compact-on-open")
+                .help("compact database before running the command"),
+        )
+        .arg(
+            Arg::with_name("write-once")
+                .long("write-once")
+                .help("forbid overwriting of existing keys"),
+        )
+        .arg(
+            Arg::with_name("force-new")
+                .long("force-new")
+                .help("fail if database already has data, instead of opening it"),
+        )
+        .arg(
+            Arg::with_name("slow-threshold")
+                .long("slow-threshold")
+                .value_name("MS")
+                .help("warn about operations, which took longer than given milliseconds"),
+        )
         .subcommand(
             SubCommand::with_name("get")
                 .about("get key from storage")
@@ -24,6 +92,18 @@ fn main() -> Result<()> {
                         .help("search key")
                         .required(true)
                         .index(1),
+                )
+                .arg(
+                    Arg::with_name("raw")
+                        .short("n")
+                        .long("raw")
+                        .help("print exact value, without trailing newline"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("write exact value to the file instead of stdout"),
                 ),
         )
         .subcommand(
@@ -38,8 +118,119 @@ fn main() -> Result<()> {
                 .arg(
                     Arg::with_name("VALUE")
                         .help("value to set")
-                        .required(true)
+                        .required_unless("stdin")
                         .index(2),
+                )
+                .arg(
+                    Arg::with_name("stdin")
+                        .long("stdin")
+                        .help("read value from stdin")
+                        .conflicts_with("VALUE"),
+                )
+                .arg(
+                    Arg::with_name("nx")
+                        .long("nx")
+                        .help("set value only if key doesn't exist"),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("DURATION")
+                        .help("expire key after duration, e.g. 60s, 5m, 2h or 1d")
+                        .validator(|v| parse_duration(&v).map(|_| ()))
+                        .conflicts_with("nx"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("print value of the key on every change, until interrupted")
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("key name")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("compact database, dropping overwritten and removed records")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("print estimate of reclaimed space, don't modify database"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("write all key-value pairs to stdout as JSON object")
+                .arg(
+                    Arg::with_name("jsonl")
+                        .long("jsonl")
+                        .help("stream one JSON object per pair and line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("set key-value pairs read from stdin as JSON object")
+                .arg(
+                    Arg::with_name("jsonl")
+                        .long("jsonl")
+                        .help("read one JSON object per pair and line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("rewrite database of an older format in the current one"),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("check integrity of database without modifying it"),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("print diagnostic information about database")
+                .arg(
+                    Arg::with_name("dead")
+                        .long("dead")
+                        .help("list records, which compaction would drop")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tail")
+                .about("print the last records of the log")
+                .arg(
+                    Arg::with_name("n")
+                        .short("n")
+                        .help("amount of records")
+                        .takes_value(true)
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("print records as JSON, one per line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("print live keys in sorted order")
+                .arg(
+                    Arg::with_name("match")
+                        .long("match")
+                        .value_name("PATTERN")
+                        .help("print only keys matching glob pattern with * and ? wildcards")
+                        .default_value("*"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("histogram")
+                .about("print amount of live keys per prefix")
+                .arg(
+                    Arg::with_name("sep")
+                        .long("sep")
+                        .help("separator, which ends prefix of the key")
+                        .default_value(":"),
                 ),
         )
         .subcommand(
@@ -52,57 +243,500 @@ fn main() -> Result<()> {
                         .index(1),
                 ),
         )
-        .subcommand(SubCommand::with_name("shell").about("start KVS shell"))
-        .get_matches();
-    let db_name = if let Some(db) = args.value_of("db") {
-        db
-    } else {
-        "kvs.db"
-    };
+        .subcommand(
+            SubCommand::with_name("shell")
+                .about("start KVS shell")
+                .arg(
+                    Arg::with_name("script")
+                        .long("script")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("run commands from the file instead of reading them interactively"),
+                )
+                .arg(
+                    Arg::with_name("keep-going")
+                        .long("keep-going")
+                        .requires("script")
+                        .help("don't stop the script at the first failed command"),
+                )
+                .arg(
+                    Arg::with_name("compact-interval")
+                        .long("compact-interval")
+                        .value_name("DURATION")
+                        .takes_value(true)
+                        .validator(|v| parse_duration(&v).map(|_| ()))
+                        .help("compact database in background periodically, e.g. 10m"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("run mixed read/write benchmark against temporary database")
+                .arg(
+                    Arg::with_name("ops")
+                        .long("ops")
+                        .help("amount of operations")
+                        .default_value("100000"),
+                )
+                .arg(
+                    Arg::with_name("value-size")
+                        .long("value-size")
+                        .help("size of values in bytes")
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::with_name("read-ratio")
+                        .long("read-ratio")
+                        .help("share of reads among operations, from 0 to 1")
+                        .allow_hyphen_values(true)
+                        .validator(|v| parse_ratio(&v).map(|_| ()))
+                        .default_value("0.8"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("compare two databases, exiting with 1 if they differ")
+                .arg(
+                    Arg::with_name("FIRST")
+                        .help("path to the first database")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("SECOND")
+                        .help("path to the second database")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("print differences as JSON object"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("write shell completion script to stdout")
+                .setting(AppSettings::Hidden)
+                .arg(
+                    Arg::with_name("SHELL")
+                        .help("shell to complete in")
+                        .possible_values(&["bash", "zsh", "fish"])
+                        .required(true)
+                        .index(1),
+                ),
+        )
+}
+
+fn run() -> Result<()> {
+    let args = app().get_matches();
     match args.subcommand() {
-        ("set", Some(matches)) => set_cmd(db_name, matches)?,
-        ("get", Some(matches)) => get_cmd(db_name, matches)?,
-        ("rm", Some(matches)) => rm_cmd(db_name, matches)?,
-        ("shell", Some(matches)) => shell_cmd(db_name, matches)?,
+        ("set", Some(matches)) => set_cmd(&mut open_store(&args)?, matches)?,
+        ("get", Some(matches)) => get_cmd(&mut open_store(&args)?, matches)?,
+        ("rm", Some(matches)) => rm_cmd(&mut open_store(&args)?, matches)?,
+        ("shell", Some(matches)) => shell_cmd(&args, matches)?,
+        ("bench", Some(matches)) => bench_cmd(&args, matches)?,
+        ("export", Some(matches)) => export_cmd(&open_existing_store(&args)?, matches)?,
+        ("import", Some(matches)) => import_cmd(&mut open_store(&args)?, matches)?,
+        ("compact", Some(matches)) => compact_cmd(&mut open_store(&args)?, matches)?,
+        ("watch", Some(matches)) => watch_cmd(args.value_of("db").unwrap_or("kvs.db"), matches)?,
+        ("migrate", Some(_)) => migrate_cmd(&mut open_store(&args)?)?,
+        ("verify", Some(_)) => verify_cmd(args.value_of("db").unwrap_or("kvs.db"))?,
+        ("inspect", Some(_)) => inspect_cmd(&open_existing_store(&args)?)?,
+        ("histogram", Some(matches)) => histogram_cmd(&open_existing_store(&args)?, matches)?,
+        ("list", Some(matches)) => list_cmd(&open_existing_store(&args)?, matches)?,
+        ("diff", Some(matches)) => diff_cmd(&args, matches)?,
+        ("completions", Some(matches)) => {
+            let shell = value_t!(matches, "SHELL", clap::Shell)?;
+            app().gen_completions_to(crate_name!(), shell, &mut stdout());
+        }
+        ("tail", Some(matches)) => tail_cmd(args.value_of("db").unwrap_or("kvs.db"), matches)?,
         _ => unreachable!(),
     }
     Ok(())
 }
 
-fn set_cmd(db_name: &str, args: &ArgMatches) -> Result<()> {
-    let mut store = KvStore::new(db_name)?;
+/// Open store with options given in global arguments
+fn open_store(args: &ArgMatches) -> Result<KvStore> {
+    open_store_at(args.value_of("db").unwrap_or("kvs.db"), args)
+}
+
+/// Open store like [`open_store`], failing if it doesn't exist, e.g. for inspection commands
+fn open_existing_store(args: &ArgMatches) -> Result<KvStore> {
+    open_existing(args.value_of("db").unwrap_or("kvs.db"), args)
+}
+
+/// Open store at given path with options given in global arguments
+fn open_store_at(db_name: &str, args: &ArgMatches) -> Result<KvStore> {
+    let no_compact = args.is_present("no-compact") || env::var_os("KVS_NO_COMPACT").is_some();
+    let mut builder = KvStore::builder(db_name)
+        .auto_compact(!no_compact)
+        .compact_on_open(args.is_present("compact-on-open"))
+        .write_once(args.is_present("write-once"))
+        .create_new(args.is_present("force-new"));
+    if args.is_present("slow-threshold") {
+        let threshold = value_t!(args, "slow-threshold", u64)?;
+        // Logger may be already installed, e.g. by previous store of `bench`
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Warn);
+        }
+        builder = builder.slow_threshold(Duration::from_millis(threshold));
+    }
+    builder.build()
+}
+
+fn set_cmd(store: &mut KvStore, args: &ArgMatches) -> Result<()> {
     let key = args.value_of("KEY").unwrap();
-    let value = args.value_of("VALUE").unwrap();
-    store.set(key, value)
+    let mut value = String::new();
+    if args.is_present("stdin") {
+        stdin().read_to_string(&mut value)?;
+    } else {
+        value.push_str(args.value_of("VALUE").unwrap());
+    }
+    if args.is_present("nx") {
+        if !store.set_nx(key, &value)? {
+            return Err(KvsError::KeyExists);
+        }
+        return Ok(());
+    }
+    if let Some(ttl) = args.value_of("ttl") {
+        let ttl = parse_duration(ttl).expect("duration is validated by clap");
+        return store.set_with_ttl(key, &value, ttl);
+    }
+    store.set(key, &value)
 }
 
-fn get_cmd(db_name: &str, args: &ArgMatches) -> Result<()> {
-    let mut store = KvStore::new(db_name)?;
+/// Parse human duration, i.e. number followed by one of `s`, `m`, `h` or `d`
+fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let split = s.len() - s.chars().last().map_or(0, char::len_utf8);
+    let (number, unit) = s.split_at(split);
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration unit in '{}', expected s, m, h or d",
+                s
+            ))
+        }
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too long", s))
+}
+
+fn get_cmd(store: &mut KvStore, args: &ArgMatches) -> Result<()> {
     let key = args.value_of("KEY").unwrap();
     let entry = match store.get(key) {
         Ok(v) => v,
-        Err(err) => format!("{}", err),
+        Err(err) => {
+            println!("{}", err);
+            return Ok(());
+        }
     };
-    println!("{}", entry);
+    if let Some(path) = args.value_of("out") {
+        File::create(path)?.write_all(entry.as_bytes())?;
+    } else if args.is_present("raw") {
+        let mut out = stdout();
+        out.write_all(entry.as_bytes())?;
+        out.flush()?;
+    } else {
+        println!("{}", entry);
+    }
     Ok(())
 }
 
-fn rm_cmd(db_name: &str, args: &ArgMatches) -> Result<()> {
-    let mut store = KvStore::new(db_name)?;
-    let key = args.value_of("KEY").unwrap();
-    match store.remove(key) {
-        Ok(_) => Ok(()),
-        Err(KvsError::KeyNotFound) => {
-            eprintln!("Key not found");
-            exit(1)
+/// Line of JSON-lines export
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+/// Snapshot is built in memory, while JSON-lines are streamed from disk
+fn export_cmd(store: &KvStore, args: &ArgMatches) -> Result<()> {
+    let mut out = BufWriter::new(stdout());
+    if args.is_present("jsonl") {
+        for entry in store.stream_entries() {
+            let (key, value) = entry?;
+            serde_json::to_writer(&mut out, &Entry { key, value })?;
+            out.write_all(b"\n")?;
+        }
+    } else {
+        serde_json::to_writer(&mut out, store)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn import_cmd(store: &mut KvStore, args: &ArgMatches) -> Result<()> {
+    if args.is_present("jsonl") {
+        for line in stdin().lock().lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line)?;
+            store.set(&entry.key, &entry.value)?;
+        }
+    } else {
+        let map: HashMap<String, String> = serde_json::from_reader(stdin().lock())?;
+        store.load_hashmap(&map)?;
+    }
+    store.flush()
+}
+
+fn compact_cmd(store: &mut KvStore, args: &ArgMatches) -> Result<()> {
+    let disk_usage = store.disk_usage()?;
+    if args.is_present("dry-run") {
+        let resulting = store.live_data_size() as u64;
+        println!(
+            "would reclaim {} bytes, resulting file size {} bytes",
+            disk_usage.saturating_sub(resulting),
+            resulting
+        );
+        return Ok(());
+    }
+    store.compact()?;
+    let resulting = store.disk_usage()?;
+    println!(
+        "reclaimed {} bytes, resulting file size {} bytes",
+        disk_usage.saturating_sub(resulting),
+        resulting
+    );
+    Ok(())
+}
+
+fn migrate_cmd(store: &mut KvStore) -> Result<()> {
+    if store.migrate()? {
+        println!("migrated to the current format");
+    } else {
+        println!("already in the current format");
+    }
+    Ok(())
+}
+
+fn verify_cmd(db_name: &str) -> Result<()> {
+    let report = FileStorage::verify(db_name)?;
+    println!("good records: {}", report.good_records);
+    println!("bad records: {}", report.bad_records);
+    println!("total size: {} bytes", report.total_size);
+    if report.bad_records > 0 {
+        exit(ErrorCode::Corrupt as i32)
+    }
+    Ok(())
+}
+
+/// Print dead records as `<reason> <size> <key>` lines, `*` stands for key of clear records
+fn inspect_cmd(store: &KvStore) -> Result<()> {
+    let mut out = BufWriter::new(stdout());
+    for record in store.iter_tombstones() {
+        let record = record?;
+        let reason = match record.reason {
+            DeadReason::Superseded => "superseded",
+            DeadReason::Tombstone => "tombstone",
+            DeadReason::Expired => "expired",
+        };
+        let key = record.key.as_deref().unwrap_or("*");
+        writeln!(out, "{} {} {}", reason, record.size, key)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Print the last records in order they were written. Log can't be read backwards,
+/// so it's scanned keeping only the last records
+fn tail_cmd(db_name: &str, args: &ArgMatches) -> Result<()> {
+    let n = value_t!(args, "n", usize)?;
+    // Diagnostics shouldn't create the database
+    ensure_exists(db_name)?;
+    let storage = FileStorage::new(db_name)?;
+    let mut last = VecDeque::with_capacity(n + 1);
+    for item in storage.iter_from_offset(0) {
+        let (log, _) = item?;
+        last.push_back(log);
+        if last.len() > n {
+            last.pop_front();
+        }
+    }
+    let mut out = BufWriter::new(stdout());
+    for log in last {
+        if args.is_present("json") {
+            serde_json::to_writer(&mut out, &log)?;
+            out.write_all(b"\n")?;
+            continue;
+        }
+        match log {
+            Log::Set(k, v) => writeln!(out, "set {} {}", k, v)?,
+            Log::Remove(k) => writeln!(out, "remove {}", k)?,
+            Log::Clear => writeln!(out, "clear")?,
+            Log::SetEx(k, v, expires_at) => writeln!(out, "setex {} {} {}", k, v, expires_at)?,
+            Log::SetBlob(k, blob, len) => writeln!(out, "setblob {} {} {}", k, blob, len)?,
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Print `<prefix> <count>` lines, the most populated prefixes first
+fn histogram_cmd(store: &KvStore, args: &ArgMatches) -> Result<()> {
+    let mut counts: Vec<(String, usize)> = store
+        .prefix_counts(args.value_of("sep").unwrap())?
+        .into_iter()
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let mut out = BufWriter::new(stdout());
+    for (prefix, count) in counts {
+        writeln!(out, "{} {}", prefix, count)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn list_cmd(store: &KvStore, args: &ArgMatches) -> Result<()> {
+    let mut out = BufWriter::new(stdout());
+    for key in store.keys_matching(args.value_of("match").unwrap())? {
+        writeln!(out, "{}", key)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Differences between two databases, keys are sorted
+#[derive(Serialize, Default)]
+struct Diff {
+    only_first: Vec<String>,
+    only_second: Vec<String>,
+    changed: Vec<Changed>,
+}
+
+#[derive(Serialize)]
+struct Changed {
+    key: String,
+    first: String,
+    second: String,
+}
+
+/// Print `- <key>` for keys only in the first database, `+ <key>` for keys only
+/// in the second one and `~ <key>` for keys with different values, then a summary
+fn diff_cmd(global: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let first_name = args.value_of("FIRST").unwrap();
+    let second_name = args.value_of("SECOND").unwrap();
+    let first = open_existing(first_name, global)?.to_hashmap()?;
+    let mut second = open_existing(second_name, global)?.to_hashmap()?;
+    let mut diff = Diff::default();
+    for (key, value) in first {
+        match second.remove(&key) {
+            None => diff.only_first.push(key),
+            Some(other) if other != value => diff.changed.push(Changed {
+                key,
+                first: value,
+                second: other,
+            }),
+            Some(_) => {}
+        }
+    }
+    diff.only_second.extend(second.into_keys());
+    diff.only_first.sort();
+    diff.only_second.sort();
+    diff.changed.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut out = BufWriter::new(stdout());
+    if args.is_present("json") {
+        serde_json::to_writer(&mut out, &diff)?;
+        out.write_all(b"\n")?;
+    } else {
+        for key in &diff.only_first {
+            writeln!(out, "- {}", key)?;
+        }
+        for key in &diff.only_second {
+            writeln!(out, "+ {}", key)?;
+        }
+        for changed in &diff.changed {
+            writeln!(out, "~ {}", changed.key)?;
+        }
+        writeln!(
+            out,
+            "{} only in {}, {} only in {}, {} changed",
+            diff.only_first.len(),
+            first_name,
+            diff.only_second.len(),
+            second_name,
+            diff.changed.len()
+        )?;
+    }
+    out.flush()?;
+    if !(diff.only_first.is_empty() && diff.only_second.is_empty() && diff.changed.is_empty()) {
+        exit(1)
+    }
+    Ok(())
+}
+
+/// Open store, which must exist, so that a typo in path isn't reported as empty database
+fn open_existing(db_name: &str, args: &ArgMatches) -> Result<KvStore> {
+    ensure_exists(db_name)?;
+    open_store_at(db_name, args)
+}
+
+/// Fail if database doesn't exist, instead of creating it on open
+fn ensure_exists(db_name: &str) -> Result<()> {
+    if !Path::new(db_name).is_file() {
+        return Err(KvsError::InvalidPath {
+            reason: format!("'{}' doesn't exist", db_name),
+        });
+    }
+    Ok(())
+}
+
+/// Print current value and then every change of it as `<unix time in ms> <value>` lines.
+/// Removed key is printed as `Key not found`. Runs until killed, e.g. by Ctrl-C
+fn watch_cmd(db_name: &str, args: &ArgMatches) -> Result<()> {
+    let mut watcher = Watcher::new(db_name, args.value_of("KEY").unwrap())?;
+    if let Some(value) = watcher.value() {
+        print_change(Some(value));
+    }
+    loop {
+        sleep(WATCH_INTERVAL);
+        for change in watcher.poll()? {
+            print_change(change.as_deref());
         }
-        Err(err) => return Err(err),
     }
 }
 
-fn shell_cmd(db_name: &str, _: &ArgMatches) -> Result<()> {
-    let store = KvStore::new(db_name)?;
-    let mut shell = Shell::create(store);
+fn print_change(value: Option<&str>) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    println!("{} {}", now, value.unwrap_or("Key not found"));
+}
+
+fn rm_cmd(store: &mut KvStore, args: &ArgMatches) -> Result<()> {
+    store.remove(args.value_of("KEY").unwrap())
+}
+
+fn shell_cmd(global: &ArgMatches<'static>, args: &ArgMatches) -> Result<()> {
+    let mut shell = Shell::create(open_store(global)?);
+    // Databases switched to by `use` are opened with the same options
+    let global = global.clone();
+    shell.set_opener(move |path| open_store_at(path, &global));
+    if let Some(interval) = args.value_of("compact-interval") {
+        let interval = parse_duration(interval).expect("duration is validated by clap");
+        shell.set_compact_interval(interval);
+    }
+    if let Some(path) = args.value_of("script") {
+        let script = BufReader::new(File::open(path)?);
+        if !shell.run_script(script, args.is_present("keep-going"))? {
+            exit(1)
+        }
+        return Ok(());
+    }
     match shell.start() {
         Ok(_) => Ok(()),
         Err(err) => {
@@ -111,3 +745,88 @@ fn shell_cmd(db_name: &str, _: &ArgMatches) -> Result<()> {
         }
     }
 }
+
+fn bench_cmd(global: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let ops = value_t!(args, "ops", usize)?;
+    let value_size = value_t!(args, "value-size", usize)?;
+    let read_ratio =
+        parse_ratio(args.value_of("read-ratio").unwrap()).expect("ratio is validated by clap");
+    // Directory is removed along with the database and its blobs, also on error
+    let dir = TempDir::new()?;
+    run_bench(
+        global,
+        &dir.path().join("kvs.db"),
+        ops,
+        value_size,
+        read_ratio,
+    )
+}
+
+/// Parse number from 0 to 1
+fn parse_ratio(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!(
+            "invalid ratio '{}', expected number from 0 to 1",
+            s
+        )),
+    }
+}
+
+fn run_bench(
+    global: &ArgMatches,
+    db_path: &Path,
+    ops: usize,
+    value_size: usize,
+    read_ratio: f64,
+) -> Result<()> {
+    let mut store = open_store_at(&db_path.to_string_lossy(), global)?;
+    let keys = (ops / 10).max(1);
+    let value = "x".repeat(value_size);
+    for i in 0..keys {
+        store.set(&format!("key{}", i), &value)?;
+    }
+
+    // xorshift is enough to shuffle workload, without pulling `rand` into the binary
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next_random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    let mut latencies = Vec::with_capacity(ops);
+    let mut reads = 0;
+    let started = Instant::now();
+    for _ in 0..ops {
+        let key = format!("key{}", next_random() as usize % keys);
+        let is_read = (next_random() % 10_000) as f64 / 10_000.0 < read_ratio;
+        let op_started = Instant::now();
+        if is_read {
+            reads += 1;
+            store.get(&key)?;
+        } else {
+            store.set(&key, &value)?;
+        }
+        latencies.push(op_started.elapsed());
+    }
+    let elapsed = started.elapsed();
+    latencies.sort();
+
+    let percentile = |p: usize| latencies.get(latencies.len() * p / 100).copied();
+    println!("ops: {} ({} reads, {} writes)", ops, reads, ops - reads);
+    println!("elapsed: {:?}", elapsed);
+    println!(
+        "throughput: {:.0} ops/s",
+        ops as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    println!(
+        "latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(50).unwrap_or_default(),
+        percentile(90).unwrap_or_default(),
+        percentile(99).unwrap_or_default(),
+        latencies.last().copied().unwrap_or_default()
+    );
+    println!("compactions: {}", store.stats().compactions);
+    Ok(())
+}
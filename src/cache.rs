@@ -1,7 +1,10 @@
 use crate::kv::Log;
-use crate::{Cache, Result};
-use std::collections::HashMap;
+use crate::{Cache, RecordMeta, Result};
+use std::collections::{BTreeMap, HashMap};
 
+const DEFAULT_LRU_CAPACITY: usize = 1024;
+
+/// Unbounded cache, which keeps all live records in memory
 pub struct InMemoryMapCache {
     cache: HashMap<String, SizedLog>,
     uncompacted: usize,
@@ -10,39 +13,72 @@ pub struct InMemoryMapCache {
 struct SizedLog {
     log: Log,
     size: usize,
+    /// Location and write time of the record in storage, if they are known
+    meta: Option<RecordMeta>,
 }
 
 impl SizedLog {
-    fn new(log: Log, size: usize) -> Self {
-        Self { log, size }
+    fn new(log: Log, size: usize, meta: Option<RecordMeta>) -> Self {
+        Self { log, size, meta }
     }
 }
 
-impl Cache for InMemoryMapCache {
-    fn new() -> Result<Self> {
-        Ok(Self {
-            cache: HashMap::new(),
+impl InMemoryMapCache {
+    /// Create cache with space preallocated for `capacity` keys,
+    /// which saves rehashing during replay of large databases
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            cache: HashMap::with_capacity(capacity),
             uncompacted: 0,
-        })
+        }
     }
 
-    fn insert(&mut self, log: Log, size: usize) -> Result<()> {
+    /// Insert record, keeping its metadata if it's known
+    fn insert_log(&mut self, log: Log, size: usize, meta: Option<RecordMeta>) -> Result<()> {
         match &log {
             Log::Remove(k) => {
-                self.uncompacted += size;
+                self.uncompacted = self.uncompacted.saturating_add(size);
                 if let Some(l) = self.cache.remove(k) {
-                    self.uncompacted += l.size;
+                    self.uncompacted = self.uncompacted.saturating_add(l.size);
                 }
             }
-            Log::Set(k, _) => {
-                let old = self.cache.insert(k.clone(), SizedLog::new(log, size));
+            Log::Set(k, _) | Log::SetEx(k, _, _) | Log::SetBlob(k, _, _) => {
+                let old = self.cache.insert(k.clone(), SizedLog::new(log, size, meta));
                 if let Some(item) = old {
-                    self.uncompacted += item.size
+                    self.uncompacted = self.uncompacted.saturating_add(item.size);
                 }
             }
+            Log::Clear => {
+                self.uncompacted = self.uncompacted.saturating_add(size);
+                self.uncompacted = self
+                    .uncompacted
+                    .saturating_add(self.cache.values().map(|l| l.size).sum::<usize>());
+                self.cache.clear();
+            }
         }
         Ok(())
     }
+}
+
+impl Cache for InMemoryMapCache {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            cache: HashMap::new(),
+            uncompacted: 0,
+        })
+    }
+
+    fn insert(&mut self, log: Log, size: usize) -> Result<()> {
+        self.insert_log(log, size, None)
+    }
+
+    fn insert_at(&mut self, log: Log, meta: RecordMeta) -> Result<()> {
+        self.insert_log(log, meta.size, Some(meta))
+    }
+
+    fn meta(&self, key: &str) -> Result<Option<RecordMeta>> {
+        Ok(self.cache.get(key).and_then(|l| l.meta))
+    }
 
     fn get(&self, key: &str) -> Result<Option<Log>> {
         match self.cache.get(key) {
@@ -51,6 +87,10 @@ impl Cache for InMemoryMapCache {
         }
     }
 
+    fn get_ref(&self, key: &str) -> Result<Option<&Log>> {
+        Ok(self.cache.get(key).map(|sized_log| &sized_log.log))
+    }
+
     fn get_mut(&mut self, key: &str) -> Result<Option<&mut Log>> {
         match self.cache.get_mut(key) {
             Some(sized_log) => Ok(Some(&mut sized_log.log)),
@@ -59,11 +99,183 @@ impl Cache for InMemoryMapCache {
     }
 
     fn get_all(&self) -> Vec<&Log> {
-        let logs = self.cache.iter().map(|(_, v)| &v.log).collect();
-        logs
+        self.cache.values().map(|v| &v.log).collect()
+    }
+
+    fn uncompacted_space(&self) -> usize {
+        self.uncompacted
+    }
+
+    fn live_size(&self) -> usize {
+        self.cache.values().map(|l| l.size).sum()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.cache.clear();
+        self.uncompacted = 0;
+        Ok(())
+    }
+
+    fn reset_uncompacted(&mut self) {
+        self.uncompacted = 0;
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.cache.remove(key);
+        Ok(())
+    }
+
+    fn evicts(&self) -> bool {
+        false
+    }
+}
+
+/// Bounded cache, which keeps up to `capacity` records in memory,
+/// evicting least recently used ones.
+/// Space of overwritten records which were evicted before can't be accounted,
+/// so uncompacted space counter is a lower bound
+pub struct LruCache {
+    capacity: usize,
+    cache: HashMap<String, (SizedLog, u64)>,
+    /// Keys ordered by the last access
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    uncompacted: usize,
+}
+
+impl LruCache {
+    /// Create cache, which holds up to `capacity` records
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            cache: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            uncompacted: 0,
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove_key(&mut self, key: &str) -> Option<SizedLog> {
+        let (log, tick) = self.cache.remove(key)?;
+        self.order.remove(&tick);
+        Some(log)
+    }
+
+    /// Insert record, keeping its metadata if it's known
+    fn insert_log(&mut self, log: Log, size: usize, meta: Option<RecordMeta>) -> Result<()> {
+        match &log {
+            Log::Remove(k) => {
+                self.uncompacted = self.uncompacted.saturating_add(size);
+                if let Some(l) = self.remove_key(k) {
+                    self.uncompacted = self.uncompacted.saturating_add(l.size);
+                }
+            }
+            Log::Set(k, _) | Log::SetEx(k, _, _) | Log::SetBlob(k, _, _) => {
+                let k = k.clone();
+                if let Some(old) = self.remove_key(&k) {
+                    self.uncompacted = self.uncompacted.saturating_add(old.size);
+                }
+                let tick = self.next_tick();
+                self.order.insert(tick, k.clone());
+                self.cache.insert(k, (SizedLog::new(log, size, meta), tick));
+                while self.cache.len() > self.capacity {
+                    let oldest = match self.order.keys().next() {
+                        Some(&tick) => tick,
+                        None => break,
+                    };
+                    if let Some(key) = self.order.remove(&oldest) {
+                        self.cache.remove(&key);
+                    }
+                }
+            }
+            Log::Clear => {
+                self.uncompacted = self.uncompacted.saturating_add(size);
+                self.uncompacted = self
+                    .uncompacted
+                    .saturating_add(self.cache.values().map(|(l, _)| l.size).sum::<usize>());
+                self.cache.clear();
+                self.order.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Cache for LruCache {
+    /// Create cache with default capacity
+    fn new() -> Result<Self> {
+        Ok(Self::with_capacity(DEFAULT_LRU_CAPACITY))
+    }
+
+    fn insert(&mut self, log: Log, size: usize) -> Result<()> {
+        self.insert_log(log, size, None)
+    }
+
+    fn insert_at(&mut self, log: Log, meta: RecordMeta) -> Result<()> {
+        self.insert_log(log, meta.size, Some(meta))
+    }
+
+    fn meta(&self, key: &str) -> Result<Option<RecordMeta>> {
+        Ok(self.cache.get(key).and_then(|(l, _)| l.meta))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Log>> {
+        Ok(self.cache.get(key).map(|(l, _)| l.log.clone()))
+    }
+
+    /// Return reference of Log for given key, without marking it as recently used
+    fn get_ref(&self, key: &str) -> Result<Option<&Log>> {
+        Ok(self.cache.get(key).map(|(l, _)| &l.log))
+    }
+
+    /// Return mutable reference of Log for given key, marking it as recently used
+    fn get_mut(&mut self, key: &str) -> Result<Option<&mut Log>> {
+        let tick = self.next_tick();
+        match self.cache.get_mut(key) {
+            Some((sized_log, last_used)) => {
+                self.order.remove(last_used);
+                self.order.insert(tick, key.to_owned());
+                *last_used = tick;
+                Ok(Some(&mut sized_log.log))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn get_all(&self) -> Vec<&Log> {
+        self.cache.values().map(|(l, _)| &l.log).collect()
     }
 
     fn uncompacted_space(&self) -> usize {
         self.uncompacted
     }
+
+    fn live_size(&self) -> usize {
+        self.cache.values().map(|(l, _)| l.size).sum()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.cache.clear();
+        self.order.clear();
+        self.uncompacted = 0;
+        Ok(())
+    }
+
+    fn reset_uncompacted(&mut self) {
+        self.uncompacted = 0;
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.remove_key(key);
+        Ok(())
+    }
+
+    fn evicts(&self) -> bool {
+        true
+    }
 }
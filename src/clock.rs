@@ -0,0 +1,54 @@
+/// This module contains source of time used by KvStore for TTL and timestamps of records
+use crate::kv::now_millis;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Source of current unix time in milliseconds
+pub trait Clock: Send {
+    /// Return current unix time in milliseconds
+    fn now(&self) -> u64;
+}
+
+/// Clock of the system, used by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        now_millis()
+    }
+}
+
+/// Clock, which moves only when it's told to, e.g. to expire keys in tests without sleeping.
+/// Clones share the time, so a clone kept outside of the store controls its clock
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    now: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    /// Create clock, which stands at given unix time in milliseconds
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    /// Move clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        self.now
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Set clock to given unix time in milliseconds
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
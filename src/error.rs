@@ -6,16 +6,154 @@ pub enum KvsError {
     /// IO error.
     #[fail(display = "{}", _0)]
     Io(#[cause] io::Error),
+    /// IO error with description of the operation, which failed
+    #[fail(display = "{}: {}", context, cause)]
+    IoContext {
+        /// Failed operation, e.g. path of the file, which can't be opened
+        context: String,
+        /// Original error
+        #[cause]
+        cause: io::Error,
+    },
+    /// Path can't be used as a database file.
+    #[fail(display = "invalid database path: {}", reason)]
+    InvalidPath {
+        /// Description of the problem
+        reason: String,
+    },
+    /// Database is required to be new, but it already has data.
+    #[fail(display = "database '{}' already exists", path)]
+    AlreadyExists {
+        /// Path of the database file
+        path: String,
+    },
     /// Serialization or deserialization error.
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Record in storage is larger than allowed, see [`KvsError::ValueTooLarge`] for writes.
+    #[fail(
+        display = "record at offset {} exceeds size limit of {} bytes",
+        offset, limit
+    )]
+    RecordTooLarge {
+        /// Offset of the record start
+        offset: usize,
+        /// Maximum size of a record
+        limit: usize,
+    },
+    /// Record to be written is larger than allowed.
+    #[fail(
+        display = "record of {} bytes exceeds size limit of {} bytes",
+        size, limit
+    )]
+    ValueTooLarge {
+        /// Size of the serialized record, including line terminator
+        size: usize,
+        /// Maximum size of a record
+        limit: usize,
+    },
+    /// Sharded store is opened with amount of shards, different from the one it was created with.
+    #[fail(
+        display = "store has {} shards, but was opened with {}",
+        found, expected
+    )]
+    ShardCountMismatch {
+        /// Requested amount of shards
+        expected: usize,
+        /// Amount of shards on disk
+        found: usize,
+    },
+    /// File isn't a database log.
+    #[fail(display = "unknown format of database file")]
+    UnknownFormat,
+    /// Database log is written in format of unsupported version.
+    #[fail(
+        display = "database has format version {}, but only {} is supported",
+        found, expected
+    )]
+    VersionMismatch {
+        /// Version supported by this build
+        expected: u32,
+        /// Version of the file
+        found: u32,
+    },
+    /// Line of imported data can't be parsed.
+    #[fail(display = "malformed input at line {}", line)]
+    MalformedInput {
+        /// Number of the line, starting from 1
+        line: usize,
+    },
     /// Removing non-existent key error.
     #[fail(display = "Key not found")]
     KeyNotFound,
+    /// Value has a type, which isn't supported by the operation.
+    #[fail(display = "expected value of type {}, found {}", expected, found)]
+    WrongType {
+        /// Type required by the operation
+        expected: &'static str,
+        /// Actual type of the value
+        found: &'static str,
+    },
+    /// Result of arithmetic operation doesn't fit into the value.
+    #[fail(display = "integer overflow")]
+    IntegerOverflow,
+    /// Value is rejected by validator of the store.
+    #[fail(display = "validation failed: {}", _0)]
+    ValidationFailed(String),
+    /// Overwriting of existing key in write-once store.
+    #[fail(display = "Key already exists")]
+    KeyExists,
     #[fail(display = "{}", _0)]
     Clap(#[cause] clap::Error),
 }
 
+/// Stable code of the error kind, e.g. to choose exit code of a process.
+/// Value of every code is kept between releases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// Key doesn't exist
+    NotFound = 2,
+    /// Database already exists, but is required to be new
+    Exists = 3,
+    /// Data can't be changed, e.g. existing key of write-once store
+    ReadOnly = 4,
+    /// Reading or writing of a file failed
+    Io = 5,
+    /// Serialization or deserialization failed
+    Serde = 6,
+    /// Database file is damaged or isn't a database at all
+    Corrupt = 7,
+    /// Database was written by incompatible version or configuration
+    Incompatible = 8,
+    /// Argument of the operation is rejected, e.g. invalid path or value of wrong type
+    InvalidInput = 9,
+}
+
+impl KvsError {
+    /// Return stable code of the error kind
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::NotFound,
+            KvsError::AlreadyExists { .. } => ErrorCode::Exists,
+            KvsError::KeyExists => ErrorCode::ReadOnly,
+            KvsError::Io(_) | KvsError::IoContext { .. } => ErrorCode::Io,
+            KvsError::Serde(_) => ErrorCode::Serde,
+            KvsError::RecordTooLarge { .. } | KvsError::UnknownFormat => ErrorCode::Corrupt,
+            KvsError::ShardCountMismatch { .. } | KvsError::VersionMismatch { .. } => {
+                ErrorCode::Incompatible
+            }
+            KvsError::InvalidPath { .. }
+            | KvsError::MalformedInput { .. }
+            | KvsError::WrongType { .. }
+            | KvsError::IntegerOverflow
+            | KvsError::ValidationFailed(_)
+            | KvsError::ValueTooLarge { .. }
+            | KvsError::Clap(_) => ErrorCode::InvalidInput,
+        }
+    }
+}
+
 impl From<io::Error> for KvsError {
     fn from(err: io::Error) -> KvsError {
         KvsError::Io(err)
@@ -34,6 +172,20 @@ impl From<clap::Error> for KvsError {
     }
 }
 
+/// Attach description of the failed operation to IO errors
+pub(crate) trait Context<T> {
+    fn context<F: FnOnce() -> String>(self, f: F) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context<F: FnOnce() -> String>(self, f: F) -> Result<T> {
+        self.map_err(|cause| KvsError::IoContext {
+            context: f(),
+            cause,
+        })
+    }
+}
+
 /// Custom Result type to wrap all errors,
 /// which possible during work with KvStore
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -0,0 +1,73 @@
+/// This module contains order of keys for stores with limited amount of keys.
+/// Keys over the limit are removed from the store, so unlike eviction from cache
+/// it changes the data
+use std::collections::{BTreeMap, HashMap};
+
+/// Which key is evicted, when store would have more keys than allowed,
+/// see [`KvStoreBuilder::set_max_keys`](crate::KvStoreBuilder::set_max_keys)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Key, which was written the longest time ago. Reads don't change the order
+    Fifo,
+    /// Key, which was read or written the longest time ago. Reads aren't stored,
+    /// so after the store is opened again keys are ordered by their write time
+    Lru,
+}
+
+/// Keys of the store ordered from the first one to evict to the last one
+pub(crate) struct KeyOrder {
+    limit: usize,
+    policy: EvictionPolicy,
+    /// Position of every key in `order`
+    ranks: HashMap<String, u64>,
+    order: BTreeMap<u64, String>,
+    next_rank: u64,
+}
+
+impl KeyOrder {
+    pub(crate) fn new(limit: usize, policy: EvictionPolicy) -> Self {
+        Self {
+            limit,
+            policy,
+            ranks: HashMap::new(),
+            order: BTreeMap::new(),
+            next_rank: 0,
+        }
+    }
+
+    pub(crate) fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Move key to the end of the order, adding it if it's new
+    pub(crate) fn push(&mut self, key: &str) {
+        if let Some(rank) = self.ranks.get_mut(key) {
+            let key = self.order.remove(rank).expect("ranked key is ordered");
+            *rank = self.next_rank;
+            self.order.insert(self.next_rank, key);
+        } else {
+            self.ranks.insert(key.to_owned(), self.next_rank);
+            self.order.insert(self.next_rank, key.to_owned());
+        }
+        self.next_rank += 1;
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(rank) = self.ranks.remove(key) {
+            self.order.remove(&rank);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.ranks.clear();
+        self.order.clear();
+    }
+
+    /// Return the first key to evict, if there are more keys than allowed
+    pub(crate) fn victim(&self) -> Option<&str> {
+        if self.ranks.len() <= self.limit {
+            return None;
+        }
+        self.order.values().next().map(String::as_str)
+    }
+}
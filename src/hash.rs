@@ -0,0 +1,7 @@
+/// 64-bit FNV-1a hash. Unlike `DefaultHasher` it's guaranteed to not change between releases,
+/// so it's used where the hash is persisted, e.g. names of blobs or placement of keys in shards
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
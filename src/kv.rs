@@ -2,28 +2,321 @@
 //! This crate defines simple key-value storage
 //! with basic create-read-delete operations
 use crate::cache::InMemoryMapCache;
-use crate::error::{KvsError, Result};
-use crate::storage::FileStorage;
-use serde::{Deserialize, Serialize};
+use crate::clock::{Clock, SystemClock};
+use crate::error::{Context, KvsError, Result};
+use crate::eviction::{EvictionPolicy, KeyOrder};
+use crate::hash::fnv1a;
+use crate::storage::{
+    append_history, blob_dir, history_path, open_db, parse_timed, read_line, scan_lines,
+    FileStorage, LogReader, SyncPolicy, DEFAULT_MAX_RECORD_SIZE, DEFAULT_READ_BUFFER_SIZE,
+};
+use crate::value::Value;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{
+    copy as copy_file, create_dir_all, metadata, read, read_dir, read_to_string, remove_file,
+    write, OpenOptions,
+};
+use std::io::{BufRead, BufReader, ErrorKind, Seek, SeekFrom, Write};
+use std::ops::Index;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
 
 const UNCOMPACTED_THREESHOLD: usize = 1024 * 1024;
+/// Minimal share of reclaimable space in storage, which triggers automatic compaction
+const UNCOMPACTED_RATIO: f64 = 0.5;
+/// Amount of records, which [`KvStore::load_tsv`] writes at once
+const TSV_BATCH_SIZE: usize = 1024;
+/// Size of an average record, which amount of keys is estimated by,
+/// see [`KvStoreBuilder::cache_capacity`]. Overwrites and larger values make records
+/// per key larger, so it errs on the side of fewer keys
+const ESTIMATED_RECORD_SIZE: u64 = 128;
+/// Maximum amount of keys to preallocate cache for without explicit capacity,
+/// which limits memory wasted for logs of few large values
+const MAX_ESTIMATED_KEYS: u64 = 1 << 20;
 
-/// Represent different database operations
-/// last argument in all entries is a length of log
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Check of key and value before they are written, see [`KvStoreBuilder::set_value_validator`]
+type ValueValidator = Box<dyn Fn(&str, &str) -> std::result::Result<(), String> + Send>;
+
+/// Approximate memory used by a cache entry besides its contents: map slot with the copy
+/// of the key, the record itself, its size and metadata
+const CACHE_ENTRY_OVERHEAD: usize = std::mem::size_of::<String>()
+    + std::mem::size_of::<Log>()
+    + std::mem::size_of::<usize>()
+    + std::mem::size_of::<Option<RecordMeta>>();
+
+/// Represent different database operations.
+/// Records are stored internally tagged, e.g. `{"op":"set","key":"k","value":"v"}`,
+/// while records of the legacy format, e.g. `{"Set":["k","v"]}`, are still readable
+#[derive(Debug, Clone, PartialEq)]
 pub enum Log {
-    Set(String, String),
+    /// Set key to value
+    Set(String, Value),
+    /// Remove key
+    Remove(String),
+    /// Marker, which drops all records written before it
+    Clear,
+    /// Set key to value, which expires at given unix time in milliseconds
+    SetEx(String, Value, u64),
+    /// Set key to value, stored out-of-line in a separate file.
+    /// Contains name of the file in `{db}.blobs` directory and length of the value
+    SetBlob(String, String, usize),
+}
+
+impl Log {
+    /// Return key of the record, if it's related to a single key
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            Log::Set(k, _) | Log::SetEx(k, _, _) | Log::SetBlob(k, _, _) | Log::Remove(k) => {
+                Some(k)
+            }
+            Log::Clear => None,
+        }
+    }
+
+    /// Return `true` if the record sets a value, which isn't expired at `now`
+    pub(crate) fn is_live(&self, now: u64) -> bool {
+        match self {
+            Log::Set(..) | Log::SetBlob(..) => true,
+            Log::SetEx(_, _, expires_at) => *expires_at > now,
+            _ => false,
+        }
+    }
+
+    /// Return the same record for another key. Records, which aren't related to a key,
+    /// are returned as is
+    fn with_key(self, key: String) -> Log {
+        match self {
+            Log::Set(_, value) => Log::Set(key, value),
+            Log::SetEx(_, value, expires_at) => Log::SetEx(key, value, expires_at),
+            Log::SetBlob(_, name, len) => Log::SetBlob(key, name, len),
+            Log::Remove(_) => Log::Remove(key),
+            Log::Clear => Log::Clear,
+        }
+    }
+
+    /// Return amount of heap memory owned by the record, ignoring unused capacity
+    fn heap_size(&self) -> usize {
+        let value = |v: &Value| match v {
+            Value::Str(s) => s.len(),
+            Value::Bytes(b) => b.len(),
+            _ => 0,
+        };
+        match self {
+            Log::Set(k, v) | Log::SetEx(k, v, _) => k.len() + value(v),
+            Log::SetBlob(k, name, _) => k.len() + name.len(),
+            Log::Remove(k) => k.len(),
+            Log::Clear => 0,
+        }
+    }
+
+    /// Return name of the blob file, if the record refers to one
+    fn blob(&self) -> Option<&str> {
+        match self {
+            Log::SetBlob(_, name, _) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Return textual value of the record, if it sets a value, which isn't expired at `now`.
+    /// Values stored out-of-line are not available, see [`read_value`]
+    pub(crate) fn live_value(&self, now: u64) -> Option<Cow<'_, str>> {
+        match self {
+            Log::Set(_, v) => Some(v.to_text()),
+            Log::SetEx(_, v, expires_at) if *expires_at > now => Some(v.to_text()),
+            _ => None,
+        }
+    }
+}
+
+/// Storage representation of [`Log`]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record<'a> {
+    Set {
+        key: Cow<'a, str>,
+        value: Cow<'a, Value>,
+    },
+    Remove {
+        key: Cow<'a, str>,
+    },
+    Clear,
+    SetEx {
+        key: Cow<'a, str>,
+        value: Cow<'a, Value>,
+        expires_at: u64,
+    },
+    SetBlob {
+        key: Cow<'a, str>,
+        blob: Cow<'a, str>,
+        len: usize,
+    },
+}
+
+/// Positional representation of [`Log`], written by previous versions
+#[derive(Deserialize)]
+enum LegacyRecord {
+    Set(String, Value),
     Remove(String),
+    Clear,
+    SetEx(String, Value, u64),
+    SetBlob(String, String, usize),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnyRecord<'a> {
+    Tagged(Stamped<'a>),
+    Legacy(LegacyRecord),
+}
+
+/// Record along with unix time in milliseconds, when it was written.
+/// Time is missing in legacy records and in records of unknown time, so it defaults to 0
+#[derive(Serialize, Deserialize)]
+struct Stamped<'a> {
+    #[serde(flatten)]
+    record: Record<'a>,
+    #[serde(default)]
+    ts: u64,
+}
+
+/// Return `true` if the line holds record of the legacy positional format
+pub(crate) fn is_legacy_record(line: &str) -> bool {
+    serde_json::from_str::<LegacyRecord>(line.trim_end_matches(['\n', '\r'])).is_ok()
+}
+
+/// Record read from storage along with its write time, 0 if it's unknown
+pub(crate) struct Timed(pub Log, pub u64);
+
+/// Serialize record along with time `ts`, when it's written
+pub(crate) fn write_stamped<W: std::io::Write>(
+    writer: W,
+    log: &Log,
+    ts: u64,
+) -> serde_json::Result<()> {
+    let record = Record::from(log);
+    serde_json::to_writer(writer, &Stamped { record, ts })
+}
+
+impl<'a> From<&'a Log> for Record<'a> {
+    fn from(log: &'a Log) -> Self {
+        match log {
+            Log::Set(k, v) => Record::Set {
+                key: Cow::Borrowed(k),
+                value: Cow::Borrowed(v),
+            },
+            Log::Remove(k) => Record::Remove {
+                key: Cow::Borrowed(k),
+            },
+            Log::Clear => Record::Clear,
+            Log::SetEx(k, v, expires_at) => Record::SetEx {
+                key: Cow::Borrowed(k),
+                value: Cow::Borrowed(v),
+                expires_at: *expires_at,
+            },
+            Log::SetBlob(k, blob, len) => Record::SetBlob {
+                key: Cow::Borrowed(k),
+                blob: Cow::Borrowed(blob),
+                len: *len,
+            },
+        }
+    }
+}
+
+impl Serialize for Log {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Record::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Log {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(Timed::deserialize(deserializer)?.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(match AnyRecord::deserialize(deserializer)? {
+            AnyRecord::Tagged(Stamped { record, ts }) => {
+                let log = match record {
+                    Record::Set { key, value } => Log::Set(key.into_owned(), value.into_owned()),
+                    Record::Remove { key } => Log::Remove(key.into_owned()),
+                    Record::Clear => Log::Clear,
+                    Record::SetEx {
+                        key,
+                        value,
+                        expires_at,
+                    } => Log::SetEx(key.into_owned(), value.into_owned(), expires_at),
+                    Record::SetBlob { key, blob, len } => {
+                        Log::SetBlob(key.into_owned(), blob.into_owned(), len)
+                    }
+                };
+                Timed(log, ts)
+            }
+            AnyRecord::Legacy(record) => {
+                let log = match record {
+                    LegacyRecord::Set(k, v) => Log::Set(k, v),
+                    LegacyRecord::Remove(k) => Log::Remove(k),
+                    LegacyRecord::Clear => Log::Clear,
+                    LegacyRecord::SetEx(k, v, expires_at) => Log::SetEx(k, v, expires_at),
+                    LegacyRecord::SetBlob(k, blob, len) => Log::SetBlob(k, blob, len),
+                };
+                Timed(log, 0)
+            }
+        })
+    }
+}
+
+/// Return value of the record, reading it from `blob_dir` if it's stored out-of-line
+pub(crate) fn read_value<'a>(
+    blob_dir: &Path,
+    log: &'a Log,
+    now: u64,
+) -> Result<Option<Cow<'a, str>>> {
+    match log {
+        Log::SetBlob(_, name, _) => {
+            let path = blob_dir.join(name);
+            let value = read_to_string(&path)
+                .context(|| format!("failed to read blob '{}'", path.display()))?;
+            Ok(Some(Cow::Owned(value)))
+        }
+        _ => Ok(log.live_value(now)),
+    }
+}
+
+/// Return current unix time in milliseconds
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 /// Public trait, which should be implemented by all storages, which want to work as a KvStore.storage
 pub trait Storage: Iterator<Item = Result<(Log, usize)>> + Sized {
     /// Create new storage instance
-    fn new(db_name: &str) -> Result<Self>;
+    fn new<P: AsRef<Path>>(path: P) -> Result<Self>;
     /// Write value to a internal storage. Return result with amount of bytes writed
     fn write(&mut self, value: &Log) -> Result<usize>;
+    /// Write values at once, so either all of them or none are written, unless storage
+    /// fails in the middle. Return sizes of written values in the same order
+    fn write_batch(&mut self, values: &[Log]) -> Result<Vec<usize>> {
+        values.iter().map(|value| self.write(value)).collect()
+    }
     /// Override WAL file by values in Vec<&Log>
     fn override_storage(&mut self, values: Vec<&Log>) -> Result<()>;
+    /// Drop all values, so storage is empty and iteration starts over
+    fn truncate(&mut self) -> Result<()>;
+    /// Return actual size of storage in bytes
+    fn size(&self) -> Result<usize>;
+    /// Make sure all written values reached the durable storage
+    fn flush(&mut self) -> Result<()>;
 }
 
 /// Public trait which should be implemented by all structs, which want to interact with KvStore as cache
@@ -32,117 +325,2383 @@ pub trait Cache: Sized {
     fn new() -> Result<Self>;
     /// Insert result to cache. Take ownership of `log`. Second argument is a size of log entry
     fn insert(&mut self, log: Log, size: usize) -> Result<()>;
+    /// Insert result to cache along with location and write time of the record in storage.
+    /// Caches, which don't keep metadata, may ignore everything but size
+    fn insert_at(&mut self, log: Log, meta: RecordMeta) -> Result<()> {
+        self.insert(log, meta.size)
+    }
+    /// Return metadata of cached record for given key, if it's known
+    fn meta(&self, _key: &str) -> Result<Option<RecordMeta>> {
+        Ok(None)
+    }
     /// Get `Log` for given key. Return owned value.
     fn get(&self, key: &str) -> Result<Option<Log>>;
+    /// Return reference of Log for given key
+    fn get_ref(&self, key: &str) -> Result<Option<&Log>>;
     /// Return mutable reference of Log for given key
     fn get_mut(&mut self, key: &str) -> Result<Option<&mut Log>>;
     /// Return all logs in cache
     fn get_all(&self) -> Vec<&Log>;
     /// Return amount of space, which can be saved by removing old log entries
     fn uncompacted_space(&self) -> usize;
+    /// Return total size of cached records
+    fn live_size(&self) -> usize;
+    /// Remove all entries from cache and reset uncompacted space counter
+    fn clear(&mut self) -> Result<()>;
+    /// Reset uncompacted space counter, keeping entries, e.g. after compaction
+    fn reset_uncompacted(&mut self);
+    /// Drop entry of the key from cache. Record stays live in storage,
+    /// so uncompacted space isn't changed
+    fn remove(&mut self, key: &str) -> Result<()>;
+    /// Return `true` if cache may drop entries by itself (e.g. when it's full),
+    /// so it can't be used as a full copy of live records
+    fn evicts(&self) -> bool;
+}
+
+/// Transformation of keys, applied before they reach cache and storage, e.g. normalization.
+/// Transformed keys are stored on disk, so the same transform should be used
+/// every time database is opened, otherwise keys written before can't be found
+pub trait KeyTransform: Send {
+    /// Return key, which should be used instead of the given one
+    fn transform(&self, key: &str) -> String;
+}
+
+impl<F: Fn(&str) -> String + Send> KeyTransform for F {
+    fn transform(&self, key: &str) -> String {
+        self(key)
+    }
+}
+
+/// Runtime statistics of KvStore
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Amount of compactions ran since the store was opened
+    pub compactions: usize,
+    /// Amount of bytes, which can be saved by compaction
+    pub uncompacted_space: usize,
+    /// Size of the storage file in bytes, 0 if it can't be read
+    pub disk_usage: u64,
+    /// Total size of live records in bytes, see [`KvStore::live_data_size`]
+    pub live_data_size: usize,
+    /// Amount of operations, which took longer than slow threshold
+    pub slow_ops: usize,
+    /// Amount of live keys in cache. Lower bound if the cache evicts records
+    pub live_keys: usize,
+    /// Amount of cached records, which differed from storage and were corrected,
+    /// see [`KvStoreBuilder::read_repair`]
+    pub read_repairs: usize,
+    /// Approximate memory used by cache in bytes, see [`KvStore::estimate_memory`]
+    pub estimated_memory: usize,
+    /// Amount of key reads, which found the record in cache
+    pub cache_hits: usize,
+    /// Amount of key reads, which had to look the record up in storage
+    pub cache_misses: usize,
+    /// Amount of lookups of a single record in storage, i.e. log scans
+    /// or positioned reads if key index is enabled
+    pub disk_reads: usize,
+}
+
+impl Stats {
+    /// Return share of key reads served by cache, `None` if nothing was read yet
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let reads = self.cache_hits + self.cache_misses;
+        if reads == 0 {
+            return None;
+        }
+        Some(self.cache_hits as f64 / reads as f64)
+    }
+}
+
+/// Summary of loading records from storage to cache
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadReport {
+    /// Amount of records read from storage
+    pub records_replayed: usize,
+    /// Amount of live keys in cache after load
+    pub live_keys: usize,
+    /// Amount of bytes of replayed records, including header of the file
+    pub bytes_read: usize,
+    /// Amount of records, which weren't replayed, because replay stopped
+    /// at corrupted record. Includes the corrupted record itself. If there are records
+    /// after it, automatic compaction is suspended, so they stay on disk
+    pub records_skipped: usize,
+    /// Whether replay stopped at the last record, which has no line terminator,
+    /// e.g. left by interrupted write. It's counted as skipped and truncated,
+    /// so storage ends with the last complete record
+    pub torn_tail: bool,
+}
+
+/// Result of [`KvStore::set_many_report`]
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    /// Amount of written entries
+    pub succeeded: usize,
+    /// Index of every rejected entry along with the reason
+    pub failed: Vec<(usize, KvsError)>,
+}
+
+/// Location and write time of the record in storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordMeta {
+    /// Size of the record in bytes, including line terminator.
+    /// For values stored out-of-line it's the size of the reference, not of the value
+    pub size: usize,
+    /// Byte offset of the record start in storage file
+    pub offset: usize,
+    /// Unix time in milliseconds, when the record was written, 0 for legacy records.
+    /// Compaction keeps the original time
+    pub written_at: u64,
+}
+
+/// Record on disk, which would be dropped by compaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadRecord {
+    /// Key of the record, `None` for records clearing the whole store
+    pub key: Option<String>,
+    /// Size of the record in bytes, including line terminator
+    pub size: usize,
+    /// Why the record is dead
+    pub reason: DeadReason,
+}
+
+/// Reason of the record being dead, see [`DeadRecord`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadReason {
+    /// Value was overwritten, removed or cleared by a later record
+    Superseded,
+    /// Record removes key or clears the store
+    Tombstone,
+    /// Value has expired
+    Expired,
+}
+
+/// State of the key, see [`KvStore::get_status`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyStatus {
+    /// Key has live value
+    Present(String),
+    /// Key was removed, cleared or has expired
+    Deleted,
+    /// Key has never been set, as far as the log remembers
+    Absent,
+}
+
+/// Builder of [`KvStore`] with non-default configuration
+pub struct KvStoreBuilder {
+    path: PathBuf,
+    preload_cache: bool,
+    auto_compact: bool,
+    max_record_size: usize,
+    write_once: bool,
+    cache_capacity: Option<usize>,
+    sync_policy: SyncPolicy,
+    blob_threshold: Option<usize>,
+    slow_threshold: Option<Duration>,
+    key_transform: Option<Box<dyn KeyTransform>>,
+    compact_on_open: bool,
+    read_buffer_size: usize,
+    audit: bool,
+    dedup_blobs: bool,
+    clock: Box<dyn Clock>,
+    read_repair: bool,
+    length_prefixed: bool,
+    value_validator: Option<ValueValidator>,
+    create_new: bool,
+    key_index: bool,
+    #[cfg(feature = "parallel-compaction")]
+    parallel_compaction: bool,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    max_keys: Option<(usize, EvictionPolicy)>,
+}
+
+impl KvStoreBuilder {
+    /// Create builder for the database at given path
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            preload_cache: true,
+            auto_compact: true,
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            write_once: false,
+            cache_capacity: None,
+            sync_policy: SyncPolicy::Manual,
+            blob_threshold: None,
+            slow_threshold: None,
+            key_transform: None,
+            compact_on_open: false,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            audit: false,
+            dedup_blobs: false,
+            clock: Box::new(SystemClock),
+            read_repair: false,
+            length_prefixed: false,
+            value_validator: None,
+            create_new: false,
+            key_index: false,
+            #[cfg(feature = "parallel-compaction")]
+            parallel_compaction: false,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+            max_keys: None,
+        }
+    }
+
+    /// Load all records to cache on open. Enabled by default.
+    /// When disabled, records are cached on first access and reads of uncached keys go to disk,
+    /// which is the right choice for bounded caches like [`LruCache`](crate::LruCache).
+    pub fn preload_cache(mut self, preload: bool) -> Self {
+        self.preload_cache = preload;
+        self
+    }
+
+    /// Run compaction automatically after writes, once reclaimable space reaches 1 MiB
+    /// and at least half of the storage file size, so a log of mostly live records isn't
+    /// rewritten over and over. Enabled by default
+    pub fn auto_compact(mut self, enabled: bool) -> Self {
+        self.auto_compact = enabled;
+        self
+    }
+
+    /// Maximum size of a single record in bytes. Opening or reading storage with a longer
+    /// record fails with [`KvsError::RecordTooLarge`]. Defaults to 64 MiB
+    pub fn max_record_size(mut self, limit: usize) -> Self {
+        self.max_record_size = limit;
+        self
+    }
+
+    /// Forbid overwriting of live keys: setting a key, which already has a value,
+    /// fails with [`KvsError::KeyExists`]. Key can be set again after removal.
+    /// Disabled by default
+    pub fn write_once(mut self, enabled: bool) -> Self {
+        self.write_once = enabled;
+        self
+    }
+
+    /// When written records are synced to the disk. Defaults to [`SyncPolicy::Manual`],
+    /// i.e. only by [`KvStore::flush`]
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Store values longer than `threshold` bytes out-of-line, in separate files
+    /// in `{db}.blobs` directory, keeping the log small and compaction fast.
+    /// Disabled by default. Values with TTL are always stored in the log
+    pub fn blob_threshold(mut self, threshold: usize) -> Self {
+        self.blob_threshold = Some(threshold);
+        self
+    }
+
+    /// Store identical values only once: blob files are named by hash of their content
+    /// and shared by all records with the same value. Shared files are removed by compaction,
+    /// once no live record refers to them. Applies only to values stored out-of-line,
+    /// see [`KvStoreBuilder::blob_threshold`]. Disabled by default
+    pub fn dedup_blobs(mut self, enabled: bool) -> Self {
+        self.dedup_blobs = enabled;
+        self
+    }
+
+    /// Check cached record of the key against storage on every read and correct the cache,
+    /// if they differ, e.g. after the file was edited by another process.
+    /// Every read scans the whole log, so it's meant for diagnostics. Disabled by default
+    pub fn read_repair(mut self, enabled: bool) -> Self {
+        self.read_repair = enabled;
+        self
+    }
+
+    /// Prefix every written record by its length instead of relying on line terminators
+    /// for framing, see [`FileStorage::set_length_prefixed`]. Disabled by default
+    pub fn length_prefixed(mut self, enabled: bool) -> Self {
+        self.length_prefixed = enabled;
+        self
+    }
+
+    /// Serialize records in parallel on compaction,
+    /// see [`FileStorage::set_parallel_compaction`]. Disabled by default
+    #[cfg(feature = "parallel-compaction")]
+    pub fn parallel_compaction(mut self, enabled: bool) -> Self {
+        self.parallel_compaction = enabled;
+        self
+    }
+
+    /// Replay and read records from memory-mapped log file,
+    /// see [`FileStorage::set_mmap`]. Disabled by default
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self, enabled: bool) -> Self {
+        self.mmap = enabled;
+        self
+    }
+
+    /// Source of time for expiration of keys and timestamps of records,
+    /// e.g. [`MockClock`](crate::MockClock) in tests. System clock by default
+    pub fn clock<T: Clock + 'static>(mut self, clock: T) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Log `get`, `set`, `remove` and compactions, which took longer than `threshold`,
+    /// at warn level with their duration. All operations are logged at debug level anyway.
+    /// Disabled by default
+    pub fn slow_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// Transform every key before it's used, e.g. `|k: &str| k.to_lowercase()`
+    /// for case-insensitive keys. Keys are used as is by default
+    pub fn key_transform<T: KeyTransform + 'static>(mut self, transform: T) -> Self {
+        self.key_transform = Some(Box::new(transform));
+        self
+    }
+
+    /// Keep location of the latest record of every key in memory, so reads of uncached keys
+    /// seek right to the record instead of scanning the whole log. Index takes much less
+    /// memory than values, so it's meant for bounded caches and stores without preloading.
+    /// It's built by a scan of the log on open. Disabled by default
+    pub fn key_index(mut self, enabled: bool) -> Self {
+        self.key_index = enabled;
+        self
+    }
+
+    /// Refuse to open database, which already has data, failing with
+    /// [`KvsError::AlreadyExists`]. Empty file is opened as new. Disabled by default
+    pub fn create_new(mut self, enabled: bool) -> Self {
+        self.create_new = enabled;
+        self
+    }
+
+    /// Check every text value before it's written, e.g. that it's valid JSON.
+    /// Validator gets transformed key and the value. Returned error fails the write with
+    /// [`KvsError::ValidationFailed`], leaving the store unchanged
+    pub fn set_value_validator<F>(mut self, validator: F) -> Self
+    where
+        F: Fn(&str, &str) -> std::result::Result<(), String> + Send + 'static,
+    {
+        self.value_validator = Some(Box::new(validator));
+        self
+    }
+
+    /// Keep at most `limit` keys: when a write would add a key over the limit, a key chosen
+    /// by `policy` is removed, writing its tombstone. Expired keys are counted until they're
+    /// removed, e.g. by [`KvStore::purge_expired`]. Keys are ordered by reading the whole
+    /// log on open. Unlimited by default
+    pub fn set_max_keys(mut self, limit: usize, policy: EvictionPolicy) -> Self {
+        self.max_keys = Some((limit, policy));
+        self
+    }
+
+    /// Compact storage right after open if there is reclaimable space, so the store starts
+    /// with a minimal log instead of compacting while serving. Disabled by default.
+    /// Without preloaded cache reclaimable space is unknown, so storage is always compacted
+    pub fn compact_on_open(mut self, enabled: bool) -> Self {
+        self.compact_on_open = enabled;
+        self
+    }
+
+    /// Keep history of keys for audit, see [`KvStore::history`].
+    /// Records dropped by compaction are moved to `{db}.history` archive.
+    /// Values are always stored inline, [`KvStoreBuilder::blob_threshold`] is ignored
+    pub fn audit(mut self, enabled: bool) -> Self {
+        self.audit = enabled;
+        self
+    }
+
+    /// Capacity of the read buffer used on replay, 8KiB by default.
+    /// A few megabytes speed up open of multi-gigabyte databases
+    pub fn read_buffer_size(mut self, capacity: usize) -> Self {
+        self.read_buffer_size = capacity;
+        self
+    }
+
+    /// Amount of keys to preallocate default cache for. Worth setting to the expected amount
+    /// of keys for large databases to speed up open, see [`InMemoryMapCache::with_capacity`].
+    /// By default it's estimated from the size of the file, 0 disables preallocation.
+    /// Not used by [`KvStoreBuilder::build_with_cache`]
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Open store with default cache
+    pub fn build(self) -> Result<KvStore> {
+        let capacity = match self.cache_capacity {
+            Some(capacity) => capacity,
+            None if self.preload_cache => estimate_keys(&self.path),
+            None => 0,
+        };
+        let cache = InMemoryMapCache::with_capacity(capacity);
+        self.build_with_cache(cache)
+    }
+
+    /// Open store, which uses given cache
+    pub fn build_with_cache<C: Cache>(self, cache: C) -> Result<KvStore<C>> {
+        if self.create_new && metadata(&self.path).is_ok_and(|m| m.len() > 0) {
+            return Err(KvsError::AlreadyExists {
+                path: self.path.display().to_string(),
+            });
+        }
+        let mut storage = FileStorage::new(&self.path)?;
+        storage.set_max_record_size(self.max_record_size);
+        storage.set_sync_policy(self.sync_policy);
+        storage.set_read_buffer_size(self.read_buffer_size);
+        storage.set_length_prefixed(self.length_prefixed);
+        #[cfg(feature = "parallel-compaction")]
+        storage.set_parallel_compaction(self.parallel_compaction);
+        #[cfg(feature = "mmap")]
+        storage.set_mmap(self.mmap);
+        let mut instance = KvStore {
+            storage,
+            cache,
+            cache_complete: false,
+            auto_compact: self.auto_compact,
+            write_once: self.write_once,
+            compactions: 0,
+            load_report: None,
+            unreplayed: 0,
+            keep_unreplayed: false,
+            blob_dir: blob_dir(&self.path),
+            blob_threshold: self.blob_threshold.filter(|_| !self.audit),
+            blob_seq: 0,
+            slow_threshold: self.slow_threshold,
+            slow_ops: 0,
+            key_transform: self.key_transform,
+            audit: self.audit,
+            dedup_blobs: self.dedup_blobs,
+            clock: self.clock,
+            staged: None,
+            temp_dir: None,
+            read_repair: self.read_repair,
+            read_repairs: 0,
+            cache_hits: 0,
+            cache_misses: 0,
+            disk_reads: 0,
+            value_validator: self.value_validator,
+            key_index: None,
+            key_order: None,
+        };
+        if self.key_index {
+            instance.key_index = Some(HashMap::new());
+            instance.rebuild_index()?;
+        }
+        if self.preload_cache {
+            instance.cache_logs()?;
+        }
+        if let Some((limit, policy)) = self.max_keys {
+            instance.key_order = Some(KeyOrder::new(limit, policy));
+            instance.rebuild_key_order()?;
+        }
+        if self.compact_on_open && (!self.preload_cache || instance.cache.uncompacted_space() > 0) {
+            instance.compress_storage()?;
+        }
+        Ok(instance)
+    }
 }
 
 /// Key-value database
-pub struct KvStore {
+pub struct KvStore<C: Cache = InMemoryMapCache> {
     storage: FileStorage,
-    cache: InMemoryMapCache,
+    cache: C,
+    /// Whether cache holds every live record from storage
+    cache_complete: bool,
+    /// Whether compaction should run automatically after writes
+    auto_compact: bool,
+    /// Whether overwriting of live keys is forbidden
+    write_once: bool,
+    compactions: usize,
+    load_report: Option<LoadReport>,
+    /// Bytes of storage, which weren't replayed, e.g. corrupted tail, so cache doesn't account them
+    unreplayed: usize,
+    /// Whether replay stopped before records, which may be valid, so automatic compaction
+    /// is suspended to not drop them
+    keep_unreplayed: bool,
+    /// Directory of values stored out-of-line
+    blob_dir: PathBuf,
+    blob_threshold: Option<usize>,
+    /// Counter, which makes names of blob files unique
+    blob_seq: u64,
+    slow_threshold: Option<Duration>,
+    slow_ops: usize,
+    key_transform: Option<Box<dyn KeyTransform>>,
+    /// Archive dead records on compaction, see [`KvStoreBuilder::audit`]
+    audit: bool,
+    /// Name blob files by their content, see [`KvStoreBuilder::dedup_blobs`]
+    dedup_blobs: bool,
+    clock: Box<dyn Clock>,
+    /// Unfinished compaction, see [`KvStore::compact_step`]
+    staged: Option<StagedCompaction>,
+    /// Directory of the store created by [`KvStore::temp`], removed on drop
+    temp_dir: Option<TempDir>,
+    /// Check cache against storage on reads, see [`KvStoreBuilder::read_repair`]
+    read_repair: bool,
+    read_repairs: usize,
+    /// Counters of key reads, see [`Stats::hit_ratio`]
+    cache_hits: usize,
+    cache_misses: usize,
+    disk_reads: usize,
+    value_validator: Option<ValueValidator>,
+    /// Location of the latest record of every live key, see [`KvStoreBuilder::key_index`]
+    key_index: Option<HashMap<String, RecordMeta>>,
+    /// Order of eviction of keys over the limit, see [`KvStoreBuilder::set_max_keys`]
+    key_order: Option<KeyOrder>,
+}
+
+/// State of compaction, which runs in steps
+struct StagedCompaction {
+    /// Storage, which receives compacted records
+    target: FileStorage,
+    /// Offset in the log, which the next step continues from
+    cursor: usize,
+    /// Size of the log at start. Records after it are copied as is by the last step
+    boundary: usize,
+    /// End offset of the latest record of every live key at start
+    latest: HashMap<String, usize>,
+    /// Names of blob files referenced by copied records
+    blobs: HashSet<String>,
+    /// Time of the start, which expiration of records is checked at
+    now: u64,
 }
 
 impl KvStore {
     /// Return new instance of KvStore
     /// [`storage::FileStorage`] using as default storage.
     /// [`cache::InMemoryMapCache`] using as default cache.
-    pub fn new(db: &str) -> Result<Self> {
-        let mut instance = Self {
-            storage: FileStorage::new(db)?,
-            cache: InMemoryMapCache::new()?,
-        };
-        instance.cache_logs()?;
-        Ok(instance)
+    pub fn new<P: AsRef<Path>>(db: P) -> Result<Self> {
+        KvStoreBuilder::new(db).build()
+    }
+
+    /// Open store at given path, creating database if it doesn't exist.
+    /// Return store and `true` if database was newly created, or `false` if it existed before
+    pub fn open_or_create<P: AsRef<Path>>(db: P) -> Result<(Self, bool)> {
+        let store = Self::new(db)?;
+        let created = store.storage.is_created();
+        Ok((store, created))
+    }
+
+    /// Return builder to configure store at given path
+    pub fn builder<P: AsRef<Path>>(db: P) -> KvStoreBuilder {
+        KvStoreBuilder::new(db)
+    }
+
+    /// Open new store at given path. Fail with [`KvsError::AlreadyExists`] if database
+    /// already has data, so data of previous runs isn't mixed with the new one
+    pub fn create_new<P: AsRef<Path>>(db: P) -> Result<Self> {
+        KvStoreBuilder::new(db).create_new(true).build()
+    }
+
+    /// Return store in a new uniquely named temporary directory.
+    /// Directory is removed along with the database and its blobs, when store is dropped
+    pub fn temp() -> Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix("kvs")
+            .tempdir()
+            .context(|| "failed to create temporary directory".to_owned())?;
+        let mut store = Self::new(dir.path().join("kvs.db"))?;
+        store.temp_dir = Some(dir);
+        Ok(store)
     }
+}
+
+/// Temporary store, see [`KvStore::temp`].
+/// Panics if temporary directory can't be created
+impl Default for KvStore {
+    fn default() -> Self {
+        Self::temp().expect("failed to create temporary store")
+    }
+}
 
+impl<C: Cache> KvStore<C> {
     /// Compress sotrage by write only actuall values from cache, omitting old records
     /// This process consist of three steps:
     /// 1. Open new storage
     /// 2. Write all actual records from cache to it
     /// 3. Remove old storage
+    ///
     /// Implementation left on the storage device, imlemented `Storage` trait via `Storage.override` function
     fn compress_storage(&mut self) -> Result<()> {
-        self.storage.override_storage(self.cache.get_all())?;
-        Ok(())
+        let started = Instant::now();
+        self.staged = None;
+        let now = self.clock.now();
+        if self.audit {
+            self.archive_dead(now)?;
+        }
+        // Copies of live records keep their original write time
+        let referenced = if self.cache_complete {
+            let logs = self.cache.get_all();
+            let live: Vec<(&Log, u64)> = logs
+                .into_iter()
+                .filter(|l| l.is_live(now))
+                .map(|l| (l, self.written_at(l)))
+                .collect();
+            self.storage.override_timed(live.clone())?;
+            blob_names(live.into_iter().map(|(l, _)| l))
+        } else {
+            // Records missing from cache would be lost, so collect live records from storage
+            let logs = self.live_logs()?;
+            let live: Vec<(&Log, u64)> = logs
+                .values()
+                .filter(|(l, _)| l.is_live(now))
+                .map(|(l, ts)| (l, *ts))
+                .collect();
+            self.storage.override_timed(live.clone())?;
+            blob_names(live.into_iter().map(|(l, _)| l))
+        };
+        self.remove_unreferenced_blobs(&referenced)?;
+        self.finish_compaction(started)
     }
 
-    /// Load all log entries to cache
-    fn cache_logs(&mut self) -> Result<()> {
-        for item in self.storage.by_ref() {
-            match item {
-                Ok((log, size)) => self.cache.insert(log, size)?,
-                Err(err) => return Err(err.into()),
+    /// Run the next step of compaction, which copies live records in chunks, so other
+    /// operations can interleave with it instead of waiting for the whole rewrite.
+    /// Every step scans at most `max_records` records of the log. Records written between
+    /// steps are copied as is by the last step, which replaces the log.
+    /// Return `true` when compaction is finished.
+    ///
+    /// Unfinished compaction is dropped by failed step, full compaction and [`KvStore::clear`].
+    /// In audit mode the whole compaction runs at once
+    pub fn compact_step(&mut self, max_records: usize) -> Result<bool> {
+        if self.audit {
+            self.compress_storage()?;
+            return Ok(true);
+        }
+        let started = Instant::now();
+        let mut staged = match self.staged.take() {
+            Some(staged) => staged,
+            None => self.start_compaction()?,
+        };
+        let path = self.storage.path();
+        let limit = self.storage.max_record_size();
+        let mut reader = LogReader::open_at(path, staged.cursor)?.max_record_size(limit);
+        let mut scanned = 0;
+        while staged.cursor < staged.boundary {
+            if scanned == max_records.max(1) {
+                self.staged = Some(staged);
+                self.observe("compaction step", None, started);
+                return Ok(false);
+            }
+            let (log, end) = match reader.next() {
+                Some(item) => item?,
+                None => break,
+            };
+            staged.cursor = end;
+            scanned += 1;
+            let latest = log.key().and_then(|k| staged.latest.get(k)) == Some(&end);
+            if latest && log.is_live(staged.now) {
+                staged.blobs.extend(log.blob().map(str::to_owned));
+                staged.target.write_at(&log, reader.written_at())?;
             }
         }
+        // Records written since the start supersede copied ones, so they are copied as is
+        let mut reader = LogReader::open_at(path, staged.boundary)?.max_record_size(limit);
+        while let Some(item) = reader.next() {
+            let (log, _) = item?;
+            staged.blobs.extend(log.blob().map(str::to_owned));
+            staged.target.write_at(&log, reader.written_at())?;
+        }
+        self.storage.replace_with(staged.target)?;
+        self.remove_unreferenced_blobs(&staged.blobs)?;
+        self.finish_compaction(started)?;
+        Ok(true)
+    }
+
+    /// Snapshot state of the log for compaction in steps
+    fn start_compaction(&self) -> Result<StagedCompaction> {
+        let latest = latest_offsets(self.storage.path(), self.storage.max_record_size())?;
+        Ok(StagedCompaction {
+            target: self.storage.compaction_target()?,
+            cursor: 0,
+            boundary: self.storage.size()?,
+            latest,
+            blobs: HashSet::new(),
+            now: self.clock.now(),
+        })
+    }
+
+    /// Rebuild cache after storage has been replaced by compacted one
+    fn finish_compaction(&mut self, started: Instant) -> Result<()> {
+        // Offsets of cached records have changed, so cache is rebuilt from the new storage.
+        // Incomplete cache, which doesn't evict, is kept incomplete to not load everything
+        self.cache.clear()?;
+        // Compacted log has no reclaimable space, except records written during staged
+        // compaction, which are counted by replay
+        self.cache.reset_uncompacted();
+        self.rebuild_index()?;
+        let complete = self.cache_complete;
+        if complete || self.cache.evicts() {
+            self.cache_complete = false;
+            self.replay()?;
+            self.cache_complete = complete;
+        }
+        self.unreplayed = 0;
+        self.keep_unreplayed = false;
+        self.compactions += 1;
+        self.observe("compaction", None, started);
         Ok(())
     }
 
-    fn _get_from_db(&mut self, key: &str) -> Result<Option<(Log, usize)>> {
-        // Re-create entry state from logs
-        let log = self
-            .storage
-            .by_ref()
-            .filter_map(|item| match item {
-                Ok(log) => match &log {
-                    (Log::Set(k, _), _) | (Log::Remove(k), _) if k == key => Some(log),
-                    _ => None,
-                },
-                Err(_) => None,
-            })
-            .last();
-        Ok(log)
+    /// Return write time of the cached record, 0 if it's unknown
+    fn written_at(&self, log: &Log) -> u64 {
+        let meta = log.key().and_then(|k| self.cache.meta(k).ok().flatten());
+        meta.map_or(0, |m| m.written_at)
     }
 
-    /// Get cloned String value from storage stored with given `key`
-    pub fn get(&mut self, key: &str) -> Result<String> {
-        match self.cache.get_mut(key)? {
-            Some(Log::Set(_, value)) => Ok(value.clone()),
-            Some(Log::Remove(_)) => Err(KvsError::KeyNotFound),
-            None => {
-                let value = match self._get_from_db(&key)? {
-                    Some((log, size)) => match &log {
-                        Log::Set(_, value) => {
-                            let v = value.clone();
-                            self.cache.insert(log, size)?;
-                            Some(v)
-                        }
-                        _ => None,
-                    },
-                    _ => None,
-                };
-                match value {
-                    Some(v) => Ok(v),
-                    None => Err(KvsError::KeyNotFound),
+    /// Append records of known write time, which would be dropped by compaction at `now`,
+    /// to the history archive, see [`KvStore::history`]
+    fn archive_dead(&self, now: u64) -> Result<()> {
+        let path = self.storage.path();
+        let limit = self.storage.max_record_size();
+        let latest = latest_offsets(path, limit)?;
+        let mut dead = Vec::new();
+        let mut offset = 0;
+        scan_lines(path, limit, |line| {
+            offset += line.len();
+            if let Ok(Timed(log, ts)) = parse_timed(line) {
+                let live =
+                    log.is_live(now) && log.key().and_then(|k| latest.get(k)) == Some(&offset);
+                if ts != 0 && !live {
+                    dead.extend_from_slice(line.trim_end_matches(['\n', '\r']).as_bytes());
+                    dead.push(b'\n');
                 }
             }
+            Ok(())
+        })?;
+        if !dead.is_empty() {
+            append_history(path, &dead)?;
         }
+        Ok(())
     }
 
-    /// Set `value` to storage behind given `key`
-    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
-        let log = Log::Set(key.to_owned(), value.to_owned());
-        let size = self.storage.write(&log)?;
-        self.cache.insert(log, size)?;
-        if self.cache.uncompacted_space() >= UNCOMPACTED_THREESHOLD {
-            self.compress_storage()?
+    /// Remove blob files, which aren't referenced by live records, e.g. left after overwrites
+    /// of keys missing in cache or after interrupted writes
+    fn remove_unreferenced_blobs(&self, referenced: &HashSet<String>) -> Result<()> {
+        let entries = match read_dir(&self.blob_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).context(|| format!("failed to read '{}'", self.blob_dir.display()))
+            }
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !referenced.contains(name.to_string_lossy().as_ref()) {
+                remove_file(entry.path())
+                    .context(|| format!("failed to remove '{}'", entry.path().display()))?;
+            }
         }
         Ok(())
     }
 
-    /// Remove key-value pair from storage
-    pub fn remove(&mut self, key: &str) -> Result<()> {
-        if self.get(key).is_err() {
-            return Err(KvsError::KeyNotFound);
+    /// Write value to a new blob file and return its name.
+    /// With deduplication existing file of the same content is reused
+    fn write_blob(&mut self, value: &str) -> Result<String> {
+        create_dir_all(&self.blob_dir)
+            .context(|| format!("failed to create '{}'", self.blob_dir.display()))?;
+        if self.dedup_blobs {
+            let name = format!("{:016x}-{}", fnv1a(value.as_bytes()), value.len());
+            let path = self.blob_dir.join(&name);
+            match read(&path) {
+                Ok(content) if content == value.as_bytes() => return Ok(name),
+                // Hash collision or leftover of interrupted write, fall back to a unique name
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    write(&path, value)
+                        .context(|| format!("failed to write blob '{}'", path.display()))?;
+                    return Ok(name);
+                }
+                Err(err) => {
+                    return Err(err).context(|| format!("failed to read blob '{}'", path.display()))
+                }
+            }
+        }
+        // Sequence starts over on open, so the name may be taken by a blob of the store
+        // opened before in the same millisecond. Existing blobs are never overwritten
+        loop {
+            self.blob_seq += 1;
+            let name = format!("{}-{}-{}", now_millis(), std::process::id(), self.blob_seq);
+            let path = self.blob_dir.join(&name);
+            let write_failed = || format!("failed to write blob '{}'", path.display());
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(value.as_bytes()).context(write_failed)?;
+                    return Ok(name);
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err).context(write_failed),
+            }
+        }
+    }
+
+    /// Replay storage and return latest record of every live key along with its write time
+    fn live_logs(&mut self) -> Result<HashMap<String, (Log, u64)>> {
+        let mut live = HashMap::new();
+        while let Some(item) = self.storage.next_timed() {
+            match item? {
+                (Log::Remove(k), _, _) => {
+                    live.remove(&k);
+                }
+                (Log::Clear, _, _) => live.clear(),
+                (log, _, ts) => {
+                    if let Some(k) = log.key() {
+                        live.insert(k.to_owned(), (log, ts));
+                    }
+                }
+            }
+        }
+        Ok(live)
+    }
+
+    /// Enable or disable automatic compaction for the lifetime of this instance.
+    /// Useful for bulk imports, where compaction in the middle of load is a waste of IO.
+    /// Call [`KvStore::compact`] to compact storage manually afterwards.
+    pub fn set_auto_compact(&mut self, enabled: bool) {
+        self.auto_compact = enabled;
+    }
+
+    /// Make every following write to storage fail, see [`FileStorage::set_fail_writes`]
+    #[cfg(feature = "fault-injection")]
+    pub fn set_fail_writes(&mut self, enabled: bool) {
+        self.storage.set_fail_writes(enabled);
+    }
+
+    /// Compact storage right now, regardless of the amount of uncompacted space.
+    /// Records, which weren't replayed because of corrupted record before them, are dropped,
+    /// see [`LoadReport::records_skipped`]
+    pub fn compact(&mut self) -> Result<()> {
+        self.compress_storage()
+    }
+
+    /// Rewrite storage in the current format, e.g. add header and convert records
+    /// of the legacy format. Rewrite is done by compaction, so it's crash-safe.
+    /// Return `false` if storage is already in the current format, so nothing is written
+    pub fn migrate(&mut self) -> Result<bool> {
+        let path = self.storage.path();
+        let current = if self.storage.size()? == 0 {
+            true
+        } else {
+            let mut legacy = false;
+            scan_lines(path, self.storage.max_record_size(), |line| {
+                legacy = legacy || is_legacy_record(line);
+                Ok(())
+            })?;
+            self.storage.has_header() && !legacy
+        };
+        if current {
+            return Ok(false);
+        }
+        self.compress_storage()?;
+        Ok(true)
+    }
+
+    /// Write compacted copy of live records to a new database at `dest`, e.g. for backup.
+    /// Records keep their write time, values stored out-of-line are copied to `{dest}.blobs`.
+    /// The store itself isn't changed. Fails if `dest` already exists
+    pub fn compact_into<P: AsRef<Path>>(&self, dest: P) -> Result<()> {
+        let dest = dest.as_ref();
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dest)
+            .context(|| format!("failed to create '{}'", dest.display()))?;
+        let mut copy = FileStorage::new(dest)?;
+        copy.set_length_prefixed(self.storage.length_prefixed());
+        let path = self.storage.path();
+        let limit = self.storage.max_record_size();
+        let latest = latest_offsets(path, limit)?;
+        let dest_blobs = blob_dir(dest);
+        let now = self.clock.now();
+        let mut offset = 0;
+        scan_lines(path, limit, |line| {
+            offset += line.len();
+            let (log, ts) = match parse_timed(line) {
+                Ok(Timed(log, ts)) => (log, ts),
+                Err(_) => return Ok(()),
+            };
+            match log.key() {
+                Some(k) if latest.get(k) == Some(&offset) && log.is_live(now) => {}
+                _ => return Ok(()),
+            }
+            if let Some(name) = log.blob() {
+                create_dir_all(&dest_blobs)
+                    .context(|| format!("failed to create '{}'", dest_blobs.display()))?;
+                let source = self.blob_dir.join(name);
+                copy_file(&source, dest_blobs.join(name))
+                    .context(|| format!("failed to copy blob '{}'", source.display()))?;
+            }
+            copy.write_at(&log, ts).map(drop)
+        })?;
+        copy.flush()
+    }
+
+    /// Flush all written records to the disk
+    pub fn flush(&mut self) -> Result<()> {
+        self.storage.flush()
+    }
+
+    /// Return path of the database file
+    pub fn path(&self) -> &Path {
+        self.storage.path()
+    }
+
+    /// Return current statistics of the store
+    pub fn stats(&self) -> Stats {
+        Stats {
+            compactions: self.compactions,
+            uncompacted_space: self.cache.uncompacted_space(),
+            disk_usage: self.disk_usage().unwrap_or_default(),
+            live_data_size: self.live_data_size(),
+            slow_ops: self.slow_ops,
+            live_keys: self.live_keys(),
+            read_repairs: self.read_repairs,
+            estimated_memory: self.estimate_memory(),
+            cache_hits: self.cache_hits,
+            cache_misses: self.cache_misses,
+            disk_reads: self.disk_reads,
+        }
+    }
+
+    /// Return approximate amount of memory used by cache in bytes: sizes of keys and values
+    /// plus fixed overhead per entry. Values stored out-of-line aren't cached, so only
+    /// their references are counted. Useful to choose capacity of bounded caches
+    pub fn estimate_memory(&self) -> usize {
+        self.cache
+            .get_all()
+            .into_iter()
+            .map(|log| log.heap_size() + log.key().map_or(0, str::len) + CACHE_ENTRY_OVERHEAD)
+            .sum()
+    }
+
+    /// Return amount of live keys. Keys are counted in cache if it holds all records,
+    /// otherwise storage is scanned, see [`KvStore::keys`]
+    pub fn len(&self) -> Result<usize> {
+        if self.cache_complete {
+            return Ok(self.live_keys());
+        }
+        self.keys().try_fold(0, |count, key| key.map(|_| count + 1))
+    }
+
+    /// Return `true` if there are no live keys, see [`KvStore::len`]
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Count live keys in cache
+    fn live_keys(&self) -> usize {
+        let now = self.clock.now();
+        self.cache
+            .get_all()
+            .into_iter()
+            .filter(|l| l.is_live(now))
+            .count()
+    }
+
+    /// Apply key transform to the key given by user
+    fn transform_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match &self.key_transform {
+            Some(transform) => Cow::Owned(transform.transform(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Log duration of the operation since `started`, warning if it's slow
+    fn observe(&mut self, op: &str, key: Option<&str>, started: Instant) {
+        let elapsed = started.elapsed();
+        let key = key.unwrap_or("-");
+        debug!("{} of key '{}' took {:?}", op, key, elapsed);
+        match self.slow_threshold {
+            Some(threshold) if elapsed >= threshold => {
+                self.slow_ops += 1;
+                warn!("slow {} of key '{}' took {:?}", op, key, elapsed);
+            }
+            _ => {}
+        }
+    }
+
+    /// Return size of the storage file in bytes
+    pub fn disk_usage(&self) -> Result<u64> {
+        let path = self.storage.path();
+        let meta =
+            metadata(path).context(|| format!("failed to open database '{}'", path.display()))?;
+        Ok(meta.len())
+    }
+
+    /// Return total size of live records in bytes, i.e. approximate size of the storage file
+    /// after compaction. Only cached records are counted, so for stores, which don't keep
+    /// whole database in cache, it's a lower bound
+    pub fn live_data_size(&self) -> usize {
+        self.cache.live_size()
+    }
+
+    /// Index location of the latest record of every key in storage
+    fn rebuild_index(&mut self) -> Result<()> {
+        let mut index = match self.key_index.take() {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        index.clear();
+        while let Some(item) = self.storage.next_timed() {
+            let (log, size, written_at) = match item {
+                Ok(item) => item,
+                Err(err) => {
+                    self.key_index = Some(index);
+                    return Err(err);
+                }
+            };
+            let meta = RecordMeta {
+                size,
+                offset: self.storage.read_offset() - size,
+                written_at,
+            };
+            index_record(&mut index, &log, meta);
         }
-        let log = Log::Remove(key.to_owned());
-        let size = self.storage.write(&log)?;
-        self.cache.insert(log, size)?;
+        self.key_index = Some(index);
         Ok(())
     }
+
+    /// Order live keys of storage by write time of their latest records
+    fn rebuild_key_order(&mut self) -> Result<()> {
+        let mut order = match self.key_order.take() {
+            Some(order) => order,
+            None => return Ok(()),
+        };
+        let now = self.clock.now();
+        let mut latest = HashMap::new();
+        while let Some(item) = self.storage.next_timed() {
+            let (log, size, written_at) = match item {
+                Ok(item) => item,
+                Err(err) => {
+                    self.key_order = Some(order);
+                    return Err(err);
+                }
+            };
+            let offset = self.storage.read_offset() - size;
+            match log {
+                Log::Clear => latest.clear(),
+                log => match log.key() {
+                    Some(key) if log.is_live(now) => {
+                        latest.insert(key.to_owned(), (written_at, offset));
+                    }
+                    Some(key) => {
+                        latest.remove(key);
+                    }
+                    None => {}
+                },
+            }
+        }
+        let mut keys: Vec<(String, (u64, usize))> = latest.into_iter().collect();
+        keys.sort_by_key(|(_, position)| *position);
+        order.clear();
+        for (key, _) in keys {
+            order.push(&key);
+        }
+        self.key_order = Some(order);
+        // Limit may be lower than the one store was written with
+        self.evict_over_limit()
+    }
+
+    /// Remove keys over the limit of [`KvStoreBuilder::set_max_keys`]
+    fn evict_over_limit(&mut self) -> Result<()> {
+        let victim = |store: &Self| Some(store.key_order.as_ref()?.victim()?.to_owned());
+        while let Some(key) = victim(self) {
+            debug!("Evicting key '{}' over the limit of keys", key);
+            self.write_log(Log::Remove(key))?;
+        }
+        Ok(())
+    }
+
+    /// Insert all log entries from storage to cache. Return amount of records and bytes read
+    fn replay(&mut self) -> Result<(usize, usize)> {
+        let (mut records, mut bytes) = (0, self.storage.read_offset());
+        while let Some(item) = self.storage.next_timed() {
+            let (log, size, written_at) = item?;
+            bytes = self.storage.read_offset();
+            let meta = RecordMeta {
+                size,
+                offset: bytes - size,
+                written_at,
+            };
+            self.cache.insert_at(log, meta)?;
+            records += 1;
+        }
+        Ok((records, bytes))
+    }
+
+    /// Load all log entries to cache
+    fn cache_logs(&mut self) -> Result<()> {
+        let mut report = LoadReport::default();
+        (report.records_replayed, report.bytes_read) = self.replay()?;
+        self.cache_complete = !self.cache.evicts();
+        let now = self.clock.now();
+        let cached = self.cache.get_all();
+        report.live_keys = cached.iter().filter(|l| l.is_live(now)).count();
+        report.records_skipped = count_records_from(self.storage.path(), report.bytes_read)?;
+        report.torn_tail =
+            report.records_skipped == 1 && is_torn_tail(self.storage.path(), report.bytes_read)?;
+        if report.torn_tail {
+            warn!(
+                "Torn record at offset {} of '{}'",
+                report.bytes_read,
+                self.storage.path().display()
+            );
+            // Otherwise the next write would be appended right to it and lost on replay
+            self.storage.truncate_to(report.bytes_read)?;
+        }
+        // Only the record replay stopped at is known to be dead
+        self.keep_unreplayed = report.records_skipped > 1;
+        self.unreplayed = if self.keep_unreplayed {
+            warn!(
+                "{} records after offset {} of '{}' weren't replayed, automatic compaction \
+                is suspended to keep them",
+                report.records_skipped,
+                report.bytes_read,
+                self.storage.path().display()
+            );
+            0
+        } else {
+            self.storage.size()?.saturating_sub(report.bytes_read)
+        };
+        self.load_report = Some(report);
+        Ok(())
+    }
+
+    /// Re-open storage file and rebuild cache from its current contents, keeping configuration.
+    /// Useful when file was changed by another process, e.g. compacted
+    pub fn reopen(&mut self) -> Result<()> {
+        self.staged = None;
+        let mut storage = FileStorage::new(self.storage.path())?;
+        storage.set_max_record_size(self.storage.max_record_size());
+        storage.set_sync_policy(self.storage.sync_policy());
+        storage.set_read_buffer_size(self.storage.read_buffer_size());
+        storage.set_length_prefixed(self.storage.length_prefixed());
+        #[cfg(feature = "parallel-compaction")]
+        storage.set_parallel_compaction(self.storage.parallel_compaction());
+        #[cfg(feature = "mmap")]
+        storage.set_mmap(self.storage.mmap());
+        self.storage = storage;
+        self.cache.clear()?;
+        self.rebuild_index()?;
+        self.cache_logs()?;
+        self.rebuild_key_order()
+    }
+
+    /// Return report of the last load of records to cache, either on open or by
+    /// [`KvStore::warm_cache`]. `None` if records haven't been loaded yet
+    pub fn last_load_report(&self) -> Option<&LoadReport> {
+        self.load_report.as_ref()
+    }
+
+    /// Drop all cached entries, so next reads will go to the storage
+    pub fn clear_cache(&mut self) -> Result<()> {
+        self.cache.clear()?;
+        self.cache_complete = false;
+        Ok(())
+    }
+
+    /// Load all records to cache, replacing currently cached entries.
+    /// Needed only for stores opened without cache preloading
+    pub fn warm_cache(&mut self) -> Result<()> {
+        self.cache.clear()?;
+        self.cache_logs()
+    }
+
+    fn _get_from_db(&mut self, key: &str) -> Result<Option<(Log, RecordMeta)>> {
+        self.disk_reads += 1;
+        if let Some(index) = &self.key_index {
+            return match index.get(key) {
+                Some(&meta) => {
+                    let Timed(log, _) = self.storage.read_at(meta.offset, meta.size)?;
+                    Ok(Some((log, meta)))
+                }
+                None => Ok(None),
+            };
+        }
+        // Re-create entry state from logs
+        let mut last = None;
+        while let Some(item) = self.storage.next_timed() {
+            let (log, size, written_at) = item?;
+            let meta = RecordMeta {
+                size,
+                offset: self.storage.read_offset() - size,
+                written_at,
+            };
+            match log.key() {
+                Some(k) if k != key => {}
+                _ => last = Some((log, meta)),
+            }
+        }
+        Ok(last)
+    }
+
+    /// Get cloned String value from storage stored with given `key`
+    pub fn get(&mut self, key: &str) -> Result<String> {
+        let started = Instant::now();
+        let result = self.read_text(&self.transform_key(key));
+        self.observe("get", Some(key), started);
+        result
+    }
+
+    /// Get value stored with given `key`, or a copy of `default` if key is absent.
+    /// Default isn't written, so key stays absent. Other errors, e.g. IO ones, are returned
+    pub fn get_or_default(&mut self, key: &str, default: &str) -> Result<String> {
+        match self.get(key) {
+            Err(KvsError::KeyNotFound) => Ok(default.to_owned()),
+            result => result,
+        }
+    }
+
+    fn read_text(&mut self, key: &str) -> Result<String> {
+        let now = self.clock.now();
+        if !self.load_live(key, now)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        match self.cache.get_mut(key)? {
+            Some(log) => match read_value(&self.blob_dir, log, now)? {
+                Some(value) => Ok(value.into_owned()),
+                None => Err(KvsError::KeyNotFound),
+            },
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Make sure record of the key is in cache, loading it from storage if needed.
+    /// Return `false` if key has no live value
+    fn load_live(&mut self, key: &str, now: u64) -> Result<bool> {
+        let live = self.load_cached(key, now)?;
+        if let Some(order) = &mut self.key_order {
+            if live && order.policy() == EvictionPolicy::Lru {
+                order.push(key);
+            }
+        }
+        Ok(live)
+    }
+
+    /// Implementation of [`KvStore::load_live`]
+    fn load_cached(&mut self, key: &str, now: u64) -> Result<bool> {
+        if self.read_repair {
+            return self.load_repaired(key, now);
+        }
+        if let Some(log) = self.cache.get_mut(key)? {
+            self.cache_hits += 1;
+            return Ok(log.is_live(now));
+        }
+        self.cache_misses += 1;
+        match self._get_from_db(key)? {
+            Some((log, meta)) if log.is_live(now) => {
+                self.cache.insert_at(log, meta)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Load record of the key from storage, replacing cached record if it differs.
+    /// Return `false` if key has no live value
+    fn load_repaired(&mut self, key: &str, now: u64) -> Result<bool> {
+        let cached = self.cache.get_mut(key)?.cloned();
+        // Storage is read anyway, cache is only checked against it
+        if cached.is_some() {
+            self.cache_hits += 1;
+        } else {
+            self.cache_misses += 1;
+        }
+        match self._get_from_db(key)? {
+            Some((log, meta)) if log.is_live(now) => {
+                if cached.as_ref() != Some(&log) {
+                    if cached.is_some() {
+                        warn!("Cached record of key '{}' differs from storage", key);
+                        self.read_repairs += 1;
+                    }
+                    self.cache.insert_at(log, meta)?;
+                }
+                Ok(true)
+            }
+            _ => {
+                if cached.is_some_and(|log| log.is_live(now)) {
+                    warn!("Cached key '{}' has no live record in storage", key);
+                    self.read_repairs += 1;
+                    self.cache.remove(key)?;
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// Return status of the key, distinguishing removed keys from never set ones.
+    /// Live values are taken from cache, while for other keys storage is scanned for their
+    /// latest record. Compaction drops tombstones, so keys removed before the last
+    /// compaction are reported as absent
+    pub fn get_status(&mut self, key: &str) -> Result<KeyStatus> {
+        let key = &*self.transform_key(key);
+        match self.read_text(key) {
+            Ok(value) => return Ok(KeyStatus::Present(value)),
+            Err(KvsError::KeyNotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let limit = self.storage.max_record_size();
+        for item in LogReader::open(self.storage.path())?.max_record_size(limit) {
+            if item?.0.key() == Some(key) {
+                return Ok(KeyStatus::Deleted);
+            }
+        }
+        Ok(KeyStatus::Absent)
+    }
+
+    /// Get value of the given `key` along with size and offset of its record in storage,
+    /// e.g. to build secondary indexes. Return `None` if key doesn't exist
+    pub fn get_with_metadata(&mut self, key: &str) -> Result<Option<(String, RecordMeta)>> {
+        let key = &*self.transform_key(key);
+        let now = self.clock.now();
+        if !self.load_live(key, now)? {
+            return Ok(None);
+        }
+        let meta = match self.cache.meta(key)? {
+            Some(meta) => meta,
+            // Cache doesn't keep offsets, so look the record up in storage
+            None => match self._get_from_db(key)? {
+                Some((_, meta)) => meta,
+                None => return Ok(None),
+            },
+        };
+        let value = match self.cache.get_ref(key)? {
+            Some(log) => read_value(&self.blob_dir, log, now)?.map(Cow::into_owned),
+            None => None,
+        };
+        Ok(value.map(|value| (value, meta)))
+    }
+
+    /// Pass value of the given `key` to `f` without cloning it and return the result of `f`,
+    /// or `None` if key doesn't exist. Takes `&mut self` like `get`,
+    /// because value missing in cache is loaded there first
+    pub fn with_value<R, F: FnOnce(&str) -> R>(&mut self, key: &str, f: F) -> Result<Option<R>> {
+        let key = &*self.transform_key(key);
+        let now = self.clock.now();
+        if !self.load_live(key, now)? {
+            return Ok(None);
+        }
+        match self.cache.get_mut(key)? {
+            Some(log) => Ok(read_value(&self.blob_dir, log, now)?.map(|v| f(&v))),
+            None => Ok(None),
+        }
+    }
+
+    /// Get values of several keys at once.
+    /// Only existing keys are present in the result, absent keys are omitted
+    pub fn get_many(&mut self, keys: &[&str]) -> Result<HashMap<String, String>> {
+        let mut values = HashMap::with_capacity(keys.len());
+        for &key in keys {
+            match self.get(key) {
+                Ok(value) => {
+                    values.insert(key.to_owned(), value);
+                }
+                Err(KvsError::KeyNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Get values of several keys at once, using the paired default for every absent key,
+    /// e.g. to load application config. Unlike [`KvStore::get_many`] every key is present
+    pub fn multi_get_with_defaults(
+        &mut self,
+        keys: &[(&str, &str)],
+    ) -> Result<HashMap<String, String>> {
+        let mut values = HashMap::with_capacity(keys.len());
+        for &(key, default) in keys {
+            let value = match self.get(key) {
+                Ok(value) => value,
+                Err(KvsError::KeyNotFound) => default.to_owned(),
+                Err(err) => return Err(err),
+            };
+            values.insert(key.to_owned(), value);
+        }
+        Ok(values)
+    }
+
+    /// Return all live key-value pairs.
+    /// Pairs are taken from cache if it holds all records, otherwise storage is scanned
+    pub fn get_all_pairs(&self) -> Result<Vec<(String, String)>> {
+        if !self.cache_complete {
+            return self.stream_entries().collect();
+        }
+        let now = self.clock.now();
+        let mut pairs = Vec::new();
+        for log in self.cache.get_all() {
+            if let (Some(k), Some(v)) = (log.key(), read_value(&self.blob_dir, log, now)?) {
+                pairs.push((k.to_owned(), v.into_owned()));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Fold over all live key-value pairs, without collecting them.
+    /// Pairs are borrowed from cache if it holds all records, otherwise storage is streamed,
+    /// see [`KvStore::stream_entries`]. Order of pairs is unspecified
+    pub fn fold<B, F: FnMut(B, &str, &str) -> B>(&self, init: B, mut f: F) -> Result<B> {
+        let mut acc = init;
+        if !self.cache_complete {
+            for entry in self.stream_entries() {
+                let (key, value) = entry?;
+                acc = f(acc, &key, &value);
+            }
+            return Ok(acc);
+        }
+        let now = self.clock.now();
+        for log in self.cache.get_all() {
+            if let (Some(k), Some(v)) = (log.key(), read_value(&self.blob_dir, log, now)?) {
+                acc = f(acc, k, &v);
+            }
+        }
+        Ok(acc)
+    }
+
+    /// Return snapshot of all live key-value pairs as a map
+    pub fn to_hashmap(&self) -> Result<HashMap<String, String>> {
+        Ok(self.get_all_pairs()?.into_iter().collect())
+    }
+
+    /// Set every key-value pair from the map, overwriting existing keys.
+    /// Keys, which are absent in the map, are kept as is
+    pub fn load_hashmap(&mut self, map: &HashMap<String, String>) -> Result<()> {
+        for (key, value) in map {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Get value stored with given `key` and deserialize it from JSON into `T`.
+    /// Return `None` if key doesn't exist and [`KvsError::Serde`] if value isn't a valid `T`
+    pub fn get_as<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>> {
+        match self.get(key) {
+            Ok(value) => Ok(Some(serde_json::from_str(&value)?)),
+            Err(KvsError::KeyNotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get values of several keys at once and deserialize them from JSON into `T`.
+    /// Absent keys are omitted, the same as in [`KvStore::get_many`].
+    /// Fail with [`KvsError::Serde`] if any value isn't a valid `T`
+    pub fn get_many_as<T: DeserializeOwned>(
+        &mut self,
+        keys: &[&str],
+    ) -> Result<HashMap<String, T>> {
+        let mut values = HashMap::with_capacity(keys.len());
+        for &key in keys {
+            if let Some(value) = self.get_as(key)? {
+                values.insert(key.to_owned(), value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Serialize `value` to JSON and set it behind given `key`
+    pub fn set_as<T: Serialize>(&mut self, key: &str, value: &T) -> Result<()> {
+        let value = serde_json::to_string(value)?;
+        self.set(key, &value)
+    }
+
+    /// Stream latest value of every live key directly from disk, bypassing the cache.
+    ///
+    /// Storage is scanned twice: first pass collects position of the latest record for each key,
+    /// second pass yields values of these records in order they appear in the log.
+    /// So memory usage is bounded by the amount and size of live keys, while values are never
+    /// held in memory more than one at a time. Useful for cold scans and export of databases
+    /// which values doesn't fit into RAM.
+    pub fn stream_entries(&self) -> impl Iterator<Item = Result<(String, String)>> {
+        let path = self.storage.path();
+        let limit = self.storage.max_record_size();
+        let now = self.clock.now();
+        let blob_dir = self.blob_dir.clone();
+        let opened = latest_offsets(path, limit)
+            .and_then(|latest| Ok((latest, LogReader::open(path)?.max_record_size(limit))));
+        let (failed, entries) = match opened {
+            Ok((latest, reader)) => {
+                let entries = reader.filter_map(move |item| match item {
+                    Ok((log, offset)) => match log.key() {
+                        Some(k) if latest.get(k) == Some(&offset) => {
+                            read_value(&blob_dir, &log, now)
+                                .map(|v| v.map(|v| (k.to_owned(), v.into_owned())))
+                                .transpose()
+                        }
+                        _ => None,
+                    },
+                    Err(err) => Some(Err(err)),
+                });
+                (None, Some(entries))
+            }
+            Err(err) => (Some(Err(err)), None),
+        };
+        failed.into_iter().chain(entries.into_iter().flatten())
+    }
+
+    /// Yield live keys in order their latest records appear in the log, without reading values.
+    /// Storage is scanned twice, same as in [`KvStore::stream_entries`]
+    pub fn keys(&self) -> impl Iterator<Item = Result<String>> {
+        let path = self.storage.path();
+        let limit = self.storage.max_record_size();
+        let now = self.clock.now();
+        let opened = latest_offsets(path, limit)
+            .and_then(|latest| Ok((latest, LogReader::open(path)?.max_record_size(limit))));
+        let (failed, keys) = match opened {
+            Ok((latest, reader)) => {
+                let keys = reader.filter_map(move |item| match item {
+                    Ok((log, offset)) => match log.key() {
+                        Some(k) if latest.get(k) == Some(&offset) && log.is_live(now) => {
+                            Some(Ok(k.to_owned()))
+                        }
+                        _ => None,
+                    },
+                    Err(err) => Some(Err(err)),
+                });
+                (None, Some(keys))
+            }
+            Err(err) => (Some(Err(err)), None),
+        };
+        failed.into_iter().chain(keys.into_iter().flatten())
+    }
+
+    /// Return sorted live keys matching glob `pattern`, where `*` matches any sequence
+    /// of characters, including empty one, and `?` matches a single character.
+    /// Other characters match themselves, so e.g. `user:*:profile`.
+    /// Every live key is checked, even if pattern starts with a literal prefix, so
+    /// without complete cache it's a full scan of storage
+    pub fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = if self.cache_complete {
+            let now = self.clock.now();
+            self.cache
+                .get_all()
+                .into_iter()
+                .filter(|l| l.is_live(now))
+                .filter_map(Log::key)
+                .filter(|k| glob_match(pattern, k))
+                .map(str::to_owned)
+                .collect()
+        } else {
+            let mut keys = Vec::new();
+            for key in self.keys() {
+                let key = key?;
+                if glob_match(pattern, &key) {
+                    keys.push(key);
+                }
+            }
+            keys
+        };
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Count live keys by their prefix, i.e. part of the key before the first `sep`.
+    /// Keys without separator are counted as a prefix of their own.
+    /// Built on [`KvStore::keys`], so only the counts are held in memory besides the scan
+    pub fn prefix_counts(&self, sep: &str) -> Result<BTreeMap<String, usize>> {
+        let mut counts = BTreeMap::new();
+        for key in self.keys() {
+            let key = key?;
+            let prefix = match key.find(sep) {
+                Some(end) if !sep.is_empty() => &key[..end],
+                _ => &key,
+            };
+            match counts.get_mut(prefix) {
+                Some(count) => *count += 1,
+                None => {
+                    counts.insert(prefix.to_owned(), 1);
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Return history of the key for audit as `(time, value)` pairs in order of writes,
+    /// where time is unix time in milliseconds and `None` value stands for removal.
+    /// Records dropped by compaction are listed only if the store was in audit mode,
+    /// see [`KvStoreBuilder::audit`]. Legacy records of unknown time are skipped.
+    /// Both the history archive and the storage are scanned, so it's slow for large databases
+    pub fn history(&self, key: &str) -> Result<Vec<(u64, Option<String>)>> {
+        let key = &*self.transform_key(key);
+        let limit = self.storage.max_record_size();
+        let archive = history_path(self.storage.path());
+        let mut paths = vec![self.storage.path()];
+        if archive.exists() {
+            paths.insert(0, &archive);
+        }
+        let mut history: Vec<(u64, Option<String>)> = Vec::new();
+        for path in paths {
+            scan_lines(path, limit, |line| {
+                let (log, ts) = match parse_timed(line) {
+                    Ok(Timed(log, ts)) if ts != 0 => (log, ts),
+                    _ => return Ok(()),
+                };
+                let value = match log {
+                    Log::Set(k, v) | Log::SetEx(k, v, _) if k == key => {
+                        Some(v.to_text().into_owned())
+                    }
+                    Log::SetBlob(ref k, ..) if k == key => {
+                        read_value(&self.blob_dir, &log, 0)?.map(Cow::into_owned)
+                    }
+                    Log::Remove(k) if k == key => None,
+                    Log::Clear if matches!(history.last(), Some((_, Some(_)))) => None,
+                    _ => return Ok(()),
+                };
+                history.push((ts, value));
+                Ok(())
+            })?;
+        }
+        Ok(history)
+    }
+
+    /// Scan storage and yield records, which would be dropped by compaction, in order they
+    /// appear in the log. Read-only, useful to find out why space isn't reclaimed.
+    /// Storage is scanned twice, same as in [`KvStore::stream_entries`]
+    pub fn iter_tombstones(&self) -> impl Iterator<Item = Result<DeadRecord>> {
+        let path = self.storage.path();
+        let limit = self.storage.max_record_size();
+        let now = self.clock.now();
+        let opened = latest_offsets(path, limit)
+            .and_then(|latest| Ok((latest, LogReader::open(path)?.max_record_size(limit))));
+        let (failed, records) = match opened {
+            Ok((latest, reader)) => {
+                let mut start = 0;
+                let records = reader.filter_map(move |item| {
+                    let (log, offset) = match item {
+                        Ok(item) => item,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    let size = offset - start;
+                    start = offset;
+                    let reason = match &log {
+                        Log::Remove(_) | Log::Clear => DeadReason::Tombstone,
+                        log if latest.get(log.key()?) != Some(&offset) => DeadReason::Superseded,
+                        log if !log.is_live(now) => DeadReason::Expired,
+                        _ => return None,
+                    };
+                    let key = log.key().map(str::to_owned);
+                    Some(Ok(DeadRecord { key, size, reason }))
+                });
+                (None, Some(records))
+            }
+            Err(err) => (Some(Err(err)), None),
+        };
+        failed.into_iter().chain(records.into_iter().flatten())
+    }
+
+    /// Write record to storage and then to cache. Return size of written record.
+    /// Cache is changed only after the record is written, so failed write leaves it untouched
+    fn write_log(&mut self, log: Log) -> Result<usize> {
+        // Blobs of overwritten records are known only if they are cached,
+        // others are left for compaction
+        let replaced = match log.key() {
+            Some(key) => blob_names(self.cache.get_ref(key)?),
+            None => blob_names(self.cache.get_all()),
+        };
+        let written_at = self.clock.now();
+        let size = self.storage.write_at(&log, written_at)?;
+        // Offset is known only after the write, since header is written before the first record
+        let offset = self.storage.size()? - size;
+        let meta = RecordMeta {
+            size,
+            offset,
+            written_at,
+        };
+        self.cache_written(log, meta)?;
+        self.remove_blobs(replaced);
+        self.evict_over_limit()?;
+        Ok(size)
+    }
+
+    /// Write records to storage at once and then to cache
+    fn write_logs(&mut self, logs: Vec<Log>) -> Result<()> {
+        let mut replaced = blob_names(logs.iter().filter_map(|log| {
+            let key = log.key()?;
+            self.cache.get_ref(key).ok().flatten()
+        }));
+        // Blob may be moved to another key of the batch, e.g. by swap
+        for name in blob_names(logs.iter()) {
+            replaced.remove(&name);
+        }
+        let written_at = self.clock.now();
+        let sizes = self.storage.write_batch_at(&logs, written_at)?;
+        let mut offset = self.storage.size()? - sizes.iter().sum::<usize>();
+        for (log, size) in logs.into_iter().zip(sizes) {
+            let meta = RecordMeta {
+                size,
+                offset,
+                written_at,
+            };
+            self.cache_written(log, meta)?;
+            offset += size;
+        }
+        self.remove_blobs(replaced);
+        self.evict_over_limit()
+    }
+
+    /// Insert record, which is already written to storage, to cache
+    fn cache_written(&mut self, log: Log, meta: RecordMeta) -> Result<()> {
+        if let Some(index) = &mut self.key_index {
+            index_record(index, &log, meta);
+        }
+        if let Some(order) = &mut self.key_order {
+            match &log {
+                Log::Remove(k) => order.remove(k),
+                Log::Clear => order.clear(),
+                Log::Set(k, _) | Log::SetEx(k, _, _) | Log::SetBlob(k, _, _) => order.push(k),
+            }
+        }
+        let key = log.key().map(str::to_owned);
+        if let Err(err) = self.cache.insert_at(log, meta) {
+            // Record is already on disk, so drop stale cached state to read it from storage
+            self.cache_complete = false;
+            let _ = match key {
+                Some(key) => self.cache.remove(&key),
+                None => self.cache.clear(),
+            };
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Remove blob files of replaced records
+    fn remove_blobs(&self, names: HashSet<String>) {
+        // Shared blobs may be still referenced by other keys
+        if self.dedup_blobs {
+            return;
+        }
+        for name in names {
+            // Leftover is harmless, it's removed by the next compaction
+            let _ = remove_file(self.blob_dir.join(name));
+        }
+    }
+
+    /// Write record as is, e.g. one received from another store
+    pub(crate) fn apply(&mut self, log: Log) -> Result<()> {
+        self.write_log(log)?;
+        self.maybe_compact()
+    }
+
+    /// Compact storage if it's enabled and reclaimable space reaches threshold and ratio of size
+    fn maybe_compact(&mut self) -> Result<()> {
+        if !self.auto_compact || self.keep_unreplayed {
+            return Ok(());
+        }
+        let reclaimable = self.cache.uncompacted_space() + self.unreplayed;
+        if reclaimable >= UNCOMPACTED_THREESHOLD
+            && reclaimable as f64 >= self.storage.size()? as f64 * UNCOMPACTED_RATIO
+        {
+            self.compress_storage()?
+        }
+        Ok(())
+    }
+
+    /// Set `value` to storage behind given `key`
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.set_returning_size(key, value).map(|_| ())
+    }
+
+    /// Set `value` behind given `key`, see [`KvStore::set`]. Return size of the written record
+    /// in bytes, including line terminator. For values stored out-of-line it's the size
+    /// of the reference, the same as [`RecordMeta::size`]
+    pub fn set_returning_size(&mut self, key: &str, value: &str) -> Result<usize> {
+        let started = Instant::now();
+        let result = self.write_text(&self.transform_key(key), value);
+        self.observe("set", Some(key), started);
+        result
+    }
+
+    fn write_text(&mut self, key: &str, value: &str) -> Result<usize> {
+        self.check_write_once(key)?;
+        let log = self.text_log(key, value)?;
+        let size = self.write_log(log)?;
+        self.maybe_compact()?;
+        Ok(size)
+    }
+
+    /// Make record of the text value, storing it out-of-line if it's above the blob threshold
+    fn text_log(&mut self, key: &str, value: &str) -> Result<Log> {
+        self.validate(key, value)?;
+        Ok(match self.blob_threshold {
+            Some(threshold) if value.len() > threshold => {
+                Log::SetBlob(key.to_owned(), self.write_blob(value)?, value.len())
+            }
+            _ => Log::Set(key.to_owned(), Value::Str(value.to_owned())),
+        })
+    }
+
+    /// Set every valid key-value pair, reporting rejected ones instead of failing the batch.
+    /// Entry is rejected if its record is larger than [`KvStoreBuilder::max_record_size`]
+    /// ([`KvsError::ValueTooLarge`]), it fails validation or overwrites a live key
+    /// of write-once store. Valid entries are written at once, so either all of them
+    /// are written or none, when writing fails and the error is returned.
+    /// Their durability is the same as of any other write, see [`SyncPolicy`]
+    pub fn set_many_report(&mut self, entries: &[(&str, &str)]) -> Result<BatchReport> {
+        let mut report = BatchReport::default();
+        let mut batch = Vec::with_capacity(entries.len());
+        let mut batch_keys = HashSet::new();
+        let now = self.clock.now();
+        for (i, (key, value)) in entries.iter().enumerate() {
+            let key = self.transform_key(key).into_owned();
+            let log = if self.write_once && batch_keys.contains(&key) {
+                Err(KvsError::KeyExists)
+            } else {
+                self.check_write_once(&key)
+                    .and_then(|_| self.text_log(&key, value))
+            };
+            let log = log.and_then(|log| {
+                let limit = self.storage.max_record_size();
+                let size = self.storage.record_size(&log, now)?;
+                if size > limit {
+                    return Err(KvsError::ValueTooLarge { size, limit });
+                }
+                Ok(log)
+            });
+            match log {
+                Ok(log) => {
+                    batch.push(log);
+                    batch_keys.insert(key);
+                }
+                Err(err) => report.failed.push((i, err)),
+            }
+        }
+        report.succeeded = batch.len();
+        if !batch.is_empty() {
+            self.write_logs(batch)?;
+            self.maybe_compact()?;
+        }
+        Ok(report)
+    }
+
+    /// Set pairs from `key<TAB>value` lines of the reader, reading it line by line
+    /// and writing records in batches. Value is everything after the first tab.
+    /// Return amount of set pairs.
+    ///
+    /// Fail at the first line, which can't be set, e.g. with [`KvsError::MalformedInput`]
+    /// if it has no tab. Pairs of the lines before it are kept
+    pub fn load_tsv(&mut self, mut r: impl BufRead) -> Result<usize> {
+        let mut batch = Vec::with_capacity(TSV_BATCH_SIZE);
+        let mut batch_keys = HashSet::new();
+        let mut count = 0;
+        let mut line = String::new();
+        let mut number = 0;
+        loop {
+            line.clear();
+            if r.read_line(&mut line)? == 0 {
+                break;
+            }
+            number += 1;
+            let text = line.strip_suffix('\n').unwrap_or(&line);
+            let text = text.strip_suffix('\r').unwrap_or(text);
+            let (key, value) = match text.split_once('\t') {
+                Some(pair) => pair,
+                None => {
+                    self.write_tsv_batch(&mut batch, &mut batch_keys)?;
+                    return Err(KvsError::MalformedInput { line: number });
+                }
+            };
+            let key = self.transform_key(key).into_owned();
+            // Write-once check looks only at the storage, so repeated key waits for the next batch
+            if self.write_once && batch_keys.contains(&key) {
+                self.write_tsv_batch(&mut batch, &mut batch_keys)?;
+            }
+            let log = match self.check_write_once(&key) {
+                Ok(()) => self.text_log(&key, value),
+                Err(err) => Err(err),
+            };
+            match log {
+                Ok(log) => batch.push(log),
+                Err(err) => {
+                    self.write_tsv_batch(&mut batch, &mut batch_keys)?;
+                    return Err(err);
+                }
+            }
+            batch_keys.insert(key);
+            count += 1;
+            if batch.len() == TSV_BATCH_SIZE {
+                self.write_tsv_batch(&mut batch, &mut batch_keys)?;
+            }
+        }
+        self.write_tsv_batch(&mut batch, &mut batch_keys)?;
+        Ok(count)
+    }
+
+    /// Write pending records of [`KvStore::load_tsv`]
+    fn write_tsv_batch(&mut self, batch: &mut Vec<Log>, keys: &mut HashSet<String>) -> Result<()> {
+        keys.clear();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_logs(std::mem::take(batch))?;
+        self.maybe_compact()
+    }
+
+    /// Exchange values of two keys, writing both records at once, so either both or none
+    /// of them are changed. Type and expiration time of the values move along with them.
+    /// Fail with [`KvsError::KeyNotFound`] if any of the keys is absent and with
+    /// [`KvsError::KeyExists`] if store is write-once, since both keys are overwritten
+    pub fn swap(&mut self, a: &str, b: &str) -> Result<()> {
+        let started = Instant::now();
+        let result = self.swap_keys(&self.transform_key(a), &self.transform_key(b));
+        self.observe("swap", Some(a), started);
+        result
+    }
+
+    fn swap_keys(&mut self, a: &str, b: &str) -> Result<()> {
+        let log_a = self.live_log(a)?;
+        let log_b = self.live_log(b)?;
+        if self.write_once {
+            return Err(KvsError::KeyExists);
+        }
+        if a == b {
+            return Ok(());
+        }
+        self.write_logs(vec![
+            log_b.with_key(a.to_owned()),
+            log_a.with_key(b.to_owned()),
+        ])?;
+        self.maybe_compact()
+    }
+
+    /// Return live record of the key. Fail with [`KvsError::KeyNotFound`] if key is absent
+    fn live_log(&mut self, key: &str) -> Result<Log> {
+        if !self.load_live(key, self.clock.now())? {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.cache
+            .get_mut(key)?
+            .cloned()
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    /// Run validator of the store, see [`KvStoreBuilder::set_value_validator`]
+    fn validate(&self, key: &str, value: &str) -> Result<()> {
+        match &self.value_validator {
+            Some(validator) => validator(key, value).map_err(KvsError::ValidationFailed),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail with [`KvsError::KeyExists`] if store is write-once and `key` has live value
+    fn check_write_once(&mut self, key: &str) -> Result<()> {
+        if !self.write_once {
+            return Ok(());
+        }
+        if self.load_live(key, self.clock.now())? {
+            return Err(KvsError::KeyExists);
+        }
+        Ok(())
+    }
+
+    /// Get typed value stored with given `key`.
+    /// Values written by [`KvStore::set`] are [`Value::Str`]
+    pub fn get_value(&mut self, key: &str) -> Result<Value> {
+        let key = &*self.transform_key(key);
+        let now = self.clock.now();
+        if !self.load_live(key, now)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.read_typed(key, now)
+    }
+
+    /// Return typed value of the key, which is already loaded to cache
+    fn read_typed(&mut self, key: &str, now: u64) -> Result<Value> {
+        match self.cache.get_mut(key)? {
+            Some(Log::Set(_, value)) | Some(Log::SetEx(_, value, _)) => Ok(value.clone()),
+            Some(log) => match read_value(&self.blob_dir, log, now)? {
+                Some(value) => Ok(Value::Str(value.into_owned())),
+                None => Err(KvsError::KeyNotFound),
+            },
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Set typed `value` behind given `key`. Strings are stored the same way as by [`KvStore::set`]
+    pub fn set_value(&mut self, key: &str, value: Value) -> Result<()> {
+        if let Value::Str(s) = &value {
+            return self.set(key, s);
+        }
+        let key = &*self.transform_key(key);
+        self.check_write_once(key)?;
+        self.write_log(Log::Set(key.to_owned(), value))?;
+        self.maybe_compact()
+    }
+
+    /// Add `delta` to the integer value of `key` and return the new value.
+    /// Absent key is treated as 0. Fail with [`KvsError::WrongType`] if value isn't
+    /// [`Value::Int`] and with [`KvsError::IntegerOverflow`] if result doesn't fit into `i64`
+    pub fn incr(&mut self, key: &str, delta: i64) -> Result<i64> {
+        let current = match self.get_value(key) {
+            Ok(Value::Int(i)) => i,
+            Ok(other) => {
+                return Err(KvsError::WrongType {
+                    expected: "Int",
+                    found: other.type_name(),
+                })
+            }
+            Err(KvsError::KeyNotFound) => 0,
+            Err(err) => return Err(err),
+        };
+        let new = current
+            .checked_add(delta)
+            .ok_or(KvsError::IntegerOverflow)?;
+        self.set_value(key, Value::Int(new))?;
+        Ok(new)
+    }
+
+    /// Set `value` behind given `key`, which expires after `ttl`.
+    /// Expired keys behave as absent and are dropped by compaction or [`KvStore::purge_expired`]
+    pub fn set_with_ttl(&mut self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        let key = &*self.transform_key(key);
+        self.check_write_once(key)?;
+        self.validate(key, value)?;
+        let expires_at = self.clock.now().saturating_add(ttl.as_millis() as u64);
+        let value = Value::Str(value.to_owned());
+        self.write_log(Log::SetEx(key.to_owned(), value, expires_at))?;
+        self.maybe_compact()
+    }
+
+    /// Make existing `key` expire after `ttl`, keeping its value.
+    /// Value stored out-of-line is moved into the record. Return `false` if key doesn't exist
+    pub fn set_expire(&mut self, key: &str, ttl: Duration) -> Result<bool> {
+        let key = &*self.transform_key(key);
+        let now = self.clock.now();
+        if !self.load_live(key, now)? {
+            return Ok(false);
+        }
+        let value = self.read_typed(key, now)?;
+        let expires_at = now.saturating_add(ttl.as_millis() as u64);
+        self.write_log(Log::SetEx(key.to_owned(), value, expires_at))?;
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    /// Refresh `key` without changing its value: expiring key gets its TTL again, counted
+    /// from now, other keys only get a new write time. TTL is derived from the write time
+    /// of the record, so expiry of legacy records without one is kept as is.
+    /// Value stored out-of-line is moved into the record. Return `false` if key doesn't exist
+    pub fn touch(&mut self, key: &str) -> Result<bool> {
+        let key = &*self.transform_key(key);
+        let now = self.clock.now();
+        if !self.load_live(key, now)? {
+            return Ok(false);
+        }
+        let expires_at = match self.cache.get_ref(key)? {
+            Some(Log::SetEx(_, _, expires_at)) => Some(*expires_at),
+            _ => None,
+        };
+        let value = self.read_typed(key, now)?;
+        let log = match expires_at {
+            Some(expires_at) => {
+                let written_at = self.cache.meta(key)?.map_or(0, |m| m.written_at);
+                let expires_at = if written_at == 0 {
+                    expires_at
+                } else {
+                    now.saturating_add(expires_at.saturating_sub(written_at))
+                };
+                Log::SetEx(key.to_owned(), value, expires_at)
+            }
+            None => Log::Set(key.to_owned(), value),
+        };
+        self.write_log(log)?;
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    /// Remove expiry of the `key`, so it's kept until removed.
+    /// Return `false` if key doesn't exist or doesn't expire
+    pub fn persist(&mut self, key: &str) -> Result<bool> {
+        let key = &*self.transform_key(key);
+        if !self.load_live(key, self.clock.now())? {
+            return Ok(false);
+        }
+        let value = match self.cache.get_ref(key)? {
+            Some(Log::SetEx(_, value, _)) => value.clone(),
+            _ => return Ok(false),
+        };
+        self.write_log(Log::Set(key.to_owned(), value))?;
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    /// Remove all expired keys, writing tombstones for them, so their space is accounted
+    /// as reclaimable by compaction. Return amount of purged keys
+    pub fn purge_expired(&mut self) -> Result<usize> {
+        let now = self.clock.now();
+        let expired: Vec<String> = if self.cache_complete {
+            self.cache
+                .get_all()
+                .into_iter()
+                .filter(|l| !l.is_live(now))
+                .filter_map(|l| l.key().map(|k| k.to_owned()))
+                .collect()
+        } else {
+            self.live_logs()?
+                .into_iter()
+                .filter(|(_, (l, _))| !l.is_live(now))
+                .map(|(k, _)| k)
+                .collect()
+        };
+        for key in &expired {
+            self.write_log(Log::Remove(key.clone()))?;
+        }
+        self.maybe_compact()?;
+        Ok(expired.len())
+    }
+
+    /// Remove all live keys starting with `prefix`, writing their tombstones at once.
+    /// Key transform is applied to the prefix too. Return amount of removed keys
+    pub fn drain_prefix(&mut self, prefix: &str) -> Result<usize> {
+        let prefix = &*self.transform_key(prefix);
+        let now = self.clock.now();
+        let keys: Vec<String> = if self.cache_complete {
+            self.cache
+                .get_all()
+                .into_iter()
+                .filter(|l| l.is_live(now))
+                .filter_map(|l| l.key())
+                .filter(|k| k.starts_with(prefix))
+                .map(|k| k.to_owned())
+                .collect()
+        } else {
+            self.live_logs()?
+                .into_iter()
+                .filter(|(k, (l, _))| l.is_live(now) && k.starts_with(prefix))
+                .map(|(k, _)| k)
+                .collect()
+        };
+        let count = keys.len();
+        if count > 0 {
+            self.write_logs(keys.into_iter().map(Log::Remove).collect())?;
+            self.maybe_compact()?;
+        }
+        Ok(count)
+    }
+
+    /// Set `value` behind given `key` only if key is absent (or was removed).
+    /// Return `true` if value was set and `false` if key already exists, leaving it unchanged
+    pub fn set_nx(&mut self, key: &str, value: &str) -> Result<bool> {
+        if self.load_live(&self.transform_key(key), self.clock.now())? {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Remove all key-value pairs from storage, truncating it at once along with blob files.
+    /// In audit mode `Clear` record is written instead, which drops everything before it
+    /// during replay, so the history is archived by the next compaction.
+    ///
+    /// [`Follower`](crate::Follower) and [`Watcher`](crate::Watcher) notice truncation
+    /// only if they poll before the log grows back past their offset
+    pub fn clear(&mut self) -> Result<()> {
+        if self.audit {
+            self.write_log(Log::Clear)?;
+            return Ok(());
+        }
+        self.staged = None;
+        self.storage.truncate()?;
+        if let Some(index) = &mut self.key_index {
+            index.clear();
+        }
+        if let Some(order) = &mut self.key_order {
+            order.clear();
+        }
+        self.cache.clear()?;
+        self.cache_complete = !self.cache.evicts();
+        self.unreplayed = 0;
+        self.keep_unreplayed = false;
+        self.remove_unreferenced_blobs(&HashSet::new())
+    }
+
+    /// Remove key-value pair from storage
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        let started = Instant::now();
+        let result = self.write_tombstone(&self.transform_key(key));
+        self.observe("remove", Some(key), started);
+        result
+    }
+
+    /// Remove key-value pair only if its current value equals `expected`, so a value changed
+    /// by someone else isn't dropped. Return `true` if the pair was removed.
+    /// Nothing is written when the value differs or the key doesn't exist
+    pub fn remove_if(&mut self, key: &str, expected: &str) -> Result<bool> {
+        let started = Instant::now();
+        let result = self.remove_matching(&self.transform_key(key), expected);
+        self.observe("remove", Some(key), started);
+        result
+    }
+
+    fn remove_matching(&mut self, key: &str, expected: &str) -> Result<bool> {
+        match self.read_text(key) {
+            Ok(value) if value == expected => {}
+            Ok(_) | Err(KvsError::KeyNotFound) => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        self.write_tombstone(key)?;
+        Ok(true)
+    }
+
+    fn write_tombstone(&mut self, key: &str) -> Result<()> {
+        if !self.load_live(key, self.clock.now())? {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.write_log(Log::Remove(key.to_owned()))?;
+        self.maybe_compact()
+    }
+}
+
+/// Collect names of blob files, referenced by the records
+fn blob_names<'a>(logs: impl IntoIterator<Item = &'a Log>) -> HashSet<String> {
+    logs.into_iter()
+        .filter_map(Log::blob)
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Return `true` if log file has a single line after the given byte offset and it has
+/// no line terminator, i.e. it's the last record, which wasn't written completely
+fn is_torn_tail(path: &Path, offset: usize) -> Result<bool> {
+    let mut reader = BufReader::new(open_db(path)?);
+    reader.seek(SeekFrom::Start(offset as u64))?;
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    Ok(!line.is_empty() && !line.ends_with(b"\n"))
+}
+
+/// Count non-empty records in log file, starting from the given byte offset
+fn count_records_from(path: &Path, offset: usize) -> Result<usize> {
+    let mut reader = BufReader::new(open_db(path)?);
+    reader.seek(SeekFrom::Start(offset as u64))?;
+    let mut count = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match read_line(&mut reader, usize::MAX, &mut line) {
+            Ok(Some(0)) | Ok(None) => return Ok(count),
+            Ok(Some(_)) if line.bytes().all(|b| b.is_ascii_whitespace()) => {}
+            Ok(Some(_)) => count += 1,
+            // Line isn't valid UTF-8, it's counted and skipped
+            Err(err) if err.kind() == ErrorKind::InvalidData => {
+                reader.read_until(b'\n', &mut Vec::new())?;
+                count += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Estimate amount of keys in database at `path` by its size, 0 if it doesn't exist
+fn estimate_keys(path: &Path) -> usize {
+    let size = metadata(path).map_or(0, |m| m.len());
+    (size / ESTIMATED_RECORD_SIZE).min(MAX_ESTIMATED_KEYS) as usize
+}
+
+/// Update key index by the record at given location, see [`KvStoreBuilder::key_index`]
+fn index_record(index: &mut HashMap<String, RecordMeta>, log: &Log, meta: RecordMeta) {
+    match log {
+        Log::Remove(k) => {
+            index.remove(k);
+        }
+        Log::Clear => index.clear(),
+        Log::Set(k, _) | Log::SetEx(k, _, _) | Log::SetBlob(k, _, _) => {
+            index.insert(k.clone(), meta);
+        }
+    }
+}
+
+/// Return `true` if `text` matches glob `pattern` with `*` and `?` wildcards
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it currently matches up to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` match one more character
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Scan log file and return end offset of the latest record for every live key
+fn latest_offsets(path: &Path, limit: usize) -> Result<HashMap<String, usize>> {
+    let mut latest = HashMap::new();
+    for item in LogReader::open(path)?.max_record_size(limit) {
+        match item? {
+            (Log::Remove(k), _) => {
+                latest.remove(&k);
+            }
+            (Log::Clear, _) => latest.clear(),
+            (log, offset) => {
+                if let Some(k) = log.key() {
+                    latest.insert(k.to_owned(), offset);
+                }
+            }
+        }
+    }
+    Ok(latest)
+}
+
+/// Serialize live key-value pairs of the store as a map.
+/// Store can't be deserialized, since it's bound to the file, but map can be loaded back
+/// via [`KvStore::load_hashmap`]
+impl<C: Cache> Serialize for KvStore<C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let pairs = self.get_all_pairs().map_err(S::Error::custom)?;
+        serializer.collect_map(pairs)
+    }
+}
+
+/// Read value of the key from cache, e.g. `store["key"]`.
+///
+/// # Panics
+///
+/// Panics if key doesn't exist or cache failed to read it.
+/// Only cached records are available: for stores opened without cache preloading
+/// keys which weren't read before are missing, use [`KvStore::get`] for them.
+/// Values stored out-of-line can't be borrowed from cache as well.
+impl<C: Cache> Index<&str> for KvStore<C> {
+    type Output = str;
+
+    fn index(&self, key: &str) -> &str {
+        let log = self.cache.get_ref(&self.transform_key(key));
+        let log = log.expect("failed to read cache");
+        if log.and_then(Log::blob).is_some() {
+            panic!("value of key {} is stored out-of-line, use `get`", key);
+        }
+        match log.and_then(|l| l.live_value(self.clock.now())) {
+            Some(Cow::Borrowed(value)) => value,
+            Some(Cow::Owned(_)) => panic!("value of key {} isn't a string, use `get`", key),
+            None => panic!("key not found: {}", key),
+        }
+    }
+}
+
+/// Iterate over all live key-value pairs, see [`KvStore::get_all_pairs`].
+///
+/// # Panics
+///
+/// Panics if pairs can't be read from storage.
+impl<C: Cache> IntoIterator for &KvStore<C> {
+    type Item = (String, String);
+    type IntoIter = std::vec::IntoIter<(String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.get_all_pairs()
+            .expect("failed to read storage")
+            .into_iter()
+    }
 }
@@ -1,9 +1,29 @@
-pub use error::{KvsError, Result};
-pub use kv::{Cache, KvStore, Storage};
+pub use cache::{InMemoryMapCache, LruCache};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use error::{ErrorCode, KvsError, Result};
+pub use eviction::EvictionPolicy;
+pub use kv::{
+    BatchReport, Cache, DeadReason, DeadRecord, KeyStatus, KeyTransform, KvStore, KvStoreBuilder,
+    LoadReport, Log, RecordMeta, Stats, Storage,
+};
+pub use replication::Follower;
+pub use sharded::ShardedKvStore;
 pub use shell::Shell;
+pub use storage::{FileStorage, SyncPolicy, VerifyReport};
+pub use value::Value;
+pub use watch::Watcher;
 
 mod cache;
+mod clock;
 mod error;
+mod eviction;
+mod hash;
 mod kv;
+mod replication;
+mod sharded;
 mod shell;
+#[cfg(unix)]
+mod signal;
 mod storage;
+mod value;
+mod watch;
@@ -0,0 +1,104 @@
+/// This module contains log-shipping replication.
+/// Follower tails log file of the primary store and applies new records to its own store.
+/// For this moment primary should be reachable as a file, network transport will be added
+/// along with server mode
+use crate::storage::{open_db, LogReader};
+use crate::{Cache, InMemoryMapCache, KvStore, Result};
+use std::fs::metadata;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Amount of bytes at the start of the log and before the applied offset,
+/// which are compared to detect that the log was rewritten
+const FINGERPRINT_SIZE: usize = 256;
+
+/// Keep local store in sync with the log of the primary store.
+/// Values stored out-of-line by the primary aren't copied, only records referring to them
+pub struct Follower<C: Cache = InMemoryMapCache> {
+    primary: PathBuf,
+    store: KvStore<C>,
+    /// Offset of the end of the last applied record in primary's log
+    offset: usize,
+    /// Identity of primary's log file, which changes when primary compacts it
+    file_id: Option<u64>,
+    /// Bytes of primary's log around the applied part, see [`fingerprint`]
+    fingerprint: Vec<u8>,
+}
+
+impl<C: Cache> Follower<C> {
+    /// Create follower for the primary log at `primary` path, applying records to `store`.
+    /// Store should be dedicated to the follower, since it's cleared on resync
+    pub fn new<P: AsRef<Path>>(primary: P, store: KvStore<C>) -> Self {
+        Self {
+            primary: primary.as_ref().to_path_buf(),
+            store,
+            offset: 0,
+            file_id: None,
+            fingerprint: Vec::new(),
+        }
+    }
+
+    /// Apply records appended to primary's log since the last sync.
+    /// When primary has been compacted since then, local store is cleared and replayed
+    /// from the start. Compaction is detected by identity of the file where it's available
+    /// (unix), by shrunk log and by changed bytes at the start of the log and right before
+    /// the applied offset. On error sync can be retried, it resumes from the last applied record.
+    /// Return amount of applied records
+    pub fn sync(&mut self) -> Result<usize> {
+        let meta = metadata(&self.primary)?;
+        let file_id = file_id(&meta);
+        let compacted = (self.file_id.is_some() && self.file_id != file_id)
+            || (meta.len() as usize) < self.offset
+            || fingerprint(&self.primary, self.offset)? != self.fingerprint;
+        if compacted {
+            self.store.clear()?;
+            self.offset = 0;
+        }
+        self.file_id = file_id;
+
+        let mut applied = 0;
+        for item in LogReader::open_at(&self.primary, self.offset)? {
+            let (log, offset) = item?;
+            self.store.apply(log)?;
+            self.offset = offset;
+            applied += 1;
+        }
+        self.fingerprint = fingerprint(&self.primary, self.offset)?;
+        Ok(applied)
+    }
+
+    /// Return offset in primary's log, which follower has applied up to
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Return local store of the follower
+    pub fn store(&mut self) -> &mut KvStore<C> {
+        &mut self.store
+    }
+}
+
+/// Return bytes of the log at `path` at its start and right before `offset`,
+/// which is at most the length of the log. Log is only appended to,
+/// so these bytes change only if it's rewritten
+fn fingerprint(path: &Path, offset: usize) -> Result<Vec<u8>> {
+    let mut file = open_db(path)?;
+    let head = offset.min(FINGERPRINT_SIZE);
+    let tail_start = offset.saturating_sub(FINGERPRINT_SIZE).max(head);
+    let mut bytes = vec![0; head + offset - tail_start];
+    file.read_exact(&mut bytes[..head])?;
+    file.seek(SeekFrom::Start(tail_start as u64))?;
+    file.read_exact(&mut bytes[head..])?;
+    Ok(bytes)
+}
+
+#[cfg(unix)]
+pub(crate) fn file_id(meta: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.ino())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_id(_: &std::fs::Metadata) -> Option<u64> {
+    None
+}
@@ -0,0 +1,107 @@
+/// This module contains store, which partitions keys across several log files.
+/// Each shard is an independent `KvStore` with its own file, append handle and cache,
+/// so compaction of one shard doesn't pause writes to others
+use crate::error::Context;
+use crate::hash::fnv1a;
+use crate::storage::with_suffix;
+use crate::{Cache, InMemoryMapCache, KvStore, KvsError, Result};
+use std::fs::{read_to_string, write};
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Store, which routes each key to one of N shards by hash of the key.
+/// Amount of shards is recorded next to the shard files and can't be changed
+/// after creation, otherwise keys would be routed to wrong shards
+pub struct ShardedKvStore<C: Cache = InMemoryMapCache> {
+    shards: Vec<KvStore<C>>,
+}
+
+impl ShardedKvStore {
+    /// Open store at `path` with `shards` shards and default settings.
+    /// Shards are located at `{path}.0` .. `{path}.{shards - 1}`
+    pub fn open<P: AsRef<Path>>(path: P, shards: usize) -> Result<Self> {
+        Self::open_with(path, shards, |shard| KvStore::new(shard))
+    }
+}
+
+impl<C: Cache> ShardedKvStore<C> {
+    /// Open store at `path` with `shards` shards, each opened with `open_shard` from its path,
+    /// e.g. to configure the shard with `KvStore::builder`
+    pub fn open_with<P, F>(path: P, shards: usize, mut open_shard: F) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&Path) -> Result<KvStore<C>>,
+    {
+        assert!(shards > 0, "store should have at least one shard");
+        let path = path.as_ref();
+        check_shard_count(path, shards)?;
+        let shards = (0..shards)
+            .map(|i| open_shard(&with_suffix(path, &format!(".{}", i))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { shards })
+    }
+
+    /// Return index of the shard, which holds given `key`.
+    /// Hash is stable between runs and platforms
+    pub fn shard_for(&self, key: &str) -> usize {
+        (fnv1a(key.as_bytes()) % self.shards.len() as u64) as usize
+    }
+
+    /// Return amount of shards
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Return shard with given index
+    pub fn shard(&mut self, index: usize) -> &mut KvStore<C> {
+        &mut self.shards[index]
+    }
+
+    /// Get cloned String value stored with given `key`
+    pub fn get(&mut self, key: &str) -> Result<String> {
+        let shard = self.shard_for(key);
+        self.shards[shard].get(key)
+    }
+
+    /// Set `value` for the given `key`
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let shard = self.shard_for(key);
+        self.shards[shard].set(key, value)
+    }
+
+    /// Remove `key` from the store
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        let shard = self.shard_for(key);
+        self.shards[shard].remove(key)
+    }
+
+    /// Compact all shards one by one
+    pub fn compact(&mut self) -> Result<()> {
+        self.shards.iter_mut().try_for_each(KvStore::compact)
+    }
+
+    /// Flush all shards to the disk
+    pub fn flush(&mut self) -> Result<()> {
+        self.shards.iter_mut().try_for_each(KvStore::flush)
+    }
+}
+
+/// Compare amount of shards with the one recorded on creation, or record it for new store
+fn check_shard_count(path: &Path, shards: usize) -> Result<()> {
+    let meta = with_suffix(path, ".shards");
+    match read_to_string(&meta) {
+        Ok(content) => {
+            let found = content.trim().parse().unwrap_or(0);
+            if found != shards {
+                return Err(KvsError::ShardCountMismatch {
+                    expected: shards,
+                    found,
+                });
+            }
+            Ok(())
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => write(&meta, format!("{}\n", shards))
+            .context(|| format!("failed to write '{}'", meta.display())),
+        Err(err) => Err(err).context(|| format!("failed to read '{}'", meta.display())),
+    }
+}
@@ -3,60 +3,200 @@
 /// More features will be added later
 use crate::{KvStore, Result};
 use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
-use std::io::{stdin, stdout, Write};
+use log::warn;
+use std::io::{stdin, stdout, BufRead, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
 
 const SHELL_NEW_LINE: &str = ">>> ";
 
+/// Result of a single shell command
+enum Outcome {
+    Done,
+    Exit,
+    /// Command can't be parsed, with description of the problem
+    Invalid(String),
+}
+
+/// Opens store of the given database path, see [`Shell::set_opener`]
+type Opener = Box<dyn Fn(&str) -> Result<KvStore>>;
+
 /// This is main shell instance, which constantly read user's input until get Ctrl + C or quit command
 pub struct Shell {
-    db: KvStore,
+    db: Arc<Mutex<KvStore>>,
+    /// Print stats after every mutation
+    print_stats: bool,
+    opener: Opener,
+    /// Period of background compaction, see [`Shell::set_compact_interval`]
+    compact_interval: Option<Duration>,
 }
 
 impl Shell {
     pub fn create(db: KvStore) -> Self {
-        Shell { db }
+        Shell {
+            db: Arc::new(Mutex::new(db)),
+            print_stats: false,
+            opener: Box::new(|path| KvStore::new(path)),
+            compact_interval: None,
+        }
     }
 
-    /// Start the shell
+    /// Compact store in background every `interval` while the shell runs, if there is
+    /// reclaimable space, so compaction happens at predictable times. Compaction takes
+    /// the same lock as commands, so it never runs in the middle of one
+    pub fn set_compact_interval(&mut self, interval: Duration) {
+        self.compact_interval = Some(interval);
+    }
+
+    /// Set how stores are opened by `use` command, e.g. to keep configuration
+    /// of the current one. Stores are opened with default configuration by default
+    pub fn set_opener<F: Fn(&str) -> Result<KvStore> + 'static>(&mut self, opener: F) {
+        self.opener = Box::new(opener);
+    }
+
+    /// Start the shell.
+    /// On unix SIGINT and SIGTERM are handled as `exit` command: operation in progress
+    /// is completed, store is flushed and process exits with zero code.
     pub fn start(&mut self) -> Result<()> {
+        #[cfg(unix)]
+        self.exit_on_termination()?;
+        self.spawn_compaction();
         let mut app = create_app();
         loop {
             let mut input = String::new();
             print!("{}", SHELL_NEW_LINE);
             stdout().flush()?;
             stdin().read_line(&mut input)?;
-            let args: Vec<&str> = input.trim().split_whitespace().collect();
-            let res_args = app.get_matches_from_safe_borrow(args);
-            match res_args {
-                Ok(args) => match args.subcommand() {
-                    ("set", Some(matches)) => self.set_cmd(matches)?,
-                    ("get", Some(matches)) => self.get_cmd(matches)?,
-                    ("rm", Some(matches)) => self.rm_cmd(matches)?,
-                    ("help", _) => {
-                        app.print_long_help()?;
-                        println!();
-                    }
-                    ("exit", _) => {
-                        println!("Bye!");
-                        break;
-                    }
-                    _ => println!("error: invalid command"),
-                },
-                Err(err) => println!("{}", err),
+            match self.execute(&mut app, &input)? {
+                Outcome::Done => {}
+                Outcome::Exit => {
+                    println!("Bye!");
+                    break;
+                }
+                Outcome::Invalid(err) => println!("{}", err),
             }
         }
         Ok(())
     }
 
+    /// Run commands from the script line by line, the same way as typed in the shell.
+    /// Blank lines and lines starting with `#` are skipped, `exit` ends the script.
+    /// Failed commands are reported to stderr along with their line number.
+    /// Script stops at the first failed command, unless `keep_going` is set.
+    /// Return `true` if every command succeeded
+    pub fn run_script<R: BufRead>(&mut self, script: R, keep_going: bool) -> Result<bool> {
+        self.spawn_compaction();
+        let mut app = create_app();
+        let mut succeeded = true;
+        for (number, line) in script.lines().enumerate() {
+            let line = line?;
+            let command = line.trim();
+            if command.is_empty() || command.starts_with('#') {
+                continue;
+            }
+            let err = match self.execute(&mut app, command) {
+                Ok(Outcome::Done) => continue,
+                Ok(Outcome::Exit) => break,
+                Ok(Outcome::Invalid(err)) => err,
+                Err(err) => err.to_string(),
+            };
+            eprintln!("line {}: {}", number + 1, err.trim_end());
+            succeeded = false;
+            if !keep_going {
+                break;
+            }
+        }
+        Ok(succeeded)
+    }
+
+    /// Parse and run a single command
+    fn execute(&mut self, app: &mut App, input: &str) -> Result<Outcome> {
+        let args: Vec<&str> = input.split_whitespace().collect();
+        let args = match app.get_matches_from_safe_borrow(args) {
+            Ok(args) => args,
+            Err(err) => return Ok(Outcome::Invalid(err.to_string())),
+        };
+        match args.subcommand() {
+            ("set", Some(matches)) => {
+                self.set_cmd(matches)?;
+                self.maybe_print_stats();
+            }
+            ("get", Some(matches)) => self.get_cmd(matches)?,
+            ("rm", Some(matches)) => {
+                self.rm_cmd(matches)?;
+                self.maybe_print_stats();
+            }
+            ("stats", Some(matches)) => self.stats_cmd(matches),
+            ("use", Some(matches)) => {
+                if let Err(err) = self.use_cmd(matches) {
+                    return Ok(Outcome::Invalid(format!("error: {}", err)));
+                }
+            }
+            ("help", _) => {
+                app.print_long_help()?;
+                println!();
+            }
+            ("exit", _) => return Ok(Outcome::Exit),
+            _ => return Ok(Outcome::Invalid("error: invalid command".to_owned())),
+        }
+        Ok(Outcome::Done)
+    }
+
+    #[cfg(unix)]
+    fn exit_on_termination(&self) -> Result<()> {
+        let db = Arc::clone(&self.db);
+        crate::signal::on_termination(move || {
+            // Lock is held until exit, so no new operations are started
+            let mut db = db.lock().unwrap_or_else(PoisonError::into_inner);
+            let code = match db.flush() {
+                Ok(_) => 0,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    1
+                }
+            };
+            println!();
+            println!("Bye!");
+            std::process::exit(code);
+        })
+    }
+
+    /// Start background compaction, which stops once the shell is dropped
+    fn spawn_compaction(&self) {
+        let interval = match self.compact_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let db = Arc::downgrade(&self.db);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let db = match db.upgrade() {
+                Some(db) => db,
+                None => return,
+            };
+            let mut db = db.lock().unwrap_or_else(PoisonError::into_inner);
+            if db.stats().uncompacted_space > 0 {
+                if let Err(err) = db.compact() {
+                    warn!("Background compaction failed: {}", err);
+                }
+            }
+        });
+    }
+
+    fn db(&self) -> MutexGuard<'_, KvStore> {
+        self.db.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
     fn set_cmd(&mut self, args: &ArgMatches) -> Result<()> {
         let key = args.value_of("KEY").unwrap();
         let value = args.value_of("VALUE").unwrap();
-        self.db.set(key, value)
+        self.db().set(key, value)
     }
 
     fn get_cmd(&mut self, args: &ArgMatches) -> Result<()> {
         let key = args.value_of("KEY").unwrap();
-        let entry = self.db.get(key);
+        let entry = self.db().get(key);
         let result = match entry {
             Ok(v) => v,
             _ => "Key not found".to_owned(),
@@ -67,11 +207,46 @@ impl Shell {
 
     fn rm_cmd(&mut self, args: &ArgMatches) -> Result<()> {
         let key = args.value_of("KEY").unwrap();
-        if self.db.remove(key).is_err() {
+        if self.db().remove(key).is_err() {
             println!("Key not found");
         }
         Ok(())
     }
+
+    /// Flush current store and make store of another database active.
+    /// Current store stays active if the new one can't be opened
+    fn use_cmd(&mut self, args: &ArgMatches) -> Result<()> {
+        let path = args.value_of("PATH").unwrap();
+        let store = (self.opener)(path)?;
+        let mut db = self.db();
+        db.flush()?;
+        *db = store;
+        println!("Using {}", path);
+        Ok(())
+    }
+
+    /// Print stats now, or toggle printing them after every mutation
+    fn stats_cmd(&mut self, args: &ArgMatches) {
+        match args.value_of("MODE") {
+            Some("on") => self.print_stats = true,
+            Some("off") => self.print_stats = false,
+            _ => self.print_stats(),
+        }
+    }
+
+    fn maybe_print_stats(&self) {
+        if self.print_stats {
+            self.print_stats();
+        }
+    }
+
+    fn print_stats(&self) {
+        let stats = self.db().stats();
+        println!(
+            "keys: {}, uncompacted: {} bytes, compactions: {}",
+            stats.live_keys, stats.uncompacted_space, stats.compactions
+        );
+    }
 }
 
 fn create_app<'a>() -> App<'a, 'a> {
@@ -118,6 +293,26 @@ fn create_app<'a>() -> App<'a, 'a> {
                         .index(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("print stats of storage, or toggle printing them after every change")
+                .arg(
+                    Arg::with_name("MODE")
+                        .help("print stats after every change")
+                        .possible_values(&["on", "off"])
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("use")
+                .about("switch to another database")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("path to database file")
+                        .required(true)
+                        .index(1),
+                ),
+        )
         .subcommand(SubCommand::with_name("help").about("print help"))
         .subcommand(SubCommand::with_name("exit").about("quit shell"))
 }
@@ -0,0 +1,37 @@
+/// Termination signals handling.
+/// Instead of async signal handlers, SIGINT and SIGTERM are blocked
+/// and synchronously awaited in the dedicated thread, so handler is free
+/// to take locks and do IO
+use crate::Result;
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::thread;
+
+/// Call `handler` from a separate thread, when process receives SIGINT or SIGTERM.
+/// Signals get blocked for the calling thread and all threads spawned by it afterwards,
+/// so this should be called before any other threads are started.
+pub(crate) fn on_termination<F>(handler: F) -> Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let set = unsafe {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        let mut set = set.assume_init();
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        let code = libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        if code != 0 {
+            return Err(io::Error::from_raw_os_error(code).into());
+        }
+        set
+    };
+    thread::spawn(move || {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            handler();
+        }
+    });
+    Ok(())
+}
@@ -1,83 +1,1044 @@
-use crate::error::Result;
-use crate::kv::{Log, Storage};
-use serde_json;
+use crate::error::{Context, KvsError, Result};
+use crate::kv::{now_millis, write_stamped, Log, Storage, Timed};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
 use std::fs::{remove_file, rename, File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{BufReader, ErrorKind, SeekFrom};
+use std::io::{self, BufReader, ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+/// Default limit of a single record size, which protects replay from unbounded buffering
+pub const DEFAULT_MAX_RECORD_SIZE: usize = 64 * 1024 * 1024;
+
+/// Default capacity of the read buffer used on replay
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Write buffer of larger capacity is dropped after use instead of being reused
+const MAX_RETAINED_BUFFER: usize = 1024 * 1024;
+
+/// Start of the first line of log files, which is followed by the format version
+const MAGIC: &str = "kvs-log ";
+
+/// Version of the log format written by this build
+const FORMAT_VERSION: u32 = 1;
+
+/// Header is looked for only in this many first bytes of the file
+const MAX_HEADER_SIZE: u64 = 32;
+/// Maximum length of the record length prefix, i.e. digits of `u64` and `:`
+const MAX_LENGTH_PREFIX: u64 = 21;
+
+/// Amount of records serialized by a single task of parallel compaction
+#[cfg(feature = "parallel-compaction")]
+const PARALLEL_CHUNK_SIZE: usize = 4096;
+
+/// When written records are synced to the disk
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SyncPolicy {
+    /// Sync only on explicit flush. Records written since the last flush
+    /// are lost if the machine crashes
+    #[default]
+    Manual,
+    /// Sync after every write. Nothing is lost, but every write waits for the disk
+    Always,
+    /// Sync in background thread with given interval, so writes don't wait for the disk.
+    /// Records written during the last interval (plus the time of one sync) are lost
+    /// if the machine crashes
+    Interval(Duration),
+}
 
 /// This is implementation of log-based file-system storage.
 /// Each storage represent single file in the filesystem,
-/// containing commands, located each on the new line
+/// containing commands, located each on the new line after the header line
+/// with format version. Legacy files without header are upgraded by compaction
 pub struct FileStorage {
-    path: String,
-    file: File,
-    reader: BufReader<File>,
+    path: PathBuf,
+    /// The only handle of the file, opened for reading and appending.
+    /// Shared with `reader` and background sync thread
+    file: Arc<Mutex<File>>,
+    reader: BufReader<SharedReader>,
+    /// Whether file was created by this instance
+    created: bool,
+    /// Length of the file, tracked on writes to know when the header has to be written
+    len: usize,
+    /// Reusable buffer of serialized records, which saves allocations on every write
+    buffer: Vec<u8>,
+    /// Offset of the reader cursor
+    offset: usize,
+    /// Offset of the first record, i.e. length of the header, 0 for legacy logs without it
+    data_start: usize,
+    max_record_size: usize,
+    /// Whether written records are prefixed by their length, see [`read_line`]
+    length_prefixed: bool,
+    /// Whether records are serialized in parallel on compaction
+    #[cfg(feature = "parallel-compaction")]
+    parallel_compaction: bool,
+    /// Whether records are read from memory-mapped file, see [`FileStorage::set_mmap`]
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    /// Mapping of the file, created on the first read and extended when the file grows
+    #[cfg(feature = "mmap")]
+    map: Option<Mmap>,
+    /// Whether every write fails, see [`FileStorage::set_fail_writes`]
+    #[cfg(feature = "fault-injection")]
+    fail_writes: bool,
+    /// Whether iteration has just been interrupted by error
+    errored: bool,
+    sync_policy: SyncPolicy,
+    /// Stops background sync thread on drop
+    sync_stop: Option<Sender<()>>,
 }
 
 impl Storage for FileStorage {
-    fn new(db_name: &str) -> Result<Self> {
-        let f = match OpenOptions::new().append(true).open(db_name) {
+    fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let db_name = path.as_ref();
+        if db_name.is_dir() {
+            return Err(KvsError::InvalidPath {
+                reason: format!("'{}' is a directory, not a file", db_name.display()),
+            });
+        }
+        recover_compaction(db_name)?;
+        let open_failed = || format!("failed to open database '{}'", db_name.display());
+        let mut created = false;
+        let f = match open_options().open(db_name) {
             Ok(f) => f,
             Err(err) => {
                 if err.kind() == ErrorKind::NotFound {
-                    File::create(db_name)?
+                    created = true;
+                    open_options()
+                        .create(true)
+                        .open(db_name)
+                        .context(open_failed)?
                 } else {
-                    return Err(err.into());
+                    return Err(err).context(open_failed);
                 }
             }
         };
-        Ok(Self {
-            path: db_name.to_owned(),
-            file: f,
-            reader: BufReader::new(File::open(db_name)?),
-        })
+        let len = f.metadata().context(open_failed)?.len() as usize;
+        // Header of empty file is written along with the first record
+        let data_start = if len == 0 { 0 } else { read_header(&mut &f)? };
+        let file = Arc::new(Mutex::new(f));
+        let mut storage = Self {
+            path: db_name.to_path_buf(),
+            reader: BufReader::with_capacity(
+                DEFAULT_READ_BUFFER_SIZE,
+                SharedReader::new(Arc::clone(&file)),
+            ),
+            file,
+            created,
+            len,
+            buffer: Vec::new(),
+            offset: 0,
+            data_start,
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            length_prefixed: false,
+            #[cfg(feature = "parallel-compaction")]
+            parallel_compaction: false,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+            #[cfg(feature = "mmap")]
+            map: None,
+            #[cfg(feature = "fault-injection")]
+            fail_writes: false,
+            errored: false,
+            sync_policy: SyncPolicy::Manual,
+            sync_stop: None,
+        };
+        storage.rewind();
+        Ok(storage)
     }
 
     fn write(&mut self, value: &Log) -> Result<usize> {
-        let serialized = serde_json::to_string(value)?;
-        self.file
-            .write_all(format!("{}\n", serialized).as_bytes())?;
-        Ok(serialized.len())
+        self.write_at(value, now_millis())
     }
 
+    fn write_batch(&mut self, values: &[Log]) -> Result<Vec<usize>> {
+        self.write_batch_at(values, now_millis())
+    }
+
+    /// Write time of the records isn't known, so they are written without it
     fn override_storage(&mut self, values: Vec<&Log>) -> Result<()> {
-        let new_file_name = format!("{}.kvsoverride", &self.path);
-        // rename(self.path, old_file_name)?;
-        let f = File::create(&new_file_name)?;
-        self.file = f;
-        self.reader = BufReader::new(File::open(&self.path)?);
-        let old_file_name = format!("{}.kvsold", &self.path);
-        rename(&self.path, &old_file_name)?;
-        for log in values {
-            self.write(log)?;
-        }
-        rename(new_file_name, &self.path)?;
-        remove_file(&old_file_name)?;
+        self.override_timed(values.into_iter().map(|log| (log, 0)).collect())
+    }
+
+    fn truncate(&mut self) -> Result<()> {
+        #[cfg(feature = "mmap")]
+        self.unmap();
+        let file = self.file();
+        file.set_len(0)
+            .context(|| format!("failed to truncate '{}'", self.path.display()))?;
+        file.sync_data()?;
+        drop(file);
+        self.len = 0;
+        self.data_start = 0;
+        self.errored = false;
+        self.rewind();
+        Ok(())
+    }
+
+    fn size(&self) -> Result<usize> {
+        let meta = self
+            .file()
+            .metadata()
+            .context(|| format!("failed to read size of '{}'", self.path.display()))?;
+        Ok(meta.len() as usize)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut file = self.file();
+        file.flush()?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+impl FileStorage {
+    /// Write value with given write time, see [`Storage::write`]
+    pub(crate) fn write_at(&mut self, value: &Log, ts: u64) -> Result<usize> {
+        self.buffer.clear();
+        self.serialize_into_buffer(value, ts)?;
+        // Line terminator is counted, the same as on replay
+        self.write_buffer()
+    }
+
+    /// Write values with given write time, see [`Storage::write_batch`]
+    pub(crate) fn write_batch_at(&mut self, values: &[Log], ts: u64) -> Result<Vec<usize>> {
+        self.buffer.clear();
+        let mut sizes = Vec::with_capacity(values.len());
+        for value in values {
+            let start = self.buffer.len();
+            self.serialize_into_buffer(value, ts)?;
+            sizes.push(self.buffer.len() - start);
+        }
+        self.write_buffer()?;
+        Ok(sizes)
+    }
+
+    /// Override storage by values along with their write time, see [`Storage::override_storage`]
+    pub(crate) fn override_timed(&mut self, values: Vec<(&Log, u64)>) -> Result<()> {
+        let mut target = self.compaction_target()?;
+        #[cfg(feature = "parallel-compaction")]
+        {
+            if self.parallel_compaction {
+                target.write_parallel(&values)?;
+                return self.replace_with(target);
+            }
+        }
+        for (log, ts) in values {
+            target.write_at(log, ts)?;
+        }
+        self.replace_with(target)
+    }
+
+    /// Serialize records on the thread pool and append them in order they were serialized.
+    /// Only a window of chunks per thread is kept in memory, not the whole log
+    #[cfg(feature = "parallel-compaction")]
+    fn write_parallel(&mut self, values: &[(&Log, u64)]) -> Result<()> {
+        use rayon::prelude::*;
+
+        let prefixed = self.length_prefixed;
+        let window = PARALLEL_CHUNK_SIZE * rayon::current_num_threads();
+        for records in values.chunks(window) {
+            let buffers = records
+                .par_chunks(PARALLEL_CHUNK_SIZE)
+                .map(|chunk| {
+                    let mut buffer = Vec::new();
+                    for (log, ts) in chunk {
+                        serialize_record(&mut buffer, log, *ts, prefixed)?;
+                    }
+                    Ok(buffer)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for buffer in buffers {
+                self.buffer = buffer;
+                self.write_buffer()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create empty storage next to this one, which can be filled with compacted records
+    /// and then moved in place of this one by [`FileStorage::replace_with`]
+    pub(crate) fn compaction_target(&self) -> Result<FileStorage> {
+        let new_file_name = with_suffix(&self.path, ".kvsoverride");
+        // Drop leftovers of interrupted compaction
+        File::create(&new_file_name).context(|| {
+            format!(
+                "failed to create compaction file '{}'",
+                new_file_name.display()
+            )
+        })?;
+        let mut target = FileStorage::new(&new_file_name)?;
+        target.set_max_record_size(self.max_record_size);
+        target.set_length_prefixed(self.length_prefixed);
+        #[cfg(feature = "parallel-compaction")]
+        target.set_parallel_compaction(self.parallel_compaction);
+        Ok(target)
+    }
+
+    /// Replace file of this storage by the file of `target`. Old file is kept as `.kvsold`
+    /// until the new one is in place, so interrupted replacement is recovered on open.
+    /// Header of the current format is written with the first record of `target`,
+    /// so logs of older formats are upgraded
+    pub(crate) fn replace_with(&mut self, mut target: FileStorage) -> Result<()> {
+        target.flush()?;
+        let new_file_name = target.path.clone();
+        let (len, data_start) = (target.len, target.data_start);
+        drop(target);
+        let old_file_name = with_suffix(&self.path, ".kvsold");
+        rename(&self.path, &old_file_name).context(|| {
+            format!(
+                "failed to rename '{}' to '{}'",
+                self.path.display(),
+                old_file_name.display()
+            )
+        })?;
+        rename(&new_file_name, &self.path).context(|| {
+            format!(
+                "failed to rename '{}' to '{}'",
+                new_file_name.display(),
+                self.path.display()
+            )
+        })?;
+        #[cfg(feature = "mmap")]
+        self.unmap();
+        let open_failed = || format!("failed to open database '{}'", self.path.display());
+        *self.file() = open_options().open(&self.path).context(open_failed)?;
+        self.len = len;
+        self.data_start = data_start;
+        // Reader shares the handle, which already points to the new file,
+        // so it only needs to start over
+        self.rewind();
+        remove_file(&old_file_name)
+            .context(|| format!("failed to remove '{}'", old_file_name.display()))?;
+        Ok(())
+    }
+}
+
+/// Result of integrity check of storage file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Amount of valid records
+    pub good_records: usize,
+    /// Amount of records, which can't be deserialized
+    pub bad_records: usize,
+    /// Size of the file in bytes
+    pub total_size: usize,
+}
+
+impl FileStorage {
+    /// Check every record of the storage file at `path` without modifying it.
+    /// Unlike replay, scan doesn't stop at the first corrupted record, so all of them are counted
+    pub fn verify<P: AsRef<Path>>(path: P) -> Result<VerifyReport> {
+        let mut file = open_db(path.as_ref())?;
+        let mut report = VerifyReport {
+            total_size: file.metadata()?.len() as usize,
+            ..VerifyReport::default()
+        };
+        let data_start = read_header(&mut file)?;
+        file.seek(SeekFrom::Start(data_start as u64))?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let size = match read_line(&mut reader, usize::MAX, &mut line) {
+                Ok(size) => size.unwrap_or_default(),
+                // Line isn't valid UTF-8, so it's skipped as a bad record
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    reader.read_until(b'\n', &mut Vec::new())?;
+                    report.bad_records += 1;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if size == 0 {
+                break;
+            }
+            if is_blank(&line) {
+                continue;
+            }
+            if parse_record(&line).is_ok() {
+                report.good_records += 1;
+            } else {
+                report.bad_records += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Path to the storage file
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Set maximum size of a single record in bytes, including line terminator.
+    /// Reading a longer record fails with [`KvsError::RecordTooLarge`]
+    /// instead of buffering it in memory
+    pub fn set_max_record_size(&mut self, limit: usize) {
+        self.max_record_size = limit;
+    }
+
+    /// Return maximum size of a single record in bytes
+    pub fn max_record_size(&self) -> usize {
+        self.max_record_size
+    }
+
+    /// Prefix written records by their length, e.g. `25:{"op":"remove","key":"k"}`,
+    /// so framing doesn't depend on the absence of line terminators in serialized records.
+    /// Both framings are read regardless of this setting, so it can be changed for existing logs
+    pub fn set_length_prefixed(&mut self, enabled: bool) {
+        self.length_prefixed = enabled;
+    }
+
+    /// Return whether written records are prefixed by their length
+    pub fn length_prefixed(&self) -> bool {
+        self.length_prefixed
+    }
+
+    /// Serialize records on all cores during compaction, writing them in a single sequential
+    /// pass afterwards. Order of records is different from the sequential compaction,
+    /// which doesn't matter as every key is written once
+    #[cfg(feature = "parallel-compaction")]
+    pub fn set_parallel_compaction(&mut self, enabled: bool) {
+        self.parallel_compaction = enabled;
+    }
+
+    /// Return whether records are serialized in parallel on compaction
+    #[cfg(feature = "parallel-compaction")]
+    pub fn parallel_compaction(&self) -> bool {
+        self.parallel_compaction
+    }
+
+    /// Read records from memory-mapped file instead of buffered reads, so the OS page cache
+    /// manages what is kept in memory. Writes still append to the file, which is mapped again
+    /// once reads reach the end of the mapping. Log must not be truncated by other
+    /// processes while it's mapped, reads of the truncated part would crash the process
+    #[cfg(feature = "mmap")]
+    pub fn set_mmap(&mut self, enabled: bool) {
+        self.mmap = enabled;
+        self.unmap();
+        self.rewind();
+    }
+
+    /// Return whether records are read from memory-mapped file
+    #[cfg(feature = "mmap")]
+    pub fn mmap(&self) -> bool {
+        self.mmap
+    }
+
+    /// Make every following write fail without touching the file, e.g. to test handling
+    /// of IO errors. Storage created by compaction writes normally
+    #[cfg(feature = "fault-injection")]
+    pub fn set_fail_writes(&mut self, enabled: bool) {
+        self.fail_writes = enabled;
+    }
+
+    /// Drop mapping of the file, e.g. before it's truncated
+    #[cfg(feature = "mmap")]
+    fn unmap(&mut self) {
+        self.map = None;
+    }
+
+    /// Return mapped bytes of the file starting at `offset`,
+    /// mapping the file again if it grew past the current mapping
+    #[cfg(feature = "mmap")]
+    fn mapped_from(&mut self, offset: usize) -> io::Result<&[u8]> {
+        let mapped = self.map.as_ref().map_or(0, |map| map.len());
+        if self.map.is_none() || offset >= mapped {
+            let file = self.file();
+            if self.map.is_none() || file.metadata()?.len() as usize > mapped {
+                // SAFETY: file is only appended to by this crate, so mapped bytes don't change.
+                // Mapping is dropped before the file is truncated or replaced
+                let map = unsafe { Mmap::map(&*file)? };
+                drop(file);
+                self.map = Some(map);
+            }
+        }
+        let map = self.map.as_deref().unwrap_or_default();
+        Ok(map.get(offset..).unwrap_or_default())
+    }
+
+    /// Read the next record at the cursor, see [`read_line`]
+    fn read_record(&mut self, buff: &mut String) -> io::Result<Option<usize>> {
+        let limit = self.max_record_size;
+        #[cfg(feature = "mmap")]
+        {
+            if self.mmap {
+                let offset = self.offset;
+                return read_line(&mut self.mapped_from(offset)?, limit, buff);
+            }
+        }
+        read_line(&mut self.reader, limit, buff)
+    }
+
+    /// Set capacity of the read buffer used on replay. Larger buffer speeds up replay
+    /// of large logs. Buffered data is dropped, so the next iteration starts over
+    pub fn set_read_buffer_size(&mut self, capacity: usize) {
+        self.reader = BufReader::with_capacity(capacity, SharedReader::new(Arc::clone(&self.file)));
+        self.rewind();
+    }
+
+    /// Return capacity of the read buffer used on replay
+    pub fn read_buffer_size(&self) -> usize {
+        self.reader.capacity()
+    }
+
+    /// Set when written records are synced to the disk.
+    /// For [`SyncPolicy::Interval`] background thread is started,
+    /// it's stopped when policy is changed or storage is dropped
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_stop = None;
+        self.sync_policy = policy;
+        if let SyncPolicy::Interval(interval) = policy {
+            let (stop, stopped) = channel::<()>();
+            let file = Arc::clone(&self.file);
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let file = file.lock().unwrap_or_else(PoisonError::into_inner);
+                    // Error will be reported by the next explicit flush
+                    let _ = file.sync_data();
+                }
+            });
+            self.sync_stop = Some(stop);
+        }
+    }
+
+    /// Return when written records are synced to the disk
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    /// Append serialized record with its write time and line terminator to the buffer.
+    /// Zero time stands for unknown one, so it isn't written
+    fn serialize_into_buffer(&mut self, value: &Log, ts: u64) -> Result<()> {
+        serialize_record(&mut self.buffer, value, ts, self.length_prefixed)
+    }
+
+    /// Return size of the record, as it would be written at time `ts`
+    pub(crate) fn record_size(&self, value: &Log, ts: u64) -> Result<usize> {
+        let mut buffer = Vec::new();
+        serialize_record(&mut buffer, value, ts, self.length_prefixed)?;
+        Ok(buffer.len())
+    }
+
+    /// Append serialized records from the buffer to the file at once. Return written size
+    fn write_buffer(&mut self) -> Result<usize> {
+        #[cfg(feature = "fault-injection")]
+        if self.fail_writes {
+            return Err(io::Error::other("injected write failure").into());
+        }
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let empty = self.len == 0;
+        if empty {
+            self.len = write_header(&mut *file)?;
+        }
+        file.write_all(&self.buffer)?;
+        if self.sync_policy == SyncPolicy::Always {
+            file.sync_data()?;
+        }
+        drop(file);
+        if empty {
+            // Nothing could be read from the empty file, so reader only skips the new header
+            self.data_start = self.len;
+            self.rewind();
+        }
+        let size = self.buffer.len();
+        self.len += size;
+        // Don't hold memory of a single huge record for the lifetime of the storage
+        if self.buffer.capacity() > MAX_RETAINED_BUFFER {
+            self.buffer = Vec::new();
+        }
+        Ok(size)
+    }
+
+    fn file(&self) -> MutexGuard<'_, File> {
+        self.file.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Drop everything after `offset`, e.g. torn record at the end of the log.
+    /// `offset` must point to the end of a record
+    pub(crate) fn truncate_to(&mut self, offset: usize) -> Result<()> {
+        #[cfg(feature = "mmap")]
+        self.unmap();
+        let file = self.file();
+        file.set_len(offset as u64)
+            .context(|| format!("failed to truncate '{}'", self.path.display()))?;
+        file.sync_data()?;
+        drop(file);
+        self.len = offset;
+        self.errored = false;
+        self.rewind();
         Ok(())
     }
+
+    /// Read record of known location, e.g. remembered when it was written,
+    /// without moving cursor of the storage
+    pub(crate) fn read_at(&self, offset: usize, size: usize) -> Result<Timed> {
+        #[cfg(feature = "mmap")]
+        {
+            // Records written after the file was mapped are read from the file
+            let map = self.map.as_deref().filter(|_| self.mmap);
+            if let Some(record) = map.and_then(|map| map.get(offset..offset + size)) {
+                let line = std::str::from_utf8(record)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                return Ok(parse_timed(line)?);
+            }
+        }
+        let mut buff = vec![0; size];
+        let mut file = self.file();
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut buff)?;
+        drop(file);
+        let line = String::from_utf8(buff)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(parse_timed(&line)?)
+    }
+
+    /// Return `true` if storage file didn't exist and was created on open
+    pub fn is_created(&self) -> bool {
+        self.created
+    }
+
+    /// Read records, starting from the given byte offset, without moving cursor of the storage.
+    /// Each record is yielded with offset of its end, so it can be used to continue reading
+    /// later, e.g. for replication: poll with the last seen offset to get only new records.
+    /// `offset` must point to the start of a record (0 or offset returned before)
+    pub fn iter_from_offset(&self, offset: usize) -> impl Iterator<Item = Result<(Log, usize)>> {
+        let reader = LogReader::open_at(&self.path, offset);
+        let reader = reader.map(|r| r.max_record_size(self.max_record_size));
+        let (failed, reader) = match reader {
+            Ok(reader) => (None, Some(reader)),
+            Err(err) => (Some(Err(err)), None),
+        };
+        failed.into_iter().chain(reader.into_iter().flatten())
+    }
+
+    /// Return `true` if the file starts with header of the current format
+    pub(crate) fn has_header(&self) -> bool {
+        self.data_start > 0
+    }
+
+    /// Return offset of the end of the last record read by the iterator
+    pub(crate) fn read_offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Move cursor to the first record.
+    /// Since in every get request to storage we should read entire file,
+    /// we need to return cursor to the start, to enable reader re-usage
+    /// in case of few get requests from one KvStore instance.
+    /// Seeking also drops the reader's buffer
+    fn rewind(&mut self) {
+        if self
+            .reader
+            .seek(SeekFrom::Start(self.data_start as u64))
+            .is_err()
+        {};
+        self.offset = self.data_start;
+    }
 }
 
 impl Iterator for FileStorage {
     type Item = Result<(Log, usize)>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_timed()
+            .map(|item| item.map(|(log, size, _)| (log, size)))
+    }
+}
+
+impl FileStorage {
+    /// Return the next record along with its size and write time, 0 if it's unknown
+    pub(crate) fn next_timed(&mut self) -> Option<Result<(Log, usize, u64)>> {
+        if self.errored {
+            // Cursor is already rewound, so end current iteration to not start over
+            self.errored = false;
+            return None;
+        }
         let mut buff = String::new();
-        match self.reader.read_line(&mut buff) {
-            Ok(size) => {
+        let mut read = self.read_record(&mut buff);
+        // Blank lines, e.g. left by manual edits, carry no records
+        while let Ok(Some(size)) = read {
+            if size == 0 || !is_blank(&buff) {
+                break;
+            }
+            self.offset += size;
+            buff.clear();
+            read = self.read_record(&mut buff);
+        }
+        match read {
+            Ok(Some(size)) => {
                 if size == 0 {
-                    // Since in every get request to storage we should read entire file,
-                    // we need to return cursor to the start, to enable reader re-usage
-                    // in case of few get requests from one KvStore instance
-                    if self.reader.seek(SeekFrom::Start(0)).is_err() {};
+                    self.rewind();
                     None
                 } else {
-                    match serde_json::from_str(&buff) {
-                        Ok(item) => Some(Ok((item, size))),
-                        Err(_) => None,
+                    match parse_timed(&buff) {
+                        Ok(Timed(log, ts)) => {
+                            self.offset += size;
+                            Some(Ok((log, size, ts)))
+                        }
+                        Err(_) => {
+                            self.rewind();
+                            None
+                        }
+                    }
+                }
+            }
+            Ok(None) => {
+                let offset = self.offset;
+                self.rewind();
+                self.errored = true;
+                Some(Err(KvsError::RecordTooLarge {
+                    offset,
+                    limit: self.max_record_size,
+                }))
+            }
+            Err(_) => {
+                self.rewind();
+                None
+            }
+        }
+    }
+}
+
+/// Options of the storage file handle. In append mode writes always go to the end of the file,
+/// regardless of the position, which reads seek to
+fn open_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.read(true).append(true);
+    options
+}
+
+/// Reader over the handle shared with writes. Keeps its own position
+/// and seeks the handle to it before every read
+struct SharedReader {
+    file: Arc<Mutex<File>>,
+    pos: u64,
+}
+
+impl SharedReader {
+    fn new(file: Arc<Mutex<File>>) -> Self {
+        Self { file, pos: 0 }
+    }
+}
+
+impl Read for SharedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        file.seek(SeekFrom::Start(self.pos))?;
+        let size = file.read(buf)?;
+        self.pos += size as u64;
+        Ok(size)
+    }
+}
+
+impl Seek for SharedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(delta) => (self.pos as i64 + delta) as u64,
+            SeekFrom::End(delta) => {
+                let file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+                (file.metadata()?.len() as i64 + delta) as u64
+            }
+        };
+        Ok(self.pos)
+    }
+}
+
+/// Directory of values of the database at `path`, stored out-of-line
+pub(crate) fn blob_dir(path: &Path) -> PathBuf {
+    with_suffix(path, ".blobs")
+}
+
+/// Open database file for reading
+pub(crate) fn open_db(path: &Path) -> Result<File> {
+    File::open(path).context(|| format!("failed to open database '{}'", path.display()))
+}
+
+/// Bring back database after compaction, which was interrupted, e.g. by crash.
+/// If the database was already moved aside, the old file is restored, as the new one
+/// may be incomplete. If the new file is in place, old one is just a leftover
+fn recover_compaction(path: &Path) -> Result<()> {
+    let old_file_name = with_suffix(path, ".kvsold");
+    if !old_file_name.exists() {
+        return Ok(());
+    }
+    if path.exists() {
+        remove_file(&old_file_name)
+            .context(|| format!("failed to remove '{}'", old_file_name.display()))
+    } else {
+        rename(&old_file_name, path).context(|| {
+            format!(
+                "failed to restore '{}' from '{}'",
+                path.display(),
+                old_file_name.display()
+            )
+        })
+    }
+}
+
+/// Return path of the history archive of the database
+pub(crate) fn history_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".history")
+}
+
+/// Pass every line of the log file, including header, to `f`,
+/// stopping at the first oversized line
+pub(crate) fn scan_lines<F: FnMut(&str) -> Result<()>>(
+    path: &Path,
+    limit: usize,
+    mut f: F,
+) -> Result<()> {
+    let mut reader = BufReader::new(open_db(path)?);
+    let mut line = String::new();
+    let mut offset = 0;
+    loop {
+        line.clear();
+        match read_line(&mut reader, limit, &mut line)? {
+            Some(0) => return Ok(()),
+            Some(size) => {
+                f(&line)?;
+                offset += size;
+            }
+            None => return Err(KvsError::RecordTooLarge { offset, limit }),
+        }
+    }
+}
+
+/// Append `suffix` to the file name, e.g. `kvs.db` -> `kvs.db.kvsold`.
+/// Unlike `format!` it works with paths, which aren't valid UTF-8
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Read a single record, but not more than `limit` bytes.
+/// Record is either a line or, if it starts with a digit, a length prefix followed by `:`,
+/// the given amount of bytes and a line terminator. Whole record, including the prefix,
+/// is appended to `buff`. Return `None` if record is longer than the limit
+pub(crate) fn read_line<R: BufRead>(
+    reader: &mut R,
+    limit: usize,
+    buff: &mut String,
+) -> io::Result<Option<usize>> {
+    let limit = limit as u64;
+    let framed = reader.fill_buf()?.first().is_some_and(u8::is_ascii_digit);
+    if !framed {
+        let size = reader.take(limit.saturating_add(1)).read_line(buff)?;
+        return Ok(if size as u64 > limit {
+            None
+        } else {
+            Some(size)
+        });
+    }
+    let mut record = Vec::new();
+    reader
+        .take(MAX_LENGTH_PREFIX.min(limit.saturating_add(1)))
+        .read_until(b':', &mut record)?;
+    let len = std::str::from_utf8(&record[..record.len() - 1])
+        .ok()
+        .filter(|_| record.ends_with(b":"))
+        .and_then(|len| len.parse::<u64>().ok());
+    match len {
+        Some(len) if (record.len() as u64).saturating_add(len) >= limit => return Ok(None),
+        // Payload and its line terminator
+        Some(len) => reader.take(len + 1).read_to_end(&mut record)?,
+        // Corrupted prefix is returned as is, so parsing of the record fails
+        None => reader.read_until(b'\n', &mut record)?,
+    };
+    let record =
+        String::from_utf8(record).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    buff.push_str(&record);
+    Ok(Some(record.len()))
+}
+
+/// Return serialized record without line terminator and length prefix.
+/// Both `\n` and `\r\n` terminated logs (e.g. edited on Windows) are supported
+fn record_payload(line: &str) -> &str {
+    let line = line.trim_end_matches(['\n', '\r']);
+    match line.split_once(':') {
+        Some((len, payload)) if !len.is_empty() && len.bytes().all(|b| b.is_ascii_digit()) => {
+            payload
+        }
+        _ => line,
+    }
+}
+
+/// Parse record from the line, see [`record_payload`]
+pub(crate) fn parse_record(line: &str) -> serde_json::Result<Log> {
+    serde_json::from_str(record_payload(line))
+}
+
+/// Write header of the current format to the empty log file. Return its length
+fn write_header<W: Write>(file: &mut W) -> io::Result<usize> {
+    let header = format!("{}{}\n", MAGIC, FORMAT_VERSION);
+    file.write_all(header.as_bytes())?;
+    Ok(header.len())
+}
+
+/// Check header of the log file, read from the start.
+/// Return length of the header, 0 for legacy logs, which start right with records
+fn read_header<R: Read>(file: &mut R) -> Result<usize> {
+    let mut start = Vec::new();
+    file.take(MAX_HEADER_SIZE).read_to_end(&mut start)?;
+    match start.first() {
+        None | Some(b'{') => return Ok(0),
+        Some(b) if b.is_ascii_whitespace() => return Ok(0),
+        _ => {}
+    }
+    let header = start.strip_prefix(MAGIC.as_bytes()).and_then(|rest| {
+        let end = rest.iter().position(|&b| b == b'\n')?;
+        let version = std::str::from_utf8(&rest[..end]).ok()?;
+        Some((version.trim_end().parse().ok()?, MAGIC.len() + end + 1))
+    });
+    let (version, len) = header.ok_or(KvsError::UnknownFormat)?;
+    if version != FORMAT_VERSION {
+        return Err(KvsError::VersionMismatch {
+            expected: FORMAT_VERSION,
+            found: version,
+        });
+    }
+    Ok(len)
+}
+
+/// Append serialized record to `buffer`, see [`FileStorage::set_length_prefixed`]
+fn serialize_record(buffer: &mut Vec<u8>, value: &Log, ts: u64, prefixed: bool) -> Result<()> {
+    let start = buffer.len();
+    if ts == 0 {
+        serde_json::to_writer(&mut *buffer, value)?;
+    } else {
+        write_stamped(&mut *buffer, value, ts)?;
+    }
+    if prefixed {
+        let prefix = format!("{}:", buffer.len() - start);
+        buffer.splice(start..start, prefix.into_bytes());
+    }
+    buffer.push(b'\n');
+    Ok(())
+}
+
+/// Return `true` if line contains no record
+fn is_blank(line: &str) -> bool {
+    line.bytes().all(|b| b.is_ascii_whitespace())
+}
+
+/// Parse record along with its write time, see [`parse_record`]
+pub(crate) fn parse_timed(line: &str) -> serde_json::Result<Timed> {
+    serde_json::from_str(record_payload(line))
+}
+
+/// Append serialized records to the history archive of the database at `path`
+pub(crate) fn append_history(path: &Path, records: &[u8]) -> Result<()> {
+    let archive_name = history_path(path);
+    let mut archive = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&archive_name)
+        .context(|| format!("failed to open history '{}'", archive_name.display()))?;
+    archive.write_all(records)?;
+    archive.sync_data()?;
+    Ok(())
+}
+
+/// Sequential reader of storage file, independent from the reader of `FileStorage`,
+/// so scanning with it doesn't move cursor of the store.
+/// Yields log entries along with offset of the record end. Stops at the first corrupted
+/// record, the same as replay of [`FileStorage`]
+pub(crate) struct LogReader {
+    reader: BufReader<File>,
+    offset: usize,
+    max_record_size: usize,
+    done: bool,
+    /// Write time of the last yielded record
+    written_at: u64,
+}
+
+impl LogReader {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        Self::open_at(path, 0)
+    }
+
+    /// Open reader, which starts reading from the given byte offset.
+    /// Reading from the start skips the header
+    pub(crate) fn open_at(path: &Path, offset: usize) -> Result<Self> {
+        let mut file = open_db(path)?;
+        let offset = match offset {
+            0 => read_header(&mut file)?,
+            offset => offset,
+        };
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        Ok(Self {
+            reader,
+            offset,
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            done: false,
+            written_at: 0,
+        })
+    }
+
+    /// Set maximum size of a single record in bytes
+    pub(crate) fn max_record_size(mut self, limit: usize) -> Self {
+        self.max_record_size = limit;
+        self
+    }
+
+    /// Return write time of the last yielded record, 0 if it's unknown
+    pub(crate) fn written_at(&self) -> u64 {
+        self.written_at
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Result<(Log, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut buff = String::new();
+        match read_line(&mut self.reader, self.max_record_size, &mut buff) {
+            Ok(Some(0)) => {
+                self.done = true;
+                None
+            }
+            Ok(Some(size)) if is_blank(&buff) => {
+                self.offset += size;
+                self.next()
+            }
+            Ok(Some(size)) => {
+                self.offset += size;
+                match parse_timed(&buff) {
+                    Ok(Timed(log, ts)) => {
+                        self.written_at = ts;
+                        Some(Ok((log, self.offset)))
                     }
+                    // Corrupted record ends the log, the same as on replay
+                    Err(_) => {
+                        self.done = true;
+                        None
+                    }
+                }
+            }
+            Ok(None) => {
+                self.done = true;
+                Some(Err(KvsError::RecordTooLarge {
+                    offset: self.offset,
+                    limit: self.max_record_size,
+                }))
+            }
+            Err(err) => {
+                self.done = true;
+                if err.kind() == ErrorKind::InvalidData {
+                    return None;
                 }
+                Some(Err(err.into()))
             }
-            Err(_) => None,
         }
     }
 }
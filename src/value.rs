@@ -0,0 +1,170 @@
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::fmt;
+
+/// Typed value of the key
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// String value
+    Str(String),
+    /// Signed integer
+    Int(i64),
+    /// Floating point number
+    Float(f64),
+    /// Boolean
+    Bool(bool),
+    /// Arbitrary bytes
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    /// Return string, if value is [`Value::Str`]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Return name of the value type, e.g. for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "Str",
+            Value::Int(_) => "Int",
+            Value::Float(_) => "Float",
+            Value::Bool(_) => "Bool",
+            Value::Bytes(_) => "Bytes",
+        }
+    }
+
+    /// Return textual representation of the value, as it's returned by `KvStore::get`.
+    /// Strings are borrowed, bytes are decoded as UTF-8 with invalid sequences replaced
+    pub fn to_text(&self) -> Cow<'_, str> {
+        match self {
+            Value::Str(s) => Cow::Borrowed(s),
+            Value::Bytes(b) => String::from_utf8_lossy(b),
+            other => Cow::Owned(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Bytes(b) => write!(f, "{}", String::from_utf8_lossy(b)),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_owned())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        Value::Float(x)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Bytes(b)
+    }
+}
+
+/// On-disk representation of non-string values, tagged with the type, e.g. `{"Int":5}`
+#[derive(Deserialize)]
+enum Tagged {
+    Str(String),
+    Int(i64),
+    Float(Float),
+    Bool(bool),
+    Bytes(Vec<u8>),
+}
+
+/// Floats are stored as numbers, except for NaN and infinities, which JSON can't represent,
+/// so they are stored as text, e.g. `{"Float":"-inf"}`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Float {
+    Number(f64),
+    Text(String),
+}
+
+/// Serialize float the way [`Float`] reads it
+struct FloatRepr(f64);
+
+impl Serialize for FloatRepr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0.is_finite() {
+            serializer.serialize_f64(self.0)
+        } else {
+            serializer.collect_str(&self.0)
+        }
+    }
+}
+
+/// Strings are stored untagged, so logs written before typed values appeared stay readable
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Repr {
+    Plain(String),
+    Tagged(Tagged),
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Str(s) => serializer.serialize_str(s),
+            Value::Int(i) => serializer.serialize_newtype_variant("Value", 1, "Int", i),
+            Value::Float(x) => {
+                serializer.serialize_newtype_variant("Value", 2, "Float", &FloatRepr(*x))
+            }
+            Value::Bool(b) => serializer.serialize_newtype_variant("Value", 3, "Bool", b),
+            Value::Bytes(b) => serializer.serialize_newtype_variant("Value", 4, "Bytes", b),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Plain(s) | Repr::Tagged(Tagged::Str(s)) => Value::Str(s),
+            Repr::Tagged(Tagged::Int(i)) => Value::Int(i),
+            Repr::Tagged(Tagged::Float(Float::Number(x))) => Value::Float(x),
+            Repr::Tagged(Tagged::Float(Float::Text(text))) => match text.parse() {
+                Ok(x) => Value::Float(x),
+                Err(_) => {
+                    return Err(D::Error::custom(format!("invalid float '{}'", text)));
+                }
+            },
+            Repr::Tagged(Tagged::Bool(b)) => Value::Bool(b),
+            Repr::Tagged(Tagged::Bytes(b)) => Value::Bytes(b),
+        })
+    }
+}
@@ -0,0 +1,81 @@
+/// This module contains polling of a single key for changes.
+/// Watcher tails the log the same way as `Follower`, but keeps only the value of one key,
+/// so it can be used against a database of another process without loading it
+use crate::error::Context;
+use crate::kv::{now_millis, read_value};
+use crate::replication::file_id;
+use crate::storage::{blob_dir, LogReader};
+use crate::Result;
+use std::borrow::Cow;
+use std::fs::metadata;
+use std::path::{Path, PathBuf};
+
+/// Track changes of the value of a single key in the database file
+pub struct Watcher {
+    path: PathBuf,
+    key: String,
+    value: Option<String>,
+    /// Offset of the end of the last scanned record
+    offset: usize,
+    /// Identity of the log file, which changes on compaction
+    file_id: Option<u64>,
+}
+
+impl Watcher {
+    /// Start watching `key` in the database at `path`. Current value is read immediately
+    pub fn new<P: AsRef<Path>>(path: P, key: &str) -> Result<Self> {
+        let mut watcher = Self {
+            path: path.as_ref().to_path_buf(),
+            key: key.to_owned(),
+            value: None,
+            offset: 0,
+            file_id: None,
+        };
+        watcher.poll()?;
+        Ok(watcher)
+    }
+
+    /// Return the last seen value of the key, `None` if key doesn't exist
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    /// Read records appended since the last poll and return changes of the value in order,
+    /// `None` stands for removal. When log has been compacted since the last poll,
+    /// it's scanned from the start and at most one change, to the current value, is returned
+    pub fn poll(&mut self) -> Result<Vec<Option<String>>> {
+        let meta = metadata(&self.path)
+            .context(|| format!("failed to open database '{}'", self.path.display()))?;
+        let file_id = file_id(&meta);
+        let compacted = self.file_id.is_some() && self.file_id != file_id;
+        let rescan = compacted || (meta.len() as usize) < self.offset;
+        if rescan {
+            self.offset = 0;
+        }
+        self.file_id = file_id;
+
+        let now = now_millis();
+        let mut value = if rescan { None } else { self.value.clone() };
+        let mut changes = Vec::new();
+        for item in LogReader::open_at(&self.path, self.offset)? {
+            let (log, offset) = item?;
+            self.offset = offset;
+            let new = match log.key() {
+                Some(k) if k == self.key => {
+                    read_value(&blob_dir(&self.path), &log, now)?.map(Cow::into_owned)
+                }
+                Some(_) => continue,
+                None => None,
+            };
+            if !rescan && new != value {
+                changes.push(new.clone());
+            }
+            value = new;
+        }
+        if rescan && value != self.value {
+            changes.push(value.clone());
+        }
+        self.value = value;
+        Ok(changes)
+    }
+}
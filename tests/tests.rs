@@ -1,8 +1,24 @@
+// Original CLI tests predate these lints and are kept as written
+#![allow(
+    clippy::needless_borrows_for_generic_args,
+    clippy::redundant_pattern_matching
+)]
 use assert_cmd::prelude::*;
-use kvs::KvStore;
+use kvs::{
+    Cache, DeadReason, DeadRecord, ErrorCode, EvictionPolicy, FileStorage, Follower,
+    InMemoryMapCache, KeyStatus, KvStore, KvsError, LoadReport, Log, LruCache, MockClock,
+    ShardedKvStore, Storage, SyncPolicy, Value,
+};
+use predicates::prelude::*;
 use predicates::str::contains;
-use std::fs::remove_file;
-use std::process::Command;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{metadata, read, remove_file, write};
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
 
 const TEST_DB_NAME: &str = "test_kvs.db";
 
@@ -10,6 +26,14 @@ fn clean_db() {
     if let Err(_) = remove_file(TEST_DB_NAME) {};
 }
 
+/// Return current unix time in milliseconds
+fn system_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
 // `kvs` with no args should exit with a non-zero code.
 #[test]
 fn cli_no_args() {
@@ -59,7 +83,7 @@ fn cli_rm() {
         .unwrap()
         .args(&["--db", TEST_DB_NAME, "rm", "key1"])
         .assert()
-        .failure()
+        .code(ErrorCode::NotFound as i32)
         .stderr(contains("Key not found"));
     clean_db();
 }
@@ -181,3 +205,3642 @@ fn remove_key() {
     assert!(store.get("key1").is_err());
     clean_db();
 }
+
+// Disk fallback should return the latest record, even after compaction re-created the file
+#[test]
+fn get_after_cache_clear_reads_latest_value() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+
+    store.set("a", "1").unwrap();
+    assert_eq!(store.get("a").unwrap(), "1".to_owned());
+    // Overwrite key enough times to trigger compaction
+    let big_value = "x".repeat(1024);
+    for _ in 0..1100 {
+        store.set("a", &big_value).unwrap();
+    }
+    store.set("a", "2").unwrap();
+    store.clear_cache().unwrap();
+    assert_eq!(store.get("a").unwrap(), "2".to_owned());
+
+    store.set("a", "3").unwrap();
+    store.clear_cache().unwrap();
+    assert_eq!(store.get("a").unwrap(), "3".to_owned());
+}
+
+// Bulk load with disabled auto-compaction shouldn't run any compaction
+#[test]
+fn bulk_load_without_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    store.set_auto_compact(false);
+
+    let big_value = "x".repeat(1024);
+    for i in 0..2048 {
+        store.set(&format!("key{}", i % 16), &big_value).unwrap();
+    }
+    assert_eq!(store.stats().compactions, 0);
+
+    let size_before = metadata(&db).unwrap().len();
+    store.compact().unwrap();
+    assert_eq!(store.stats().compactions, 1);
+    assert!(metadata(&db).unwrap().len() < size_before);
+    assert_eq!(store.get("key3").unwrap(), big_value);
+}
+
+// `kvs --no-compact` and `KVS_NO_COMPACT` should keep storage uncompacted past the threshold
+#[test]
+fn cli_no_compact() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).auto_compact(false).build().unwrap();
+    let big_value = "x".repeat(512 * 1024);
+    for _ in 0..4 {
+        store.set("key1", &big_value).unwrap();
+    }
+    drop(store);
+    let size = metadata(&db).unwrap().len();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--no-compact", "set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(metadata(&db).unwrap().len() > size);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .env("KVS_NO_COMPACT", "1")
+        .args(["set", "key3", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(metadata(&db).unwrap().len() > size);
+
+    // Without the flag the same write compacts storage
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key4", "value4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(metadata(&db).unwrap().len() < size);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value2"));
+}
+
+// Inspection commands should fail on missing database instead of creating it
+#[test]
+fn cli_inspection_of_missing_db() {
+    let temp_dir = TempDir::new().unwrap();
+    for args in [
+        vec!["list"],
+        vec!["inspect", "--dead"],
+        vec!["histogram"],
+        vec!["export"],
+        vec!["export", "--jsonl"],
+    ] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["--db", "missing.db"])
+            .args(&args)
+            .current_dir(&temp_dir)
+            .assert()
+            .code(ErrorCode::InvalidInput as i32)
+            .stderr(contains("'missing.db' doesn't exist"));
+    }
+    assert!(!temp_dir.path().join("missing.db").exists());
+}
+
+// `kvs set <KEY> --stdin` should store entire stdin as a value
+#[test]
+fn cli_set_from_stdin() {
+    let temp_dir = TempDir::new().unwrap();
+    let value = "first line\nsecond line\twith tab\n";
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "--stdin"])
+        .current_dir(&temp_dir)
+        .write_stdin(value)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value", "--stdin"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs get <KEY> --raw` and `--out` should output exact value bytes
+#[test]
+fn cli_get_raw_and_out() {
+    let temp_dir = TempDir::new().unwrap();
+    let value = "line\nnext line";
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", value])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--raw"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(value);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+
+    let out = temp_dir.path().join("data.bin");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--out", out.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(read(&out).unwrap(), value.as_bytes());
+}
+
+// Scans of the log should stop at corrupted record, the same as replay
+#[test]
+fn log_scans_stop_at_corrupted_record() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.remove("key2").unwrap();
+    drop(store);
+    let content = std::fs::read_to_string(&db).unwrap();
+    write(&db, format!("{}garbage\n", content)).unwrap();
+
+    let mut store = KvStore::new(&db).unwrap();
+    let entries: Vec<(String, String)> = store.stream_entries().map(|e| e.unwrap()).collect();
+    assert_eq!(entries, vec![("key1".to_owned(), "value1".to_owned())]);
+    assert_eq!(store.prefix_counts(":").unwrap().len(), 1);
+    let tombstones: Result<Vec<_>, _> = store.iter_tombstones().collect();
+    assert_eq!(tombstones.unwrap().len(), 2);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key1\",\"value\":\"value1\"}\n");
+    store.compact_into(temp_dir.path().join("copy.db")).unwrap();
+    while !store.compact_step(1).unwrap() {}
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+
+    let mut copy = KvStore::new(temp_dir.path().join("copy.db")).unwrap();
+    assert_eq!(copy.get("key1").unwrap(), "value1".to_owned());
+    assert!(copy.get("key2").is_err());
+}
+
+// Streaming entries from disk should yield latest value of all live keys
+#[test]
+fn stream_entries_from_disk() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    for i in 0..100 {
+        store
+            .set(&format!("key{}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    for i in 0..50 {
+        store.set(&format!("key{}", i), "overwritten").unwrap();
+    }
+    for i in 90..100 {
+        store.remove(&format!("key{}", i)).unwrap();
+    }
+
+    let mut entries: Vec<(String, String)> = store.stream_entries().map(|e| e.unwrap()).collect();
+    entries.sort();
+    let mut expected: Vec<(String, String)> = (0..90)
+        .map(|i| {
+            let value = if i < 50 {
+                "overwritten".to_owned()
+            } else {
+                format!("value{}", i)
+            };
+            (format!("key{}", i), value)
+        })
+        .collect();
+    expected.sort();
+    assert_eq!(entries, expected);
+}
+
+// `kvs bench` should run workload and report throughput and latency
+#[test]
+fn cli_bench() {
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["bench", "--ops", "1000", "--value-size", "10"])
+        .assert()
+        .success()
+        .stdout(contains("throughput").and(contains("p99")));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["bench", "--ops", "many"])
+        .assert()
+        .failure();
+    for ratio in ["5", "-1", "half"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["bench", "--read-ratio", ratio])
+            .assert()
+            .failure()
+            .stderr(contains("expected number from 0 to 1"));
+    }
+}
+
+// `set_nx` should set only absent or removed keys
+#[test]
+fn set_if_absent() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+
+    assert!(store.set_nx("key1", "value1").unwrap());
+    assert!(!store.set_nx("key1", "value2").unwrap());
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+
+    store.remove("key1").unwrap();
+    assert!(store.set_nx("key1", "value3").unwrap());
+    assert_eq!(store.get("key1").unwrap(), "value3".to_owned());
+
+    drop(store);
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value3".to_owned());
+}
+
+// `kvs set --nx` on existing key should fail and leave value unchanged
+#[test]
+fn cli_set_nx() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--nx"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value2", "--nx"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(ErrorCode::ReadOnly as i32)
+        .stderr(contains("Key already exists"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// `kvs set --ttl` should make key expire between invocations
+#[test]
+fn cli_set_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "1s"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    sleep(Duration::from_millis(1200));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "60x"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid duration"));
+}
+
+// Shell should print stats on demand and after every change once toggled on
+#[test]
+fn shell_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let output = assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("shell")
+        .current_dir(&temp_dir)
+        .write_stdin("set key1 value1\nstats\nstats on\nset key2 value2\nset key1 value3\nstats off\nrm key2\nexit\n")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    let stats: Vec<&str> = output.lines().filter(|l| l.contains("keys: ")).collect();
+    assert_eq!(stats.len(), 3);
+    assert!(stats[0].contains("keys: 1, uncompacted: 0 bytes"));
+    assert!(stats[1].contains("keys: 2, uncompacted: 0 bytes"));
+    assert!(stats[2].contains("keys: 2, uncompacted: "));
+    assert!(!stats[2].contains("uncompacted: 0 bytes"));
+}
+
+// SIGTERM to the shell should exit cleanly, keeping data written before
+#[cfg(unix)]
+#[test]
+fn shell_exits_on_sigterm() {
+    use std::io::{Read, Write};
+    use std::process::Stdio;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("shell")
+        .current_dir(&temp_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    stdin.write_all(b"set key1 value1\n").unwrap();
+    // Second prompt means the command has been completed
+    let mut output = String::new();
+    let mut buff = [0; 64];
+    while output.matches(">>> ").count() < 2 {
+        let n = stdout.read(&mut buff).unwrap();
+        assert_ne!(n, 0);
+        output.push_str(&String::from_utf8_lossy(&buff[..n]));
+    }
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+    stdout.read_to_string(&mut output).unwrap();
+    assert!(output.contains("Bye!"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// Records written before `clear` shouldn't be replayed. Audit mode keeps `Clear` record in the log
+#[test]
+fn clear_replay() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).audit(true).build().unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.clear().unwrap();
+    store.set("key2", "value3").unwrap();
+    store.set("key4", "value4").unwrap();
+    assert!(store.get("key1").is_err());
+    drop(store);
+
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    assert!(store.get("key1").is_err());
+    assert_eq!(store.get("key2").unwrap(), "value3".to_owned());
+    assert_eq!(store.get("key4").unwrap(), "value4".to_owned());
+    store.clear_cache().unwrap();
+    assert!(store.get("key1").is_err());
+    assert_eq!(store.get("key2").unwrap(), "value3".to_owned());
+    assert_eq!(store.stream_entries().count(), 2);
+
+    let size_before = metadata(&db).unwrap().len();
+    store.compact().unwrap();
+    assert!(metadata(&db).unwrap().len() < size_before);
+    assert!(store.get("key1").is_err());
+    assert_eq!(store.get("key4").unwrap(), "value4".to_owned());
+}
+
+// Preloaded store should serve reads from cache, while store without preloading reads from disk
+#[test]
+fn preload_cache_on_and_off() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let db = db.to_str().unwrap();
+    let mut writer = KvStore::new(db).unwrap();
+    writer.set("key1", "value1").unwrap();
+
+    let mut preloaded = KvStore::builder(db).build().unwrap();
+    let mut cold = KvStore::builder(db)
+        .preload_cache(false)
+        .build_with_cache(LruCache::with_capacity(2))
+        .unwrap();
+    writer.set("key1", "value2").unwrap();
+    assert_eq!(preloaded.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(cold.get("key1").unwrap(), "value2".to_owned());
+
+    let mut warmed = KvStore::builder(db).preload_cache(false).build().unwrap();
+    warmed.warm_cache().unwrap();
+    writer.set("key1", "value3").unwrap();
+    assert_eq!(warmed.get("key1").unwrap(), "value2".to_owned());
+}
+
+// Store with small LRU cache should read evicted keys from disk and compact without data loss
+#[test]
+fn lru_cache_disk_fallback() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(db.to_str().unwrap())
+        .preload_cache(false)
+        .build_with_cache(LruCache::with_capacity(2))
+        .unwrap();
+    for i in 0..10 {
+        store
+            .set(&format!("key{}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    store.remove("key5").unwrap();
+    for i in 0..10 {
+        match i {
+            5 => assert!(store.get("key5").is_err()),
+            _ => assert_eq!(
+                store.get(&format!("key{}", i)).unwrap(),
+                format!("value{}", i)
+            ),
+        }
+    }
+
+    store.compact().unwrap();
+    assert!(store.get("key5").is_err());
+    for i in (0..10).filter(|&i| i != 5) {
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            format!("value{}", i)
+        );
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+    age: u32,
+}
+
+// Typed values should be serialized to JSON and deserialized back
+#[test]
+fn typed_get_and_set() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    let user = User {
+        name: "John".to_owned(),
+        age: 42,
+    };
+    store.set_as("user", &user).unwrap();
+    assert_eq!(store.get_as::<User>("user").unwrap(), Some(user));
+    assert_eq!(store.get_as::<User>("missing").unwrap(), None);
+
+    store.set("broken", "not a user").unwrap();
+    match store.get_as::<User>("broken") {
+        Err(KvsError::Serde(_)) => {}
+        other => panic!("expected serde error, got {:?}", other),
+    }
+}
+
+// Reading from offset should yield only records written after it
+#[test]
+fn storage_iter_from_offset() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut storage = FileStorage::new(db.to_str().unwrap()).unwrap();
+    storage
+        .write(&Log::Set("key1".to_owned(), "value1".into()))
+        .unwrap();
+    storage
+        .write(&Log::Set("key2".to_owned(), "value2".into()))
+        .unwrap();
+    let (_, offset) = storage.iter_from_offset(0).last().unwrap().unwrap();
+    assert_eq!(offset as u64, metadata(&db).unwrap().len());
+
+    storage.write(&Log::Remove("key1".to_owned())).unwrap();
+    storage
+        .write(&Log::Set("key3".to_owned(), "value3".into()))
+        .unwrap();
+    let new_records: Vec<(Log, usize)> = storage
+        .iter_from_offset(offset)
+        .map(|r| r.unwrap())
+        .collect();
+    assert_eq!(new_records.len(), 2);
+    match &new_records[0].0 {
+        Log::Remove(k) => assert_eq!(k, "key1"),
+        other => panic!("unexpected record {:?}", other),
+    }
+    match &new_records[1].0 {
+        Log::Set(k, v) => assert_eq!((k.as_str(), v.as_str()), ("key3", Some("value3"))),
+        other => panic!("unexpected record {:?}", other),
+    }
+    assert_eq!(new_records[1].1 as u64, metadata(&db).unwrap().len());
+    assert_eq!(storage.iter_from_offset(new_records[1].1).count(), 0);
+}
+
+// Follower should converge with primary, including after primary's compaction
+#[test]
+fn follower_converges_with_primary() {
+    let temp_dir = TempDir::new().unwrap();
+    let primary_db = temp_dir.path().join("primary.db");
+    let follower_db = temp_dir.path().join("follower.db");
+    let mut primary = KvStore::new(primary_db.to_str().unwrap()).unwrap();
+    let mut follower = Follower::new(
+        primary_db.to_str().unwrap(),
+        KvStore::new(follower_db.to_str().unwrap()).unwrap(),
+    );
+
+    primary.set("key1", "value1").unwrap();
+    primary.set("key2", "value2").unwrap();
+    assert_eq!(follower.sync().unwrap(), 2);
+    assert_eq!(follower.store().get("key1").unwrap(), "value1".to_owned());
+
+    primary.remove("key1").unwrap();
+    primary.set("key3", "value3").unwrap();
+    assert_eq!(follower.sync().unwrap(), 2);
+    assert_eq!(follower.sync().unwrap(), 0);
+    assert!(follower.store().get("key1").is_err());
+    assert_eq!(follower.store().get("key3").unwrap(), "value3".to_owned());
+
+    primary.set("key2", "value4").unwrap();
+    primary.compact().unwrap();
+    primary.set("key5", "value5").unwrap();
+    follower.sync().unwrap();
+    assert_eq!(
+        follower.offset() as u64,
+        metadata(&primary_db).unwrap().len()
+    );
+    for (key, value) in &[("key2", "value4"), ("key3", "value3"), ("key5", "value5")] {
+        assert_eq!(follower.store().get(key).unwrap(), value.to_string());
+    }
+    assert!(follower.store().get("key1").is_err());
+}
+
+// Follower should resync when primary's log is rewritten in place and grows past
+// the applied offset, which keeps identity of the file and can't be seen from its length
+#[test]
+fn follower_detects_rewritten_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let primary_db = temp_dir.path().join("primary.db");
+    let follower_db = temp_dir.path().join("follower.db");
+    let mut primary = KvStore::new(&primary_db).unwrap();
+    let mut follower = Follower::new(&primary_db, KvStore::new(&follower_db).unwrap());
+    primary.set("key1", "value1").unwrap();
+    primary.set("key2", "value2").unwrap();
+    assert_eq!(follower.sync().unwrap(), 2);
+    drop(primary);
+
+    let rewritten = concat!(
+        "{\"op\":\"set\",\"key\":\"key3\",\"value\":\"value3\"}\n",
+        "{\"op\":\"set\",\"key\":\"key4\",\"value\":\"a much longer value4\"}\n",
+        "{\"op\":\"set\",\"key\":\"key5\",\"value\":\"value5\"}\n",
+    );
+    assert!(rewritten.len() > follower.offset());
+    write(&primary_db, rewritten).unwrap();
+    assert_eq!(follower.sync().unwrap(), 3);
+    assert!(follower.store().get("key1").is_err());
+    for (key, value) in [
+        ("key3", "value3"),
+        ("key4", "a much longer value4"),
+        ("key5", "value5"),
+    ] {
+        assert_eq!(follower.store().get(key).unwrap(), value);
+    }
+}
+
+// `get_many` should return only existing keys
+#[test]
+fn get_many_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.set("key3", "value3").unwrap();
+    store.remove("key3").unwrap();
+
+    let values = store.get_many(&["key1", "key2", "key3", "key4"]).unwrap();
+    let mut expected = HashMap::new();
+    expected.insert("key1".to_owned(), "value1".to_owned());
+    expected.insert("key2".to_owned(), "value2".to_owned());
+    assert_eq!(values, expected);
+}
+
+// `multi_get_with_defaults` should return defaults for absent keys
+#[test]
+fn multi_get_with_defaults_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("host", "example.com").unwrap();
+    store.set("port", "8080").unwrap();
+    store.remove("port").unwrap();
+
+    let values = store
+        .multi_get_with_defaults(&[("host", "localhost"), ("port", "80"), ("user", "guest")])
+        .unwrap();
+    let mut expected = HashMap::new();
+    expected.insert("host".to_owned(), "example.com".to_owned());
+    expected.insert("port".to_owned(), "80".to_owned());
+    expected.insert("user".to_owned(), "guest".to_owned());
+    assert_eq!(values, expected);
+}
+
+// `set_expire` should make existing key expire and `persist` should cancel it
+#[test]
+fn set_expire_and_persist() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set_value("key2", Value::Int(2)).unwrap();
+    assert!(!store.set_expire("key3", Duration::from_millis(50)).unwrap());
+    assert!(!store.persist("key1").unwrap());
+
+    assert!(store.set_expire("key1", Duration::from_millis(50)).unwrap());
+    assert!(store.set_expire("key2", Duration::from_millis(50)).unwrap());
+    assert!(store.persist("key2").unwrap());
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    sleep(Duration::from_millis(100));
+    assert!(store.get("key1").is_err());
+    assert!(!store.persist("key1").unwrap());
+    assert_eq!(store.get_value("key2").unwrap(), Value::Int(2));
+}
+
+// Expired keys should be purged with their space accounted as reclaimable
+#[test]
+fn purge_expired_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let clock = MockClock::new(system_millis());
+    let mut store = KvStore::builder(&db).clock(clock.clone()).build().unwrap();
+    store
+        .set_with_ttl("key1", "value1", Duration::from_millis(50))
+        .unwrap();
+    store
+        .set_with_ttl("key2", "value2", Duration::from_millis(50))
+        .unwrap();
+    store
+        .set_with_ttl("key3", "value3", Duration::from_secs(3600))
+        .unwrap();
+    store.set("key4", "value4").unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+
+    clock.advance(Duration::from_millis(100));
+    assert!(store.get("key1").is_err());
+    let uncompacted = store.stats().uncompacted_space;
+    assert_eq!(store.purge_expired().unwrap(), 2);
+    assert!(store.stats().uncompacted_space > uncompacted);
+    assert_eq!(store.purge_expired().unwrap(), 0);
+    drop(store);
+
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    let size_before = metadata(&db).unwrap().len();
+    store.compact().unwrap();
+    assert!(metadata(&db).unwrap().len() < size_before);
+    assert!(store.get("key1").is_err());
+    assert!(store.get("key2").is_err());
+    assert_eq!(store.get("key3").unwrap(), "value3".to_owned());
+    assert_eq!(store.get("key4").unwrap(), "value4".to_owned());
+}
+
+// Failure to open database should mention its path
+#[test]
+fn open_error_contains_path() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("missing").join("kvs.db");
+    let err = KvStore::new(db.to_str().unwrap()).err().unwrap();
+    let message = format!("{}", err);
+    assert!(message.contains(&format!("failed to open database '{}'", db.display())));
+    #[cfg(unix)]
+    assert!(message.contains("No such file or directory"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", db.to_str().unwrap(), "get", "key1"])
+        .assert()
+        .failure()
+        .stderr(contains("failed to open database").and(contains("missing")));
+}
+
+// Store should support indexing by key and iterating over live pairs
+#[test]
+fn index_and_iterate() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.set("key3", "value3").unwrap();
+    store.remove("key3").unwrap();
+    assert_eq!(&store["key1"], "value1");
+
+    let mut pairs: Vec<(String, String)> = (&store).into_iter().collect();
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+
+    let cold = KvStore::builder(db.to_str().unwrap())
+        .preload_cache(false)
+        .build()
+        .unwrap();
+    assert_eq!(cold.into_iter().count(), 2);
+}
+
+#[test]
+#[should_panic(expected = "key not found")]
+fn index_missing_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let store = KvStore::new(db.to_str().unwrap()).unwrap();
+    let _ = &store["key1"];
+}
+
+// Log with CR-LF line endings should be replayed as usual
+#[test]
+fn crlf_terminated_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    write(
+        &db,
+        "{\"Set\":[\"key1\",\"value1\"]}\r\n{\"Set\":[\"key2\",\"value2\"]}\r\n{\"Remove\":\"key1\"}\r\n",
+    )
+    .unwrap();
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    assert!(store.get("key1").is_err());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+    store.set("key3", "value3").unwrap();
+    store.clear_cache().unwrap();
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+    assert_eq!(store.get("key3").unwrap(), "value3".to_owned());
+    assert_eq!(store.stream_entries().count(), 2);
+}
+
+// `open_or_create` should tell whether database is new
+#[test]
+fn open_or_create_new_and_existing() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let (mut store, created) = KvStore::open_or_create(db.to_str().unwrap()).unwrap();
+    assert!(created);
+    store.set("key1", "value1").unwrap();
+    drop(store);
+
+    let (mut store, created) = KvStore::open_or_create(db.to_str().unwrap()).unwrap();
+    assert!(!created);
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+}
+
+// Oversized record should fail replay instead of being buffered
+#[test]
+fn record_too_large() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let first = "{\"Set\":[\"key1\",\"value1\"]}\n";
+    let big = format!("{{\"Set\":[\"key2\",\"{}\"]}}\n", "x".repeat(2000));
+    write(&db, format!("{}{}", first, big)).unwrap();
+
+    let opened = KvStore::builder(db.to_str().unwrap())
+        .max_record_size(1024)
+        .build();
+    match opened {
+        Err(KvsError::RecordTooLarge { offset, limit }) => {
+            assert_eq!(offset, first.len());
+            assert_eq!(limit, 1024);
+        }
+        _ => panic!("expected RecordTooLarge error"),
+    }
+
+    let mut store = KvStore::builder(db.to_str().unwrap())
+        .preload_cache(false)
+        .max_record_size(1024)
+        .build()
+        .unwrap();
+    assert!(matches!(
+        store.get("key1"),
+        Err(KvsError::RecordTooLarge { .. })
+    ));
+}
+
+// Keys should be routed to the same shards after reopen
+#[test]
+fn sharded_store_consistent_routing() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let path = db.to_str().unwrap();
+    let mut store = ShardedKvStore::open(path, 4).unwrap();
+    for i in 0..100 {
+        store
+            .set(&format!("key{}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    store.remove("key0").unwrap();
+    let placement: Vec<usize> = (0..100)
+        .map(|i| store.shard_for(&format!("key{}", i)))
+        .collect();
+    assert!(placement.iter().any(|&shard| shard != placement[0]));
+    store.compact().unwrap();
+    drop(store);
+
+    let mut store = ShardedKvStore::open(path, 4).unwrap();
+    assert!(store.get("key0").is_err());
+    for (i, &expected) in placement.iter().enumerate().skip(1) {
+        let key = format!("key{}", i);
+        let shard = store.shard_for(&key);
+        assert_eq!(shard, expected);
+        assert_eq!(store.shard(shard).get(&key).unwrap(), format!("value{}", i));
+        assert_eq!(store.get(&key).unwrap(), format!("value{}", i));
+    }
+    drop(store);
+
+    assert!(matches!(
+        ShardedKvStore::open(path, 3),
+        Err(KvsError::ShardCountMismatch {
+            expected: 3,
+            found: 4
+        })
+    ));
+}
+
+// `with_value` should pass borrowed value to the callback
+#[test]
+fn with_value_borrows_value() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    store.set("key1", "value1").unwrap();
+    assert_eq!(store.with_value("key1", str::len).unwrap(), Some(6));
+    assert_eq!(store.with_value("key2", str::len).unwrap(), None);
+
+    store.clear_cache().unwrap();
+    let prefix = store.with_value("key1", |v| v[..5].to_owned()).unwrap();
+    assert_eq!(prefix, Some("value".to_owned()));
+    store.remove("key1").unwrap();
+    assert_eq!(store.with_value("key1", str::len).unwrap(), None);
+}
+
+// Write-once store should reject overwrite, but allow set after remove
+#[test]
+fn write_once_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(db.to_str().unwrap())
+        .write_once(true)
+        .build()
+        .unwrap();
+    store.set("key1", "value1").unwrap();
+    assert!(matches!(
+        store.set("key1", "value2"),
+        Err(KvsError::KeyExists)
+    ));
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+
+    store.remove("key1").unwrap();
+    store.set("key1", "value2").unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value2".to_owned());
+}
+
+// `kvs --write-once set` should fail on existing key
+#[test]
+fn cli_set_write_once() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--write-once", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--write-once", "set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key already exists"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// Load report should describe records replayed on open
+#[test]
+fn load_report_on_open() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let content =
+        "{\"Set\":[\"key1\",\"value1\"]}\n{\"Set\":[\"key2\",\"value2\"]}\n{\"Remove\":\"key1\"}\n";
+    write(&db, content).unwrap();
+    let store = KvStore::new(db.to_str().unwrap()).unwrap();
+    assert_eq!(
+        store.last_load_report(),
+        Some(&LoadReport {
+            records_replayed: 3,
+            live_keys: 1,
+            bytes_read: content.len(),
+            records_skipped: 0,
+            torn_tail: false,
+        })
+    );
+
+    // Replay stops at corrupted record, so it's reported along with the records after it
+    write(
+        &db,
+        format!("{}garbage\n{{\"Set\":[\"key3\",\"value3\"]}}\n", content),
+    )
+    .unwrap();
+    let store = KvStore::new(db.to_str().unwrap()).unwrap();
+    let report = store.last_load_report().unwrap();
+    assert_eq!(report.records_replayed, 3);
+    assert_eq!(report.records_skipped, 2);
+
+    let store = KvStore::builder(db.to_str().unwrap())
+        .preload_cache(false)
+        .build()
+        .unwrap();
+    assert!(store.last_load_report().is_none());
+}
+
+// Store should convert to a map of live pairs and load from it
+#[test]
+fn hashmap_snapshot() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.set("key2", "value3").unwrap();
+    store.set("key3", "value4").unwrap();
+    store.remove("key3").unwrap();
+    let map = store.to_hashmap().unwrap();
+    let mut expected = HashMap::new();
+    expected.insert("key1".to_owned(), "value1".to_owned());
+    expected.insert("key2".to_owned(), "value3".to_owned());
+    assert_eq!(map, expected);
+    assert_eq!(
+        serde_json::to_value(&store).unwrap(),
+        serde_json::json!({"key1": "value1", "key2": "value3"})
+    );
+
+    let other_db = temp_dir.path().join("other.db");
+    let mut other = KvStore::new(other_db.to_str().unwrap()).unwrap();
+    other.load_hashmap(&map).unwrap();
+    assert_eq!(other.to_hashmap().unwrap(), expected);
+}
+
+// Store with preallocated cache should replay as usual
+#[test]
+fn cache_capacity_hint() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    for i in 0..100 {
+        store.set(&format!("key{}", i), "value").unwrap();
+    }
+    drop(store);
+    let mut store = KvStore::builder(db.to_str().unwrap())
+        .cache_capacity(100)
+        .build()
+        .unwrap();
+    assert_eq!(store.get("key99").unwrap(), "value".to_owned());
+    assert_eq!(store.last_load_report().unwrap().live_keys, 100);
+}
+
+// `kvs verify` should succeed on a clean database
+#[test]
+fn cli_verify_clean() {
+    let temp_dir = TempDir::new().unwrap();
+    let content = "{\"Set\":[\"key1\",\"value1\"]}\n{\"Remove\":\"key1\"}\n";
+    write(temp_dir.path().join("kvs.db"), content).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!(
+            "good records: 2\nbad records: 0\ntotal size: {} bytes\n",
+            content.len()
+        ));
+}
+
+// `kvs verify` should count all corrupted records and fail without modifying the file
+#[test]
+fn cli_verify_corrupt() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let content =
+        "{\"Set\":[\"key1\",\"value1\"]}\ngarbage\n{\"Remove\":\"key1\"}\n{\"Set\":[\"key2\"\n";
+    write(&db, content).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(ErrorCode::Corrupt as i32)
+        .stdout(contains("good records: 2").and(contains("bad records: 2")));
+    assert_eq!(read(&db).unwrap(), content.as_bytes());
+}
+
+// Records should reach the file within sync interval without explicit flush
+#[test]
+fn sync_policy_interval() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(db.to_str().unwrap())
+        .sync_policy(SyncPolicy::Interval(Duration::from_millis(50)))
+        .build()
+        .unwrap();
+    store.set("key1", "value1").unwrap();
+    sleep(Duration::from_millis(200));
+
+    let mut reader = KvStore::new(db.to_str().unwrap()).unwrap();
+    assert_eq!(reader.get("key1").unwrap(), "value1".to_owned());
+    // Compaction replaces the file, background sync should keep working with the new one
+    store.compact().unwrap();
+    store.set("key2", "value2").unwrap();
+    sleep(Duration::from_millis(200));
+    let mut reader = KvStore::new(db.to_str().unwrap()).unwrap();
+    assert_eq!(reader.get("key2").unwrap(), "value2".to_owned());
+}
+
+// `reopen` should pick up changes made to the file by another process
+#[test]
+fn reopen_picks_external_changes() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    store.set("key1", "value1").unwrap();
+
+    let mut other = KvStore::new(db.to_str().unwrap()).unwrap();
+    other.set("key2", "value2").unwrap();
+    other.remove("key1").unwrap();
+    other.compact().unwrap();
+    drop(other);
+
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    store.reopen().unwrap();
+    assert!(store.get("key1").is_err());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+    store.set("key3", "value3").unwrap();
+    drop(store);
+
+    let mut store = KvStore::new(db.to_str().unwrap()).unwrap();
+    assert_eq!(store.get("key3").unwrap(), "value3".to_owned());
+}
+
+// Store should work with `Path` in a subdirectory with spaces, including compaction
+#[test]
+fn path_with_spaces() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("my data");
+    std::fs::create_dir(&dir).unwrap();
+    let db = dir.join("kvs store.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key1", "value2").unwrap();
+    store.compact().unwrap();
+    drop(store);
+
+    let (mut store, created) = KvStore::open_or_create(db.as_path()).unwrap();
+    assert!(!created);
+    assert_eq!(store.get("key1").unwrap(), "value2".to_owned());
+    assert_eq!(FileStorage::verify(&db).unwrap().good_records, 1);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+}
+
+// Records should be readable from disk right after they're written, including after compaction
+#[test]
+fn read_back_from_disk_after_write() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).preload_cache(false).build().unwrap();
+    for i in 0..10 {
+        let value = format!("value{}", i);
+        store.set("key1", &value).unwrap();
+        store.clear_cache().unwrap();
+        assert_eq!(store.get("key1").unwrap(), value);
+    }
+    store.compact().unwrap();
+    store.set("key2", "value2").unwrap();
+    store.clear_cache().unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value9".to_owned());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+    assert_eq!(FileStorage::verify(&db).unwrap().good_records, 2);
+}
+
+// `kvs watch` should print current value and every change of it
+#[test]
+fn cli_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let set = |value: &str| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", "key1", value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    };
+    set("value1");
+    let mut watch = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["watch", "key1"])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(watch.stdout.take().unwrap()).lines();
+    let mut next_line = || lines.next().unwrap().unwrap();
+
+    let first = next_line();
+    set("value2");
+    let second = next_line();
+    watch.kill().unwrap();
+    watch.wait().unwrap();
+
+    let (time, value) = first.split_once(' ').unwrap();
+    assert!(time.parse::<u64>().is_ok());
+    assert_eq!(value, "value1");
+    assert!(second.ends_with(" value2"));
+}
+
+// Large values should be stored out-of-line and their files removed when unreferenced
+#[test]
+fn blob_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let blobs = temp_dir.path().join("kvs.db.blobs");
+    let open = || KvStore::builder(&db).blob_threshold(100).build().unwrap();
+    let blob_count = || std::fs::read_dir(&blobs).unwrap().count();
+    let big1 = "a".repeat(1000);
+    let big2 = "b".repeat(1000);
+
+    let mut store = open();
+    store.set("small", "value").unwrap();
+    store.set("big", &big1).unwrap();
+    assert_eq!(blob_count(), 1);
+    assert!(metadata(&db).unwrap().len() < 200);
+    assert_eq!(store.get("big").unwrap(), big1);
+    assert_eq!(store.with_value("big", str::len).unwrap(), Some(1000));
+
+    // Overwrite removes the old blob
+    store.set("big", &big2).unwrap();
+    assert_eq!(blob_count(), 1);
+    drop(store);
+
+    let mut store = open();
+    assert_eq!(store.get("big").unwrap(), big2);
+    assert_eq!(store.stream_entries().count(), 2);
+    let mut pairs = store.get_all_pairs().unwrap();
+    pairs.sort();
+    assert_eq!(pairs[0], ("big".to_owned(), big2.clone()));
+
+    // Orphaned blobs are collected by compaction
+    write(blobs.join("orphan"), "x").unwrap();
+    assert_eq!(blob_count(), 2);
+    store.compact().unwrap();
+    assert_eq!(blob_count(), 1);
+    assert_eq!(store.get("big").unwrap(), big2);
+
+    store.remove("big").unwrap();
+    assert_eq!(blob_count(), 0);
+    assert!(store.get("big").is_err());
+}
+
+// Directory passed as a database path should give a clear error
+#[test]
+fn db_path_is_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    match KvStore::new(temp_dir.path()) {
+        Err(err @ KvsError::InvalidPath { .. }) => {
+            assert!(err.to_string().contains("is a directory"));
+        }
+        _ => panic!("expected InvalidPath error"),
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", ".", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid database path: '.' is a directory"));
+}
+
+// Live data size should be less than disk usage before compaction and close to it after
+#[test]
+fn disk_usage_and_live_data_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    for i in 0..100 {
+        store
+            .set(&format!("key{}", i % 10), &format!("value{}", i))
+            .unwrap();
+    }
+    let stats = store.stats();
+    assert_eq!(stats.disk_usage, store.disk_usage().unwrap());
+    assert_eq!(stats.live_data_size, store.live_data_size());
+    assert!((stats.live_data_size as u64) < stats.disk_usage / 5);
+
+    store.compact().unwrap();
+    let header = "kvs-log 1\n".len() as u64;
+    assert_eq!(
+        store.live_data_size() as u64 + header,
+        store.disk_usage().unwrap()
+    );
+}
+
+// `kvs compact --dry-run` should print estimate and leave the file intact
+#[test]
+fn cli_compact_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key1", "value2").unwrap();
+    store.set("key2", "value3").unwrap();
+    store.remove("key2").unwrap();
+    drop(store);
+    let before = read(&db).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("would reclaim ").and(contains("would reclaim 0 ").not()));
+    assert_eq!(read(&db).unwrap(), before);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let size = metadata(&db).unwrap().len();
+    output.stdout(format!(
+        "reclaimed {} bytes, resulting file size {} bytes\n",
+        before.len() as u64 - size,
+        size
+    ));
+}
+
+// Typed values should round-trip through disk, keeping their types
+#[test]
+fn typed_values_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let values = vec![
+        ("str", Value::Str("value".to_owned())),
+        ("int", Value::Int(-42)),
+        ("float", Value::Float(1.5)),
+        ("bool", Value::Bool(true)),
+        ("bytes", Value::Bytes(vec![0, 159, 255])),
+    ];
+    let mut store = KvStore::new(&db).unwrap();
+    for (key, value) in &values {
+        store.set_value(key, value.clone()).unwrap();
+    }
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    for (key, value) in &values {
+        assert_eq!(&store.get_value(key).unwrap(), value);
+    }
+    assert_eq!(store.get("str").unwrap(), "value".to_owned());
+    assert_eq!(store.get("int").unwrap(), "-42".to_owned());
+    // Strings are stored untagged, the same as before typed values
+    let log = String::from_utf8(read(&db).unwrap()).unwrap();
+    assert!(log.contains("{\"op\":\"set\",\"key\":\"str\",\"value\":\"value\","));
+    assert!(log.contains("{\"op\":\"set\",\"key\":\"int\",\"value\":{\"Int\":-42},"));
+}
+
+// NaN and infinities can't be JSON numbers, but should round-trip without breaking replay
+#[test]
+fn non_finite_floats_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set_value("nan", Value::Float(f64::NAN)).unwrap();
+    store.set_value("inf", Value::Float(f64::INFINITY)).unwrap();
+    store
+        .set_value("-inf", Value::Float(f64::NEG_INFINITY))
+        .unwrap();
+    store.set("after", "value").unwrap();
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.last_load_report().unwrap().records_skipped, 0);
+    match store.get_value("nan").unwrap() {
+        Value::Float(x) => assert!(x.is_nan()),
+        other => panic!("unexpected value {:?}", other),
+    }
+    assert_eq!(store.get_value("inf").unwrap(), Value::Float(f64::INFINITY));
+    assert_eq!(
+        store.get_value("-inf").unwrap(),
+        Value::Float(f64::NEG_INFINITY)
+    );
+    assert_eq!(store.get("after").unwrap(), "value");
+    store.set("later", "value").unwrap();
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.get("later").unwrap(), "value");
+    let log = String::from_utf8(read(&db).unwrap()).unwrap();
+    assert!(log.contains(r#""value":{"Float":"-inf"}"#));
+}
+
+// `incr` should add to integers and fail on other types
+#[test]
+fn incr_typed() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.incr("counter", 5).unwrap(), 5);
+    assert_eq!(store.incr("counter", -2).unwrap(), 3);
+    assert_eq!(store.get_value("counter").unwrap(), Value::Int(3));
+
+    store.set("name", "value").unwrap();
+    assert!(matches!(
+        store.incr("name", 1),
+        Err(KvsError::WrongType {
+            expected: "Int",
+            found: "Str"
+        })
+    ));
+    store.set_value("max", Value::Int(i64::MAX)).unwrap();
+    assert!(matches!(
+        store.incr("max", 1),
+        Err(KvsError::IntegerOverflow)
+    ));
+}
+
+// `fold` should visit every live pair once
+#[test]
+fn fold_value_lengths() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "a").unwrap();
+    store.set("key2", "bb").unwrap();
+    store.set("key3", "ccc").unwrap();
+    store.set("key3", "dddd").unwrap();
+    store.set("key4", "eeeee").unwrap();
+    store.remove("key4").unwrap();
+    let sum = |store: &KvStore| store.fold(0, |acc, _, v| acc + v.len()).unwrap();
+    assert_eq!(sum(&store), 7);
+    assert_eq!(store.fold(0, |acc, _, _| acc + 1).unwrap(), 3);
+
+    store.clear_cache().unwrap();
+    assert_eq!(sum(&store), 7);
+}
+
+// Streaming export should be importable into another database
+#[test]
+fn cli_export_import_jsonl() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::new(temp_dir.path().join("kvs.db")).unwrap();
+    for i in 0..2000 {
+        store
+            .set(&format!("key{}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    store.remove("key0").unwrap();
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--jsonl"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let exported = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(exported.lines().count(), 1999);
+    assert!(exported.contains("{\"key\":\"key1\",\"value\":\"value1\"}\n"));
+
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", "imported.db", "import", "--jsonl"])
+        .current_dir(&temp_dir)
+        .write_stdin(exported)
+        .assert()
+        .success();
+    let mut imported = KvStore::builder(temp_dir.path().join("imported.db"))
+        .preload_cache(false)
+        .build_with_cache(LruCache::with_capacity(100))
+        .unwrap();
+    assert!(imported.get("key0").is_err());
+    assert_eq!(imported.get("key1999").unwrap(), "value1999".to_owned());
+    assert_eq!(imported.stream_entries().count(), 1999);
+}
+
+// Snapshot export should be a single JSON object, importable back
+#[test]
+fn cli_export_import_snapshot() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::new(temp_dir.path().join("kvs.db")).unwrap();
+    store.set("key1", "value1").unwrap();
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"key1\":\"value1\"}\n");
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", "imported.db", "import"])
+        .current_dir(&temp_dir)
+        .write_stdin("{\"key2\":\"value2\"}")
+        .assert()
+        .success();
+    let mut imported = KvStore::new(temp_dir.path().join("imported.db")).unwrap();
+    assert_eq!(imported.get("key2").unwrap(), "value2".to_owned());
+}
+
+// Operations and compactions slower than threshold should be counted and reported
+#[test]
+fn slow_operations() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db)
+        .slow_threshold(Duration::from_secs(3600))
+        .build()
+        .unwrap();
+    store.set("key1", "value1").unwrap();
+    store.compact().unwrap();
+    assert_eq!(store.stats().slow_ops, 0);
+    drop(store);
+
+    let mut store = KvStore::builder(&db)
+        .slow_threshold(Duration::from_secs(0))
+        .build()
+        .unwrap();
+    store.compact().unwrap();
+    assert_eq!(store.stats().slow_ops, 1);
+    store.get("key1").unwrap();
+    store.remove("key1").unwrap();
+    assert_eq!(store.stats().slow_ops, 3);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--slow-threshold", "0", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("warning: slow set of key 'key1' took"));
+}
+
+// `Cache::remove` should drop a single entry without changing uncompacted space
+#[test]
+fn map_cache_remove() {
+    let mut cache = InMemoryMapCache::new().unwrap();
+    cache
+        .insert(Log::Set("key1".to_owned(), "value1".into()), 10)
+        .unwrap();
+    cache
+        .insert(Log::Set("key1".to_owned(), "value2".into()), 10)
+        .unwrap();
+    cache
+        .insert(Log::Set("key2".to_owned(), "value3".into()), 10)
+        .unwrap();
+    assert_eq!(cache.uncompacted_space(), 10);
+
+    cache.remove("key1").unwrap();
+    assert!(cache.get("key1").unwrap().is_none());
+    assert!(cache.get("key2").unwrap().is_some());
+    assert_eq!(cache.uncompacted_space(), 10);
+    assert_eq!(cache.live_size(), 10);
+    cache.remove("missing").unwrap();
+}
+
+// `Cache::clear` should drop all entries and reset uncompacted space
+#[test]
+fn map_cache_clear() {
+    let mut cache = InMemoryMapCache::new().unwrap();
+    cache
+        .insert(Log::Set("key1".to_owned(), "value1".into()), 10)
+        .unwrap();
+    cache.insert(Log::Remove("key1".to_owned()), 5).unwrap();
+    cache
+        .insert(Log::Set("key2".to_owned(), "value2".into()), 10)
+        .unwrap();
+    assert_eq!(cache.uncompacted_space(), 15);
+
+    cache.clear().unwrap();
+    assert!(cache.get_all().is_empty());
+    assert_eq!(cache.uncompacted_space(), 0);
+}
+
+// Lowercasing key transform should make keys case-insensitive
+#[test]
+fn key_transform_lowercase() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let open = || {
+        KvStore::builder(&db)
+            .key_transform(|key: &str| key.to_lowercase())
+            .build()
+            .unwrap()
+    };
+    let mut store = open();
+    store.set("KEY", "value1").unwrap();
+    assert_eq!(store.get("key").unwrap(), "value1".to_owned());
+    store.set("Key", "value2").unwrap();
+    assert_eq!(&store["kEY"], "value2");
+    assert!(!store.set_nx("key", "value3").unwrap());
+    drop(store);
+
+    // Transformed keys are stored on disk
+    let mut plain = KvStore::new(&db).unwrap();
+    assert_eq!(plain.get("key").unwrap(), "value2".to_owned());
+    assert!(plain.get("KEY").is_err());
+    drop(plain);
+
+    let mut store = open();
+    store.remove("KeY").unwrap();
+    assert!(store.get("key").is_err());
+}
+
+// Overwritten and removed records should be listed as dead
+#[test]
+fn iter_tombstones_lists_dead_records() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).auto_compact(false).build().unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.set("key1", "value3").unwrap();
+    store.remove("key2").unwrap();
+    store.flush().unwrap();
+
+    let dead: Vec<DeadRecord> = store.iter_tombstones().map(Result::unwrap).collect();
+    let summary: Vec<(Option<&str>, DeadReason)> =
+        dead.iter().map(|r| (r.key.as_deref(), r.reason)).collect();
+    assert_eq!(
+        summary,
+        vec![
+            (Some("key1"), DeadReason::Superseded),
+            (Some("key2"), DeadReason::Superseded),
+            (Some("key2"), DeadReason::Tombstone),
+        ]
+    );
+    let dead_size: usize = dead.iter().map(|r| r.size).sum();
+    let live_size = store.live_data_size() as u64;
+    assert_eq!(dead_size as u64, store.disk_usage().unwrap() - live_size);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", db.to_str().unwrap(), "inspect", "--dead"])
+        .assert()
+        .success()
+        .stdout(contains("superseded").and(contains("tombstone")));
+}
+
+// Records of legacy positional format and tagged format should be read from the same file
+#[test]
+fn read_legacy_and_tagged_records() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let content = concat!(
+        "{\"Set\":[\"key1\",\"value1\"]}\n",
+        "{\"op\":\"set\",\"key\":\"key2\",\"value\":\"value2\"}\n",
+        "{\"Remove\":\"key3\"}\n",
+        "{\"op\":\"set\",\"key\":\"key4\",\"value\":{\"Int\":4}}\n",
+        "{\"Set\":[\"key5\",\"value5\"]}\n",
+        "{\"op\":\"remove\",\"key\":\"key5\"}\n",
+    );
+    write(&db, content).unwrap();
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+    assert_eq!(store.get_value("key4").unwrap(), Value::Int(4));
+    assert!(store.get("key5").is_err());
+    store.set("key6", "value6").unwrap();
+    drop(store);
+
+    // New records are written in tagged format
+    let log = String::from_utf8(read(&db).unwrap()).unwrap();
+    let last = log.lines().last().unwrap();
+    assert!(last.starts_with("{\"op\":\"set\",\"key\":\"key6\",\"value\":\"value6\","));
+}
+
+// Failed write to storage shouldn't change the cache
+#[test]
+fn failed_write_keeps_cache_unchanged() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).blob_threshold(16).build().unwrap();
+    store.set("key1", "value1").unwrap();
+    let (live_keys, live_size) = (store.stats().live_keys, store.live_data_size());
+    // Blobs can't be written, when their directory is taken by a regular file
+    write(temp_dir.path().join("kvs.db.blobs"), "").unwrap();
+    let large = "v".repeat(64);
+    assert!(store.set("key1", &large).is_err());
+    assert!(store.set("key2", &large).is_err());
+    assert!(store.set_value("key1", Value::Str(large.clone())).is_err());
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert!(store.get("key2").is_err());
+    assert_eq!(store.stats().live_keys, live_keys);
+    assert_eq!(store.live_data_size(), live_size);
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert!(store.get("key2").is_err());
+}
+
+// Failed write of the storage itself shouldn't change the cache
+#[cfg(feature = "fault-injection")]
+#[test]
+fn failed_storage_write_keeps_cache_unchanged() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.set("key2", "value2").unwrap();
+    let stats = store.stats();
+    let size = metadata(&db).unwrap().len();
+
+    store.set_fail_writes(true);
+    assert!(store.set("key1", "other").is_err());
+    assert!(store.set("key3", "value3").is_err());
+    assert!(store.set_value("key1", Value::Int(1)).is_err());
+    assert!(store
+        .set_with_ttl("key1", "other", Duration::from_secs(60))
+        .is_err());
+    assert!(store.remove("key2").is_err());
+    assert!(store
+        .set_many_report(&[("key1", "a"), ("key3", "b")])
+        .is_err());
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+    assert!(store.get("key3").is_err());
+    let after = store.stats();
+    assert_eq!(after.live_keys, stats.live_keys);
+    assert_eq!(after.live_data_size, stats.live_data_size);
+    assert_eq!(after.uncompacted_space, stats.uncompacted_space);
+    assert_eq!(metadata(&db).unwrap().len(), size);
+
+    store.set_fail_writes(false);
+    store.set("key3", "value3").unwrap();
+    drop(store);
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+    assert_eq!(store.get("key3").unwrap(), "value3".to_owned());
+}
+
+// `drain_prefix` should remove only keys with the given prefix
+#[test]
+fn drain_prefix_removes_matching_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("session:1", "a").unwrap();
+    store.set("session:2", "b").unwrap();
+    store.set("user:1", "c").unwrap();
+    store.set("sessions", "d").unwrap();
+    store.remove("session:2").unwrap();
+    store.set("session:3", "e").unwrap();
+
+    assert_eq!(store.drain_prefix("session:").unwrap(), 2);
+    assert_eq!(store.drain_prefix("session:").unwrap(), 0);
+    assert!(store.get("session:1").is_err());
+    assert!(store.get("session:3").is_err());
+    assert_eq!(store.get("user:1").unwrap(), "c".to_owned());
+    assert_eq!(store.get("sessions").unwrap(), "d".to_owned());
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert!(store.get("session:1").is_err());
+    let mut keys: Vec<String> = store
+        .get_all_pairs()
+        .unwrap()
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect();
+    keys.sort();
+    assert_eq!(keys, vec!["sessions".to_owned(), "user:1".to_owned()]);
+}
+
+// `--compact-on-open` should compact stale records before running the command
+#[test]
+fn cli_compact_on_open() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).auto_compact(false).build().unwrap();
+    for i in 0..100 {
+        store.set("key1", &format!("value{}", i)).unwrap();
+    }
+    drop(store);
+    let size_before = metadata(&db).unwrap().len();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--compact-on-open", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value99\n");
+    assert!(metadata(&db).unwrap().len() < size_before / 10);
+}
+
+// Database moved aside by interrupted compaction should be restored on open
+#[test]
+fn recover_interrupted_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    drop(store);
+    // Crash after the old file was moved aside, but before the new one was in place
+    std::fs::rename(&db, temp_dir.path().join("kvs.db.kvsold")).unwrap();
+    write(temp_dir.path().join("kvs.db.kvsoverride"), "{\"op\":\"se").unwrap();
+
+    let mut store = KvStore::builder(&db).compact_on_open(true).build().unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert!(!temp_dir.path().join("kvs.db.kvsold").exists());
+}
+
+// Compaction should be triggered by actual size of storage, when counters miss some records
+#[test]
+fn compaction_accounts_unreplayed_records() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    // Corrupted tail isn't replayed, so cache doesn't know about its space
+    let content = format!(
+        "{{\"op\":\"set\",\"key\":\"key1\",\"value\":\"value1\"}}\n{}\n",
+        "x".repeat(2 * 1024 * 1024)
+    );
+    write(&db, content).unwrap();
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.stats().uncompacted_space, 0);
+    store.set("key2", "value2").unwrap();
+    assert_eq!(store.stats().compactions, 1);
+    assert!(metadata(&db).unwrap().len() < 1024);
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+}
+
+// Records after corrupted one aren't replayed, but automatic compaction shouldn't drop them
+#[test]
+fn compaction_keeps_records_after_corrupted_one() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut content = "{\"op\":\"set\",\"key\":\"key1\",\"value\":\"value1\"}\n".to_owned();
+    let corrupted = "{\"op\":\"set\",\"key\":\n";
+    content.push_str(corrupted);
+    for i in 0..20_000 {
+        content.push_str(&format!(
+            "{{\"op\":\"set\",\"key\":\"k{}\",\"value\":\"{}\"}}\n",
+            i,
+            "v".repeat(100)
+        ));
+    }
+    write(&db, &content).unwrap();
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.last_load_report().unwrap().records_skipped, 20_001);
+    store.set("c", "3").unwrap();
+    assert_eq!(store.stats().compactions, 0);
+    drop(store);
+    assert!(metadata(&db).unwrap().len() > content.len() as u64);
+
+    // Once corrupted record is removed, the rest is replayed again
+    let content = std::fs::read_to_string(&db)
+        .unwrap()
+        .replacen(corrupted, "", 1);
+    write(&db, content).unwrap();
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(store.get("k5").unwrap(), "v".repeat(100));
+    assert_eq!(store.get("c").unwrap(), "3".to_owned());
+}
+
+// Size of storage should be the actual length of the file, also written by someone else
+#[test]
+fn storage_size_is_file_length() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut storage = FileStorage::new(&db).unwrap();
+    storage
+        .write(&Log::Set(
+            "key1".to_owned(),
+            Value::Str("value1".to_owned()),
+        ))
+        .unwrap();
+    let mut file = std::fs::OpenOptions::new().append(true).open(&db).unwrap();
+    std::io::Write::write_all(&mut file, b"{\"op\":\"remove\",\"key\":\"key1\"}\n").unwrap();
+    assert_eq!(storage.size().unwrap() as u64, metadata(&db).unwrap().len());
+}
+
+// Automatic compaction should wait until at least half of the file is reclaimable
+#[test]
+fn compaction_waits_for_half_of_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("live", &"l".repeat(3 * 1024 * 1024)).unwrap();
+    let value = "v".repeat(1024 * 1024 + 512 * 1024);
+    store.set("key1", &value).unwrap();
+    store.set("key1", &value).unwrap();
+    // Reclaimable space is above 1 MiB, but only a quarter of the file
+    assert!(store.stats().uncompacted_space > 1024 * 1024);
+    assert_eq!(store.stats().compactions, 0);
+
+    for _ in 0..3 {
+        store.set("key1", &value).unwrap();
+    }
+    assert_eq!(store.stats().compactions, 1);
+    assert_eq!(store.get("live").unwrap().len(), 3 * 1024 * 1024);
+}
+
+// `get_with_metadata` should return size and offset of the latest record
+#[test]
+fn get_with_metadata_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).auto_compact(false).build().unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.set("key1", "value3").unwrap();
+    store.flush().unwrap();
+    assert!(store.get_with_metadata("key3").unwrap().is_none());
+
+    let check = |store: &mut KvStore| {
+        let content = read(&db).unwrap();
+        let (value, meta) = store.get_with_metadata("key1").unwrap().unwrap();
+        assert_eq!(value, "value3");
+        let record = format!(
+            "{{\"op\":\"set\",\"key\":\"key1\",\"value\":\"value3\",\"ts\":{}}}\n",
+            meta.written_at
+        );
+        assert_eq!(meta.size, record.len());
+        assert_eq!(
+            &content[meta.offset..meta.offset + meta.size],
+            record.as_bytes()
+        );
+    };
+    check(&mut store);
+    // Offsets are tracked through replay, compaction and reads without cache
+    drop(store);
+    let mut store = KvStore::new(&db).unwrap();
+    check(&mut store);
+    store.compact().unwrap();
+    check(&mut store);
+    let mut store = KvStore::builder(&db).preload_cache(false).build().unwrap();
+    check(&mut store);
+}
+
+// Replay should produce the same state with any read buffer size
+#[test]
+fn replay_with_read_buffer_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).auto_compact(false).build().unwrap();
+    for i in 0..1000 {
+        store
+            .set(&format!("key{}", i % 100), &format!("value{}", i))
+            .unwrap();
+    }
+    drop(store);
+
+    for capacity in [1, 64, 1024 * 1024] {
+        let mut store = KvStore::builder(&db)
+            .read_buffer_size(capacity)
+            .build()
+            .unwrap();
+        assert_eq!(store.last_load_report().unwrap().records_replayed, 1000);
+        assert_eq!(store.get("key42").unwrap(), "value942".to_owned());
+    }
+    let mut storage = FileStorage::new(&db).unwrap();
+    storage.set_read_buffer_size(1024 * 1024);
+    assert_eq!(storage.read_buffer_size(), 1024 * 1024);
+    assert_eq!(storage.count(), 1000);
+}
+
+// `kvs histogram` should count live keys per prefix
+#[test]
+fn cli_histogram() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    for i in 0..5 {
+        store.set(&format!("tenant1:{}", i), "value").unwrap();
+    }
+    for i in 0..3 {
+        store.set(&format!("tenant2:{}", i), "value").unwrap();
+    }
+    store
+        .set_with_ttl("tenant2:expired", "value", Duration::from_millis(1))
+        .unwrap();
+    store.set("tenant3:0", "value").unwrap();
+    store.set("tenant3:1", "value").unwrap();
+    store.remove("tenant3:1").unwrap();
+    store.set("plain", "value").unwrap();
+    drop(store);
+    sleep(Duration::from_millis(10));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["histogram", "--sep", ":"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("tenant1 5\ntenant2 3\nplain 1\ntenant3 1\n");
+}
+
+// Audit mode should keep full history of the key through compaction
+#[test]
+fn audit_history() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).audit(true).build().unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "other").unwrap();
+    store.set("key1", "value2").unwrap();
+    store.compact().unwrap();
+    store.remove("key1").unwrap();
+    store.compact().unwrap();
+    store.set("key1", "value3").unwrap();
+    store.clear().unwrap();
+    drop(store);
+
+    let store = KvStore::builder(&db).audit(true).build().unwrap();
+    let history = store.history("key1").unwrap();
+    let values: Vec<Option<&str>> = history.iter().map(|(_, v)| v.as_deref()).collect();
+    assert_eq!(
+        values,
+        vec![Some("value1"), Some("value2"), None, Some("value3"), None]
+    );
+    assert!(history.windows(2).all(|w| w[0].0 <= w[1].0));
+    assert_eq!(store.history("key2").unwrap().len(), 2);
+    assert!(store.history("key3").unwrap().is_empty());
+}
+
+// Written records should carry their write time, which survives compaction,
+// while legacy records without it still load
+#[test]
+fn record_timestamps() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    write(&db, "{\"Set\":[\"legacy\",\"value\"]}\n").unwrap();
+    let before = system_millis();
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    let after = system_millis();
+
+    let written_at = |store: &mut KvStore, key: &str| {
+        let (_, meta) = store.get_with_metadata(key).unwrap().unwrap();
+        meta.written_at
+    };
+    assert_eq!(store.get("legacy").unwrap(), "value".to_owned());
+    assert_eq!(written_at(&mut store, "legacy"), 0);
+    let ts = written_at(&mut store, "key1");
+    assert!(before <= ts && ts <= after);
+    let log = String::from_utf8(read(&db).unwrap()).unwrap();
+    assert!(log.contains(&format!(
+        "\"key\":\"key1\",\"value\":\"value1\",\"ts\":{}}}",
+        ts
+    )));
+
+    sleep(Duration::from_millis(10));
+    store.compact().unwrap();
+    drop(store);
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(written_at(&mut store, "key1"), ts);
+    assert_eq!(written_at(&mut store, "legacy"), 0);
+}
+
+// `compact_into` should write live records to a new database, leaving the store unchanged
+#[test]
+fn compact_into_backup() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let backup = temp_dir.path().join("backup.db");
+    let mut store = KvStore::builder(&db)
+        .auto_compact(false)
+        .blob_threshold(16)
+        .build()
+        .unwrap();
+    for i in 0..100 {
+        store.set("key1", &format!("value{}", i)).unwrap();
+    }
+    store.set("key2", "value2").unwrap();
+    store.set("key3", "value3").unwrap();
+    store.remove("key3").unwrap();
+    store.set("blob", &"b".repeat(100)).unwrap();
+    let size = metadata(&db).unwrap().len();
+
+    store.compact_into(&backup).unwrap();
+    assert_eq!(metadata(&db).unwrap().len(), size);
+    assert!(metadata(&backup).unwrap().len() < size);
+    assert!(store.compact_into(&backup).is_err());
+    store.set("key4", "value4").unwrap();
+    drop(store);
+
+    let mut copy = KvStore::new(&backup).unwrap();
+    assert_eq!(copy.stats().uncompacted_space, 0);
+    let mut pairs = copy.get_all_pairs().unwrap();
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![
+            ("blob".to_owned(), "b".repeat(100)),
+            ("key1".to_owned(), "value99".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert!(copy.get("key4").is_err());
+    assert_eq!(KvStore::new(&db).unwrap().get("key4").unwrap(), "value4");
+}
+
+// Empty files, files of tombstones only and of blank lines should open as empty stores
+#[test]
+fn open_empty_databases() {
+    let temp_dir = TempDir::new().unwrap();
+    let check = |name: &str, content: &str, uncompacted: usize| {
+        let db = temp_dir.path().join(name);
+        write(&db, content).unwrap();
+        let mut store = KvStore::new(&db).unwrap();
+        assert_eq!(store.len().unwrap(), 0, "{}", name);
+        assert!(store.is_empty().unwrap());
+        assert_eq!(store.stats().uncompacted_space, uncompacted, "{}", name);
+        assert_eq!(store.last_load_report().unwrap().records_skipped, 0);
+        store.set("key1", "value1").unwrap();
+        assert_eq!(store.len().unwrap(), 1);
+        drop(store);
+
+        let mut store = KvStore::builder(&db).preload_cache(false).build().unwrap();
+        assert_eq!(store.len().unwrap(), 1, "{}", name);
+        assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+        store.compact().unwrap();
+        let store = KvStore::new(&db).unwrap();
+        assert_eq!(store.len().unwrap(), 1, "{}", name);
+        assert_eq!(store.stats().uncompacted_space, 0, "{}", name);
+    };
+    check("empty.db", "", 0);
+    let tombstones = concat!(
+        "{\"op\":\"set\",\"key\":\"key1\",\"value\":\"value1\"}\n",
+        "{\"op\":\"set\",\"key\":\"key2\",\"value\":\"value2\"}\n",
+        "{\"op\":\"remove\",\"key\":\"key1\"}\n",
+        "{\"op\":\"remove\",\"key\":\"key2\"}\n",
+    );
+    check("tombstones.db", tombstones, tombstones.len());
+    check("newline.db", "\n", 0);
+}
+
+// `kvs tail -n N` should print only the last N records in order
+#[test]
+fn cli_tail() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    for i in 1..=5 {
+        store
+            .set(&format!("key{}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    store.remove("key1").unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["tail", "-n", "3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("set key4 value4\nset key5 value5\nremove key1\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["tail", "-n", "2", "--json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(concat!(
+            "{\"op\":\"set\",\"key\":\"key5\",\"value\":\"value5\"}\n",
+            "{\"op\":\"remove\",\"key\":\"key1\"}\n"
+        ));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["tail", "-n", "100"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("set key1 value1\nset key2 value2\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", "missing.db", "tail", "-n", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("'missing.db' doesn't exist"));
+    assert!(!temp_dir.path().join("missing.db").exists());
+}
+
+// Truncated storage should be empty and its iterator should yield nothing
+#[test]
+fn storage_truncate() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut storage = FileStorage::new(&db).unwrap();
+    storage
+        .write(&Log::Set("key1".to_owned(), "value1".into()))
+        .unwrap();
+    storage.write(&Log::Remove("key1".to_owned())).unwrap();
+    assert_eq!(storage.by_ref().count(), 2);
+
+    storage.truncate().unwrap();
+    assert_eq!(storage.size().unwrap(), 0);
+    assert_eq!(metadata(&db).unwrap().len(), 0);
+    assert_eq!(storage.by_ref().count(), 0);
+    storage.write(&Log::Clear).unwrap();
+    assert!(matches!(storage.next(), Some(Ok((Log::Clear, _)))));
+    assert!(storage.next().is_none());
+}
+
+// `clear` should truncate storage and drop blob files
+#[test]
+fn clear_truncates_storage() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).blob_threshold(16).build().unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("blob", &"b".repeat(100)).unwrap();
+    store.clear().unwrap();
+    assert_eq!(metadata(&db).unwrap().len(), 0);
+    assert_eq!(store.stats().uncompacted_space, 0);
+    assert!(store.is_empty().unwrap());
+    assert_eq!(
+        std::fs::read_dir(temp_dir.path().join("kvs.db.blobs"))
+            .unwrap()
+            .count(),
+        0
+    );
+    store.set("key2", "value2").unwrap();
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert!(store.get("key1").is_err());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+}
+
+// Identical values should be stored once with blob deduplication and removed
+// by compaction only when no key refers to them
+#[test]
+fn dedup_blobs_shared_copy() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let blobs = temp_dir.path().join("kvs.db.blobs");
+    let blobs_size = || -> u64 {
+        std::fs::read_dir(&blobs)
+            .unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    let value = "v".repeat(10_000);
+    let mut store = KvStore::builder(&db)
+        .blob_threshold(100)
+        .dedup_blobs(true)
+        .build()
+        .unwrap();
+    store.set("key1", &value).unwrap();
+    store.set("key2", &value).unwrap();
+    assert_eq!(blobs_size(), value.len() as u64);
+
+    store.set("key1", "small").unwrap();
+    store.compact().unwrap();
+    assert_eq!(blobs_size(), value.len() as u64);
+    drop(store);
+
+    let mut store = KvStore::builder(&db)
+        .blob_threshold(100)
+        .dedup_blobs(true)
+        .build()
+        .unwrap();
+    assert_eq!(store.get("key2").unwrap(), value);
+    store.remove("key2").unwrap();
+    store.compact().unwrap();
+    assert_eq!(blobs_size(), 0);
+}
+
+// `remove_if` should remove the key only if its value matches the expected one
+#[test]
+fn remove_if_matching_value() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    let size = metadata(&db).unwrap().len();
+
+    assert!(!store.remove_if("key2", "other").unwrap());
+    assert!(!store.remove_if("key3", "value3").unwrap());
+    assert_eq!(metadata(&db).unwrap().len(), size);
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+
+    assert!(store.remove_if("key1", "value1").unwrap());
+    assert!(store.get("key1").is_err());
+    assert!(!store.remove_if("key1", "value1").unwrap());
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert!(store.get("key1").is_err());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+}
+
+// `remove_if` should run automatic compaction and be counted as operation, the same as `remove`
+#[test]
+fn remove_if_compacts_and_observes() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db)
+        .slow_threshold(Duration::from_secs(0))
+        .build()
+        .unwrap();
+    store.set("key1", "value1").unwrap();
+    let slow_ops = store.stats().slow_ops;
+    assert!(store.remove_if("key1", "value1").unwrap());
+    assert_eq!(store.stats().slow_ops, slow_ops + 1);
+
+    let value = "v".repeat(2 * 1024 * 1024);
+    store.set("key2", &value).unwrap();
+    assert!(store.remove_if("key2", &value).unwrap());
+    assert_eq!(store.stats().compactions, 1);
+    assert!(metadata(&db).unwrap().len() < 1024);
+}
+
+// TTL should be driven by the clock of the store, so keys expire without sleeping
+#[test]
+fn mock_clock_expiration() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let clock = MockClock::new(1_000_000);
+    let mut store = KvStore::builder(&db).clock(clock.clone()).build().unwrap();
+    store
+        .set_with_ttl("key1", "value1", Duration::from_secs(60))
+        .unwrap();
+    store.set("key2", "value2").unwrap();
+    let (_, meta) = store.get_with_metadata("key2").unwrap().unwrap();
+    assert_eq!(meta.written_at, 1_000_000);
+
+    clock.advance(Duration::from_secs(59));
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    clock.advance(Duration::from_secs(1));
+    assert!(store.get("key1").is_err());
+    assert_eq!(store.len().unwrap(), 1);
+    drop(store);
+
+    clock.set(1_000_000);
+    let mut store = KvStore::builder(&db).clock(clock).build().unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+}
+
+// `kvs completions bash` should write completion script with subcommands
+#[test]
+fn cli_completions() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["completions", "bash"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("_kvs()")
+                .and(contains("get"))
+                .and(contains("set"))
+                .and(contains("rm"))
+                .and(contains("histogram")),
+        );
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["completions", "powershell"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `get_many_as` should deserialize present keys, skip absent and fail on invalid ones
+#[test]
+fn typed_get_many() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    for (name, age) in [("alice", 30), ("bob", 25), ("carol", 41)] {
+        let user = User {
+            name: name.to_owned(),
+            age,
+        };
+        store.set_as(&format!("user:{}", name), &user).unwrap();
+    }
+    store.set("user:broken", "not a user").unwrap();
+
+    let users: HashMap<String, User> = store
+        .get_many_as(&["user:alice", "user:carol", "user:dave"])
+        .unwrap();
+    assert_eq!(users.len(), 2);
+    assert_eq!(users["user:alice"].age, 30);
+    assert_eq!(users["user:carol"].name, "carol");
+    match store.get_many_as::<User>(&["user:bob", "user:broken"]) {
+        Err(KvsError::Serde(_)) => {}
+        other => panic!("unexpected result {:?}", other.map(|m| m.len())),
+    }
+}
+
+// New files should start with format header, while files of unknown format or version
+// shouldn't be opened and legacy files without header should be upgraded by compaction
+#[test]
+fn log_format_header() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    drop(store);
+    let log = String::from_utf8(read(&db).unwrap()).unwrap();
+    assert!(log.starts_with("kvs-log 1\n{\"op\":\"set\",\"key\":\"key1\""));
+    let mut store = KvStore::builder(&db).preload_cache(false).build().unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(store.keys().count(), 1);
+    let report = FileStorage::verify(&db).unwrap();
+    assert_eq!((report.good_records, report.bad_records), (1, 0));
+
+    let other = temp_dir.path().join("other.db");
+    write(&other, "not a database\n").unwrap();
+    assert!(matches!(KvStore::new(&other), Err(KvsError::UnknownFormat)));
+    write(&other, "kvs-log 2\n").unwrap();
+    assert!(matches!(
+        KvStore::new(&other),
+        Err(KvsError::VersionMismatch {
+            expected: 1,
+            found: 2
+        })
+    ));
+
+    let legacy = temp_dir.path().join("legacy.db");
+    let content =
+        "{\"Set\":[\"key1\",\"value1\"]}\n{\"op\":\"set\",\"key\":\"key2\",\"value\":\"value2\"}\n";
+    write(&legacy, content).unwrap();
+    let mut store = KvStore::new(&legacy).unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    store.set("key3", "value3").unwrap();
+    assert!(!String::from_utf8(read(&legacy).unwrap())
+        .unwrap()
+        .starts_with("kvs-log"));
+    store.compact().unwrap();
+    drop(store);
+    assert!(String::from_utf8(read(&legacy).unwrap())
+        .unwrap()
+        .starts_with("kvs-log 1\n"));
+    let mut store = KvStore::new(&legacy).unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+    assert_eq!(store.get("key3").unwrap(), "value3".to_owned());
+}
+
+// `kvs migrate` should rewrite legacy database in the current format once, keeping data
+#[test]
+fn cli_migrate_legacy() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let content = concat!(
+        "{\"Set\":[\"key1\",\"value1\"]}\n",
+        "{\"Set\":[\"key2\",\"value2\"]}\n",
+        "{\"SetEx\":[\"key3\",\"value3\",18446744073709551615]}\n",
+        "{\"Remove\":\"key2\"}\n",
+        "{\"op\":\"set\",\"key\":\"key4\",\"value\":{\"Int\":4}}\n",
+    );
+    write(&db, content).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("migrated to the current format\n");
+    let migrated = read(&db).unwrap();
+    let log = String::from_utf8(migrated.clone()).unwrap();
+    assert!(log.starts_with("kvs-log 1\n"));
+    assert_eq!(log.lines().count(), 4);
+    assert!(log.lines().skip(1).all(|l| l.starts_with("{\"op\":")));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("already in the current format\n");
+    assert_eq!(read(&db).unwrap(), migrated);
+
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert!(store.get("key2").is_err());
+    assert_eq!(store.get("key3").unwrap(), "value3".to_owned());
+    assert_eq!(store.get_value("key4").unwrap(), Value::Int(4));
+}
+
+// `get_status` should tell live, removed and never set keys apart
+#[test]
+fn key_status() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let clock = MockClock::new(system_millis());
+    let mut store = KvStore::builder(&db)
+        .audit(true)
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.remove("key2").unwrap();
+    store
+        .set_with_ttl("key3", "value3", Duration::from_secs(1))
+        .unwrap();
+    clock.advance(Duration::from_secs(2));
+
+    let check = |store: &mut KvStore| {
+        assert_eq!(
+            store.get_status("key1").unwrap(),
+            KeyStatus::Present("value1".to_owned())
+        );
+        assert_eq!(store.get_status("key2").unwrap(), KeyStatus::Deleted);
+        assert_eq!(store.get_status("key3").unwrap(), KeyStatus::Deleted);
+        assert_eq!(store.get_status("key4").unwrap(), KeyStatus::Absent);
+    };
+    check(&mut store);
+    store.clear_cache().unwrap();
+    check(&mut store);
+
+    store.clear().unwrap();
+    assert_eq!(store.get_status("key1").unwrap(), KeyStatus::Deleted);
+    assert_eq!(store.get_status("key4").unwrap(), KeyStatus::Absent);
+}
+
+// Compaction in steps interleaved with writes should end with the same data as full compaction
+#[test]
+fn compact_in_steps() {
+    let temp_dir = TempDir::new().unwrap();
+    let full_db = temp_dir.path().join("full.db");
+    let db = temp_dir.path().join("kvs.db");
+    let mut full = KvStore::new(&full_db).unwrap();
+    let mut store = KvStore::new(&db).unwrap();
+    for store in [&mut full, &mut store] {
+        for i in 0..20 {
+            store
+                .set(&format!("key{}", i % 5), &format!("value{}", i))
+                .unwrap();
+        }
+        store.remove("key4").unwrap();
+    }
+    let size_before = std::fs::metadata(&db).unwrap().len();
+
+    full.compact().unwrap();
+    let mut steps = 0;
+    while !store.compact_step(3).unwrap() {
+        full.set(&format!("new{}", steps), "value").unwrap();
+        store.set(&format!("new{}", steps), "value").unwrap();
+        if steps == 1 {
+            full.set("key0", "changed").unwrap();
+            store.set("key0", "changed").unwrap();
+            full.remove("key1").unwrap();
+            store.remove("key1").unwrap();
+        }
+        steps += 1;
+    }
+    assert!(steps > 1);
+    assert_eq!(store.stats().compactions, 1);
+    assert!(std::fs::metadata(&db).unwrap().len() < size_before);
+
+    let mut expected = full.get_all_pairs().unwrap();
+    expected.sort();
+    let mut pairs = store.get_all_pairs().unwrap();
+    pairs.sort();
+    assert_eq!(pairs, expected);
+    assert_eq!(store.get("key0").unwrap(), "changed".to_owned());
+
+    drop(store);
+    let store = KvStore::new(&db).unwrap();
+    let mut pairs = store.get_all_pairs().unwrap();
+    pairs.sort();
+    assert_eq!(pairs, expected);
+}
+
+// Temporary store should work like a regular one and remove its files on drop
+#[test]
+fn temp_store() {
+    let mut store = KvStore::temp().unwrap();
+    store.set("key1", "value1").unwrap();
+    store.flush().unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    let db = store.path().to_owned();
+    assert!(db.exists());
+
+    let mut other = KvStore::default();
+    assert_ne!(other.path(), db.as_path());
+    assert!(other.get("key1").is_err());
+
+    drop(store);
+    assert!(!db.exists());
+    assert!(!db.parent().unwrap().exists());
+}
+
+// TSV loader should split lines on the first tab and report line without a tab
+#[test]
+fn load_tsv() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    let input = "key1\tvalue1\nkey2\tvalue\twith\ttabs\r\nkey3\t\nkey1\tvalue4";
+    assert_eq!(store.load_tsv(input.as_bytes()).unwrap(), 4);
+    assert_eq!(store.get("key1").unwrap(), "value4".to_owned());
+    assert_eq!(store.get("key2").unwrap(), "value\twith\ttabs".to_owned());
+    assert_eq!(store.get("key3").unwrap(), "".to_owned());
+
+    let input = "key5\tvalue5\nbroken\nkey6\tvalue6\n";
+    match store.load_tsv(input.as_bytes()) {
+        Err(KvsError::MalformedInput { line }) => assert_eq!(line, 2),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+    assert_eq!(store.get("key5").unwrap(), "value5".to_owned());
+    assert!(store.get("key6").is_err());
+
+    drop(store);
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.get("key2").unwrap(), "value\twith\ttabs".to_owned());
+    assert_eq!(store.get("key5").unwrap(), "value5".to_owned());
+}
+
+// Swap should exchange values of present keys, including typed and out-of-line ones
+#[test]
+fn swap_present_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).blob_threshold(16).build().unwrap();
+    let large = "x".repeat(64);
+    store.set("key1", "value1").unwrap();
+    store.set_value("key2", Value::Int(2)).unwrap();
+    store.set("key3", &large).unwrap();
+
+    store.swap("key1", "key2").unwrap();
+    store.swap("key1", "key3").unwrap();
+    assert_eq!(store.get("key1").unwrap(), large);
+    assert_eq!(store.get("key2").unwrap(), "value1".to_owned());
+    assert_eq!(store.get_value("key3").unwrap(), Value::Int(2));
+
+    drop(store);
+    let mut store = KvStore::builder(&db).blob_threshold(16).build().unwrap();
+    assert_eq!(store.get("key1").unwrap(), large);
+    assert_eq!(store.get("key2").unwrap(), "value1".to_owned());
+    assert_eq!(store.get_value("key3").unwrap(), Value::Int(2));
+    store.compact().unwrap();
+    assert_eq!(store.get("key1").unwrap(), large);
+}
+
+// Swap with absent key should fail and leave the present key unchanged
+#[test]
+fn swap_absent_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.remove("key2").unwrap();
+    let size = std::fs::metadata(&db).unwrap().len();
+
+    assert!(matches!(
+        store.swap("key1", "key2"),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.swap("key3", "key1"),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert!(store.get("key2").is_err());
+    assert_eq!(std::fs::metadata(&db).unwrap().len(), size);
+}
+
+// Read-repair should correct cached records, which differ from externally edited file
+#[test]
+fn read_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).read_repair(true).build().unwrap();
+    let mut plain = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.flush().unwrap();
+    plain.reopen().unwrap();
+    assert_eq!(plain.get("key1").unwrap(), "value1".to_owned());
+
+    let contents = std::fs::read_to_string(&db).unwrap();
+    std::fs::write(&db, contents.replace("value1", "edited")).unwrap();
+    let mut other = KvStore::new(&db).unwrap();
+    other.remove("key2").unwrap();
+    drop(other);
+
+    // Store without read-repair keeps serving cached values
+    assert_eq!(plain.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(store.get("key1").unwrap(), "edited".to_owned());
+    assert!(store.get("key2").is_err());
+    assert_eq!(store.stats().read_repairs, 2);
+    assert_eq!(store.get("key1").unwrap(), "edited".to_owned());
+    assert_eq!(store.stats().read_repairs, 2);
+
+    store.reopen().unwrap();
+    assert_eq!(store.get("key1").unwrap(), "edited".to_owned());
+    assert!(store.get("key2").is_err());
+    assert_eq!(store.stats().read_repairs, 2);
+    assert_eq!(plain.stats().read_repairs, 0);
+}
+
+// Length-prefixed records should be read back intact, even if they contain line terminators
+#[test]
+fn length_prefixed_records() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let value = "line1\nline2\r\n\nline3\n";
+    let mut store = KvStore::builder(&db).length_prefixed(true).build().unwrap();
+    store.set("key1", value).unwrap();
+    store.set_value("key2", Value::Int(2)).unwrap();
+    store.set("key3", "value3").unwrap();
+    store.remove("key3").unwrap();
+    drop(store);
+    let contents = std::fs::read_to_string(&db).unwrap();
+    assert!(contents.lines().skip(1).all(|line| line.contains(":{")));
+
+    // Framing doesn't depend on the absence of raw line terminators in the payload
+    let payload = "{\"op\":\"set\",\n\"key\":\"key4\",\r\n\"value\":\"value4\"}";
+    let record = format!("{}:{}\n", payload.len(), payload);
+    std::fs::write(&db, contents + &record).unwrap();
+    assert_eq!(FileStorage::verify(&db).unwrap().good_records, 5);
+
+    // Framing of existing records is detected regardless of the setting
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.get("key1").unwrap(), value.to_owned());
+    assert_eq!(store.get_value("key2").unwrap(), Value::Int(2));
+    assert!(store.get("key3").is_err());
+    assert_eq!(store.get("key4").unwrap(), "value4".to_owned());
+    store.set("key5", "value5").unwrap();
+    drop(store);
+
+    let mut store = KvStore::builder(&db).length_prefixed(true).build().unwrap();
+    store.compact().unwrap();
+    store.clear_cache().unwrap();
+    assert_eq!(store.get("key1").unwrap(), value.to_owned());
+    assert_eq!(store.get("key4").unwrap(), "value4".to_owned());
+    assert_eq!(store.get("key5").unwrap(), "value5".to_owned());
+    assert_eq!(store.len().unwrap(), 4);
+}
+
+// Memory estimate should grow with the size of cached keys and values
+#[test]
+fn estimate_memory() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    assert_eq!(store.estimate_memory(), 0);
+
+    // 100 keys of 8 bytes and values of 1000 bytes
+    for i in 0..100 {
+        store
+            .set(&format!("key{:05}", i), &"x".repeat(1000))
+            .unwrap();
+    }
+    let contents = 100 * (8 + 1000);
+    let estimate = store.estimate_memory();
+    assert!(estimate > contents);
+    assert!(estimate < contents * 2);
+    assert_eq!(store.stats().estimated_memory, estimate);
+
+    store.remove("key00000").unwrap();
+    assert!(store.estimate_memory() < estimate);
+    store.clear_cache().unwrap();
+    assert_eq!(store.estimate_memory(), 0);
+}
+
+// Shell script should run commands in order and stop at the first failed one
+#[test]
+fn shell_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let script = temp_dir.path().join("setup.kvs");
+    std::fs::write(
+        &script,
+        "# setup\nset key1 value1\n\nget key1\nset key2\nset key3 value3\nget key3\n",
+    )
+    .unwrap();
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["shell", "--script", script.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("value1\n")
+        .stderr(contains("line 5: error:"));
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .stdout("Key not found\n");
+
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "shell",
+            "--script",
+            script.to_str().unwrap(),
+            "--keep-going",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("value1\nvalue3\n");
+
+    std::fs::write(&script, "set key4 value4\nget key4\nexit\nget key1\n").unwrap();
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["shell", "--script", script.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value4\n");
+}
+
+// Validator should reject values before anything is written
+#[test]
+fn value_validator() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db)
+        .set_value_validator(|_, value| match value.parse::<f64>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("'{}' isn't a number", value)),
+        })
+        .build()
+        .unwrap();
+    store.set("key1", "42").unwrap();
+    let size = std::fs::metadata(&db).unwrap().len();
+
+    match store.set("key1", "forty two") {
+        Err(KvsError::ValidationFailed(msg)) => assert_eq!(msg, "'forty two' isn't a number"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(matches!(
+        store.set_with_ttl("key2", "abc", Duration::from_secs(60)),
+        Err(KvsError::ValidationFailed(_))
+    ));
+    assert_eq!(std::fs::metadata(&db).unwrap().len(), size);
+    assert!(matches!(
+        store.load_tsv("key3\t1.5\nkey4\tabc\n".as_bytes()),
+        Err(KvsError::ValidationFailed(_))
+    ));
+    assert_eq!(store.get("key1").unwrap(), "42".to_owned());
+    assert!(store.get("key2").is_err());
+    // Lines before the rejected one are loaded
+    assert_eq!(store.get("key3").unwrap(), "1.5".to_owned());
+    assert!(store.get("key4").is_err());
+    assert!(std::fs::metadata(&db).unwrap().len() > size);
+}
+
+// Glob matching of keys should support `*`, `?` and literal patterns
+#[test]
+fn keys_matching() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    for key in [
+        "user:1:profile",
+        "user:22:profile",
+        "user:3:settings",
+        "user:4:profile",
+        "admin",
+    ] {
+        store.set(key, "value").unwrap();
+    }
+    store.remove("user:4:profile").unwrap();
+
+    let check = |store: &KvStore| {
+        assert_eq!(
+            store.keys_matching("user:*:profile").unwrap(),
+            vec!["user:1:profile", "user:22:profile"]
+        );
+        assert_eq!(
+            store.keys_matching("user:?:*").unwrap(),
+            vec!["user:1:profile", "user:3:settings"]
+        );
+        assert_eq!(store.keys_matching("admin").unwrap(), vec!["admin"]);
+        assert_eq!(store.keys_matching("adm").unwrap(), Vec::<String>::new());
+        assert_eq!(store.keys_matching("*").unwrap().len(), 4);
+        assert_eq!(store.keys_matching("**n").unwrap(), vec!["admin"]);
+    };
+    check(&store);
+    store.clear_cache().unwrap();
+    check(&store);
+
+    drop(store);
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", db.to_str().unwrap(), "list", "--match", "user:*"])
+        .assert()
+        .success()
+        .stdout("user:1:profile\nuser:22:profile\nuser:3:settings\n");
+}
+
+// New store should be created only if database has no data yet
+#[test]
+fn create_new() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::create_new(&db).unwrap();
+    drop(store);
+    // Empty file left by previous run is fine
+    store = KvStore::create_new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    drop(store);
+
+    match KvStore::create_new(&db) {
+        Err(KvsError::AlreadyExists { path }) => assert_eq!(path, db.display().to_string()),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+    assert_eq!(KvStore::new(&db).unwrap().get("key1").unwrap(), "value1");
+
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", "new.db", "--force-new", "set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", "new.db", "--force-new", "set", "key3", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already exists"));
+}
+
+// Batch should write valid entries and report oversized ones by their index
+#[test]
+fn set_many_report() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let large = "x".repeat(300);
+    let mut store = KvStore::builder(&db).max_record_size(200).build().unwrap();
+    let report = store
+        .set_many_report(&[
+            ("key1", "value1"),
+            ("key2", &large),
+            ("key3", "value3"),
+            ("key4", &large),
+        ])
+        .unwrap();
+    assert_eq!(report.succeeded, 2);
+    let failed: Vec<usize> = report.failed.iter().map(|(i, _)| *i).collect();
+    assert_eq!(failed, vec![1, 3]);
+    assert!(report
+        .failed
+        .iter()
+        .all(|(_, err)| matches!(err, KvsError::ValueTooLarge { limit: 200, .. })));
+
+    drop(store);
+    let mut store = KvStore::builder(&db).max_record_size(200).build().unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1".to_owned());
+    assert_eq!(store.get("key3").unwrap(), "value3".to_owned());
+    assert!(store.get("key2").is_err());
+    assert!(store.get("key4").is_err());
+}
+
+// `use` in the shell should switch reads and writes to another database
+#[test]
+fn shell_use() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::create_dir(temp_dir.path().join("dir.db")).unwrap();
+    let output = assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("shell")
+        .current_dir(&temp_dir)
+        .write_stdin(
+            "set key1 value1\nuse other.db\nget key1\nset key2 value2\nuse dir.db\nget key2\nexit\n",
+        )
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("Using other.db\n"));
+    assert!(output.contains("Key not found\n"));
+    assert!(output.contains("is a directory"));
+    assert!(output.contains("value2\n"));
+
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .stdout("value1\n");
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", "other.db", "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .stdout("value2\n");
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .stdout("Key not found\n");
+}
+
+// Compaction should return uncompacted space to zero, whichever way it runs
+#[test]
+fn compaction_resets_uncompacted_space() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).auto_compact(false).build().unwrap();
+    for i in 0..10 {
+        store.set("key1", &format!("value{}", i)).unwrap();
+        store.set(&format!("key{}", i), "value").unwrap();
+    }
+    store.remove("key2").unwrap();
+    assert!(store.stats().uncompacted_space > 0);
+    store.compact().unwrap();
+    assert_eq!(store.stats().uncompacted_space, 0);
+
+    store.set("key1", "value").unwrap();
+    store.set("key1", "value").unwrap();
+    assert!(store.stats().uncompacted_space > 0);
+    while !store.compact_step(2).unwrap() {}
+    assert_eq!(store.stats().uncompacted_space, 0);
+
+    let mut cache = InMemoryMapCache::new().unwrap();
+    cache
+        .insert(Log::Set("key1".to_owned(), "value1".into()), 10)
+        .unwrap();
+    cache
+        .insert(Log::Set("key1".to_owned(), "value2".into()), 10)
+        .unwrap();
+    assert_eq!(cache.uncompacted_space(), 10);
+    cache.reset_uncompacted();
+    assert_eq!(cache.uncompacted_space(), 0);
+    assert!(cache.get("key1").unwrap().is_some());
+}
+
+// Default value should be returned for absent key, but not stored
+#[test]
+fn get_or_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    store.remove("key2").unwrap();
+    let size = std::fs::metadata(&db).unwrap().len();
+
+    assert_eq!(store.get_or_default("key1", "default").unwrap(), "value1");
+    assert_eq!(store.get_or_default("key2", "default").unwrap(), "default");
+    assert_eq!(store.get_or_default("key3", "default").unwrap(), "default");
+    assert!(store.get("key3").is_err());
+    assert_eq!(std::fs::metadata(&db).unwrap().len(), size);
+
+    drop(store);
+    let mut store = KvStore::new(&db).unwrap();
+    assert!(store.get("key2").is_err());
+    assert!(store.get("key3").is_err());
+}
+
+// Shell should compact database in background once the interval elapses
+#[test]
+fn shell_compact_interval() {
+    use std::io::{Read, Write};
+    use std::process::Stdio;
+
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut child = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["shell", "--compact-interval", "1s"])
+        .current_dir(&temp_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let commands: String = (0..50).map(|i| format!("set key1 value{}\n", i)).collect();
+    stdin.write_all(commands.as_bytes()).unwrap();
+    // Prompt after the last command means all of them have been completed
+    let mut output = String::new();
+    let mut buff = [0; 1024];
+    while output.matches(">>> ").count() < 51 {
+        let n = stdout.read(&mut buff).unwrap();
+        assert_ne!(n, 0);
+        output.push_str(&String::from_utf8_lossy(&buff[..n]));
+    }
+    let size = std::fs::metadata(&db).unwrap().len();
+
+    let started = Instant::now();
+    while std::fs::metadata(&db).unwrap().len() >= size {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    stdin.write_all(b"get key1\nexit\n").unwrap();
+    assert!(child.wait().unwrap().success());
+    stdout.read_to_string(&mut output).unwrap();
+    assert!(output.contains("value49\n"));
+}
+
+// Reads of uncached keys through key index should see the latest records
+#[test]
+fn key_index() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let open = || {
+        KvStore::builder(&db)
+            .preload_cache(false)
+            .key_index(true)
+            .build_with_cache(LruCache::with_capacity(2))
+            .unwrap()
+    };
+    let mut store = open();
+    for i in 0..20 {
+        store
+            .set(&format!("key{}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    store.set("key1", "changed").unwrap();
+    store.remove("key2").unwrap();
+    store.set_value("key3", Value::Int(3)).unwrap();
+
+    let check = |store: &mut KvStore<LruCache>| {
+        assert_eq!(store.get("key0").unwrap(), "value0".to_owned());
+        assert_eq!(store.get("key1").unwrap(), "changed".to_owned());
+        assert!(store.get("key2").is_err());
+        assert_eq!(store.get_value("key3").unwrap(), Value::Int(3));
+        assert_eq!(store.get("key19").unwrap(), "value19".to_owned());
+        assert!(store.get("missing").is_err());
+    };
+    check(&mut store);
+    drop(store);
+    let mut store = open();
+    check(&mut store);
+    store.compact().unwrap();
+    check(&mut store);
+    store.set("key0", "after compaction").unwrap();
+    store.clear_cache().unwrap();
+    assert_eq!(store.get("key0").unwrap(), "after compaction".to_owned());
+
+    store.clear().unwrap();
+    assert!(store.get("key0").is_err());
+    store.set("key0", "value").unwrap();
+    store.clear_cache().unwrap();
+    assert_eq!(store.get("key0").unwrap(), "value".to_owned());
+}
+
+// Torn record at the end of the log should be dropped and truncated
+#[test]
+fn torn_tail() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    drop(store);
+    let complete = std::fs::read_to_string(&db).unwrap();
+    write(
+        &db,
+        format!("{}{{\"op\":\"set\",\"key\":\"key3\",\"val", complete),
+    )
+    .unwrap();
+
+    let mut store = KvStore::new(&db).unwrap();
+    let report = store.last_load_report().unwrap();
+    assert!(report.torn_tail);
+    assert_eq!(report.records_replayed, 2);
+    assert_eq!(report.records_skipped, 1);
+    assert_eq!(store.get("key2").unwrap(), "value2".to_owned());
+    assert!(store.get("key3").is_err());
+    assert_eq!(std::fs::read_to_string(&db).unwrap(), complete);
+    drop(store);
+
+    // Corrupted record with line terminator isn't torn, so it's kept
+    let content = std::fs::read_to_string(&db).unwrap();
+    write(&db, format!("{}garbage\n", content)).unwrap();
+    let store = KvStore::new(&db).unwrap();
+    assert!(!store.last_load_report().unwrap().torn_tail);
+    assert_eq!(store.last_load_report().unwrap().records_skipped, 1);
+    drop(store);
+    assert!(std::fs::read_to_string(&db).unwrap().ends_with("garbage\n"));
+}
+
+// Write after torn record should survive reopen
+#[test]
+fn write_after_torn_tail() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("a", "1").unwrap();
+    drop(store);
+    let content = std::fs::read_to_string(&db).unwrap();
+    write(&db, format!("{}{{\"op\":\"set\",\"key\":\"x\"", content)).unwrap();
+
+    let mut store = KvStore::new(&db).unwrap();
+    store.set("b", "2").unwrap();
+    assert_eq!(store.get("b").unwrap(), "2".to_owned());
+    drop(store);
+
+    let mut store = KvStore::new(&db).unwrap();
+    let report = store.last_load_report().unwrap();
+    assert!(!report.torn_tail);
+    assert_eq!(report.records_skipped, 0);
+    assert_eq!(store.get("a").unwrap(), "1".to_owned());
+    assert_eq!(store.get("b").unwrap(), "2".to_owned());
+    assert!(store.get("x").is_err());
+}
+
+// Capacity estimated from file size shouldn't change what's loaded
+#[test]
+fn estimated_cache_capacity() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    for i in 0..1000 {
+        store
+            .set(&format!("key{}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    store.remove("key0").unwrap();
+    drop(store);
+
+    let mut estimated = KvStore::new(&db).unwrap();
+    let mut unhinted = KvStore::builder(&db).cache_capacity(0).build().unwrap();
+    assert_eq!(estimated.len().unwrap(), 999);
+    let mut pairs = estimated.get_all_pairs().unwrap();
+    pairs.sort();
+    let mut expected = unhinted.get_all_pairs().unwrap();
+    expected.sort();
+    assert_eq!(pairs, expected);
+    assert_eq!(estimated.get("key999").unwrap(), "value999".to_owned());
+    assert!(unhinted.get("key0").is_err());
+}
+
+// Diff should report keys only in one of the databases and keys with different values
+#[test]
+fn cli_diff() {
+    let temp_dir = TempDir::new().unwrap();
+    let first = temp_dir.path().join("first.db");
+    let second = temp_dir.path().join("second.db");
+    let mut store = KvStore::new(&first).unwrap();
+    store.set("same", "1").unwrap();
+    store.set("changed", "old").unwrap();
+    store.set("gone", "1").unwrap();
+    let mut other = KvStore::new(&second).unwrap();
+    other.set("same", "1").unwrap();
+    other.set("changed", "new").unwrap();
+    other.set("added", "1").unwrap();
+    drop(store);
+    drop(other);
+
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["diff", "first.db", "second.db"])
+        .assert()
+        .code(1)
+        .stdout("- gone\n+ added\n~ changed\n1 only in first.db, 1 only in second.db, 1 changed\n");
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["diff", "--json", "first.db", "second.db"])
+        .assert()
+        .code(1)
+        .stdout(concat!(
+            r#"{"only_first":["gone"],"only_second":["added"],"#,
+            r#""changed":[{"key":"changed","first":"old","second":"new"}]}"#,
+            "\n"
+        ));
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["diff", "first.db", "first.db"])
+        .assert()
+        .success()
+        .stdout("0 only in first.db, 0 only in first.db, 0 changed\n");
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["diff", "first.db", "missing.db"])
+        .assert()
+        .failure()
+        .stderr(contains("'missing.db' doesn't exist"));
+    assert!(!temp_dir.path().join("missing.db").exists());
+}
+
+// Parallel compaction should write the same records as the sequential one
+#[cfg(feature = "parallel-compaction")]
+#[test]
+fn parallel_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).auto_compact(false).build().unwrap();
+    for i in 0..20_000 {
+        store
+            .set(&format!("key{}", i % 10_000), &i.to_string())
+            .unwrap();
+    }
+    for i in 0..1_000 {
+        store.remove(&format!("key{}", i * 3)).unwrap();
+    }
+    store
+        .set_with_ttl("ttl", "value", Duration::from_secs(60))
+        .unwrap();
+    drop(store);
+
+    let compacted = |name: &str, parallel: bool| {
+        let path = temp_dir.path().join(name);
+        std::fs::copy(&db, &path).unwrap();
+        let mut store = KvStore::builder(&path)
+            .auto_compact(false)
+            .parallel_compaction(parallel)
+            .build()
+            .unwrap();
+        store.compact().unwrap();
+        let values = store.to_hashmap().unwrap();
+        let contents = String::from_utf8(read(&path).unwrap()).unwrap();
+        let mut lines: Vec<String> = contents.lines().map(String::from).collect();
+        lines.sort();
+        (values, lines)
+    };
+    let (sequential, sequential_lines) = compacted("sequential.db", false);
+    let (parallel, parallel_lines) = compacted("parallel.db", true);
+    assert_eq!(sequential.len(), 9_001);
+    assert_eq!(parallel, sequential);
+    assert_eq!(parallel_lines, sequential_lines);
+}
+
+// Touched key should get its TTL again, counted from the time of touch
+#[test]
+fn touch_refreshes_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let clock = MockClock::new(1_000_000);
+    let mut store = KvStore::builder(&db).clock(clock.clone()).build().unwrap();
+    store
+        .set_with_ttl("key1", "value1", Duration::from_millis(100))
+        .unwrap();
+    store.set("key2", "value2").unwrap();
+    assert!(!store.touch("key3").unwrap());
+
+    clock.advance(Duration::from_millis(60));
+    assert!(store.touch("key1").unwrap());
+    assert!(store.touch("key2").unwrap());
+    clock.advance(Duration::from_millis(60));
+    assert_eq!(store.get("key1").unwrap(), "value1");
+    drop(store);
+
+    let mut store = KvStore::builder(&db).clock(clock.clone()).build().unwrap();
+    assert_eq!(store.get("key1").unwrap(), "value1");
+    assert_eq!(store.get("key2").unwrap(), "value2");
+    clock.advance(Duration::from_millis(40));
+    assert!(store.get("key1").is_err());
+    assert!(!store.touch("key1").unwrap());
+    assert_eq!(store.get("key2").unwrap(), "value2");
+}
+
+// Reads of keys evicted from small cache should be counted as misses served by storage
+#[test]
+fn cache_hit_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db)
+        .build_with_cache(LruCache::with_capacity(1))
+        .unwrap();
+    assert_eq!(store.stats().hit_ratio(), None);
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+
+    assert_eq!(store.get("key2").unwrap(), "value2");
+    let stats = store.stats();
+    assert_eq!(
+        (stats.cache_hits, stats.cache_misses, stats.disk_reads),
+        (1, 0, 0)
+    );
+    assert_eq!(stats.hit_ratio(), Some(1.0));
+
+    assert_eq!(store.get("key1").unwrap(), "value1");
+    assert_eq!(store.get("key2").unwrap(), "value2");
+    assert!(store.get("key3").is_err());
+    let stats = store.stats();
+    assert_eq!(
+        (stats.cache_hits, stats.cache_misses, stats.disk_reads),
+        (1, 3, 3)
+    );
+    assert_eq!(stats.hit_ratio(), Some(0.25));
+}
+
+// Records read from memory-mapped log should be the same as read by buffered reader,
+// including records written after the file was mapped
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::builder(&db).mmap(true).build().unwrap();
+    assert!(store.get("key0").is_err());
+    for i in 0..1_000 {
+        store
+            .set(&format!("key{}", i % 300), &i.to_string())
+            .unwrap();
+    }
+    store.remove("key1").unwrap();
+    drop(store);
+    let mut store = KvStore::builder(&db).length_prefixed(true).build().unwrap();
+    store.set("framed", "line\nbreak").unwrap();
+    let expected = store.to_hashmap().unwrap();
+    drop(store);
+
+    let store = KvStore::builder(&db).mmap(true).build().unwrap();
+    assert_eq!(store.to_hashmap().unwrap(), expected);
+    drop(store);
+
+    for indexed in [false, true] {
+        let mut store = KvStore::builder(&db)
+            .mmap(true)
+            .key_index(indexed)
+            .build_with_cache(LruCache::with_capacity(1))
+            .unwrap();
+        assert_eq!(store.get("key299").unwrap(), "899");
+        assert_eq!(store.get("framed").unwrap(), "line\nbreak");
+        assert!(store.get("key1").is_err());
+        store.set("late", "value").unwrap();
+        store.set("other", "value").unwrap();
+        assert_eq!(store.get("late").unwrap(), "value");
+        assert_eq!(store.get("key0").unwrap(), "900");
+
+        store.compact().unwrap();
+        assert_eq!(store.get("key2").unwrap(), "902");
+        assert_eq!(store.get("late").unwrap(), "value");
+        store.remove("late").unwrap();
+        store.remove("other").unwrap();
+    }
+
+    let mut store = KvStore::builder(&db).mmap(true).build().unwrap();
+    store.clear().unwrap();
+    store.set("key", "value").unwrap();
+    drop(store);
+    let store = KvStore::builder(&db).mmap(true).build().unwrap();
+    assert_eq!(store.to_hashmap().unwrap().len(), 1);
+}
+
+// Returned size should be the size of the record appended to the log
+#[test]
+fn set_returning_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    let mut store = KvStore::new(&db).unwrap();
+    let header = "kvs-log 1\n".len();
+    let size = store.set_returning_size("key1", "value1").unwrap();
+    assert_eq!(store.disk_usage().unwrap() as usize, header + size);
+    let contents = String::from_utf8(read(&db).unwrap()).unwrap();
+    let record = contents.lines().nth(1).unwrap();
+    assert!(record.contains(r#""key":"key1","value":"value1""#));
+    assert_eq!(size, record.len() + 1);
+
+    let before = store.disk_usage().unwrap() as usize;
+    let size = store.set_returning_size("key2", "longer value").unwrap();
+    assert_eq!(store.disk_usage().unwrap() as usize, before + size);
+    let (_, meta) = store.get_with_metadata("key2").unwrap().unwrap();
+    assert_eq!(meta.size, size);
+}
+
+// Every error should have a stable code, which is used as exit code of the binary
+#[test]
+fn error_codes() {
+    let io = || std::io::Error::other("failed");
+    let serde = || serde_json::from_str::<u8>("x").unwrap_err();
+    let cases = vec![
+        (KvsError::KeyNotFound, ErrorCode::NotFound),
+        (
+            KvsError::AlreadyExists {
+                path: "kvs.db".to_owned(),
+            },
+            ErrorCode::Exists,
+        ),
+        (KvsError::KeyExists, ErrorCode::ReadOnly),
+        (KvsError::Io(io()), ErrorCode::Io),
+        (
+            KvsError::IoContext {
+                context: "kvs.db".to_owned(),
+                cause: io(),
+            },
+            ErrorCode::Io,
+        ),
+        (KvsError::Serde(serde()), ErrorCode::Serde),
+        (
+            KvsError::RecordTooLarge {
+                offset: 0,
+                limit: 1,
+            },
+            ErrorCode::Corrupt,
+        ),
+        (KvsError::UnknownFormat, ErrorCode::Corrupt),
+        (
+            KvsError::ValueTooLarge { size: 2, limit: 1 },
+            ErrorCode::InvalidInput,
+        ),
+        (
+            KvsError::VersionMismatch {
+                expected: 1,
+                found: 2,
+            },
+            ErrorCode::Incompatible,
+        ),
+        (
+            KvsError::ShardCountMismatch {
+                expected: 1,
+                found: 2,
+            },
+            ErrorCode::Incompatible,
+        ),
+        (
+            KvsError::InvalidPath {
+                reason: "dir".to_owned(),
+            },
+            ErrorCode::InvalidInput,
+        ),
+        (
+            KvsError::MalformedInput { line: 1 },
+            ErrorCode::InvalidInput,
+        ),
+        (
+            KvsError::WrongType {
+                expected: "Int",
+                found: "Str",
+            },
+            ErrorCode::InvalidInput,
+        ),
+        (KvsError::IntegerOverflow, ErrorCode::InvalidInput),
+        (
+            KvsError::ValidationFailed("empty".to_owned()),
+            ErrorCode::InvalidInput,
+        ),
+        (
+            KvsError::Clap(clap::Error::with_description(
+                "bad",
+                clap::ErrorKind::InvalidValue,
+            )),
+            ErrorCode::InvalidInput,
+        ),
+    ];
+    for (err, code) in cases {
+        assert_eq!(err.code(), code, "{}", err);
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    write(temp_dir.path().join("kvs.db"), "not a database\n").unwrap();
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(ErrorCode::Corrupt as i32);
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--force-new", "set", "key", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(ErrorCode::Exists as i32);
+}
+
+// Key over the limit should be durably removed: the oldest written one for FIFO
+// and the least recently used one for LRU
+#[test]
+fn max_keys_eviction() {
+    for (policy, evicted, kept) in [
+        (EvictionPolicy::Fifo, "key1", "key2"),
+        (EvictionPolicy::Lru, "key2", "key1"),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let db = temp_dir.path().join("kvs.db");
+        let mut store = KvStore::builder(&db)
+            .set_max_keys(3, policy)
+            .build()
+            .unwrap();
+        for i in 1..=3 {
+            store.set(&format!("key{}", i), "value").unwrap();
+        }
+        assert_eq!(store.get("key1").unwrap(), "value");
+        // Overwrite doesn't add a key, so nothing is evicted
+        store.set("key3", "new value").unwrap();
+        assert_eq!(store.len().unwrap(), 3);
+
+        store.set("key4", "value").unwrap();
+        assert_eq!(store.len().unwrap(), 3);
+        assert_eq!(
+            store.get_status(evicted).unwrap(),
+            KeyStatus::Deleted,
+            "{:?}",
+            policy
+        );
+        assert_eq!(store.get(kept).unwrap(), "value");
+        drop(store);
+
+        let mut store = KvStore::new(&db).unwrap();
+        assert!(store.get(evicted).is_err());
+        assert_eq!(store.len().unwrap(), 3);
+        drop(store);
+
+        // Lower limit evicts keys written the longest time ago on open
+        let mut store = KvStore::builder(&db)
+            .set_max_keys(2, policy)
+            .build()
+            .unwrap();
+        assert_eq!(store.len().unwrap(), 2);
+        assert!(store.get(kept).is_err());
+        assert_eq!(store.get("key4").unwrap(), "value");
+    }
+}
+
+// Blobs written right after reopening shouldn't take names of blobs written before
+#[test]
+fn blob_names_unique_across_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = temp_dir.path().join("kvs.db");
+    for i in 0..20 {
+        let mut store = KvStore::builder(&db).blob_threshold(4).build().unwrap();
+        store
+            .set(&format!("key{}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    let mut store = KvStore::builder(&db).blob_threshold(4).build().unwrap();
+    for i in 0..20 {
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            format!("value{}", i)
+        );
+    }
+}